@@ -0,0 +1,241 @@
+//! Parsers of nginx and Apache access logs
+//!
+//! Format is described the way nginx `log_format` directive does it: variables like
+//! `$request` or `${status}` separated by literal text. Known variables fill `LogRecord`,
+//! the others are skipped. Two variables in a row can't be split and are not allowed.
+//!
+//! Known variables: `$request`, `$request_method`, `$request_uri`, `$uri`, `$args`
+//! (`$uri?$args` makes url with query), `$status`, `$http_user_agent`, `$http_referer`,
+//! `$time_local`, `$time_iso8601`, `$msec`, `$host`, `$http_host`.
+
+use std::cell::RefCell;
+use twoway;
+use LogRecord;
+
+/// nginx `combined` format which is the same as Apache combined
+pub const COMBINED: &str = "$remote_addr $remote_ident $remote_user [$time_local] \"$request\" $status $body_bytes_sent \"$http_referer\" \"$http_user_agent\"";
+
+/// Apache common log format
+pub const COMMON: &str = "$remote_addr $remote_ident $remote_user [$time_local] \"$request\" $status $body_bytes_sent";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Field {
+    Request,
+    Method,
+    Url,
+    Uri,
+    Args,
+    Status,
+    UserAgent,
+    Referrer,
+    Timestamp,
+    Host,
+    Skip,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Field {
+        match name {
+            "request" => Field::Request,
+            "request_method" => Field::Method,
+            "request_uri" => Field::Url,
+            "uri" | "document_uri" => Field::Uri,
+            "args" | "query_string" => Field::Args,
+            "status" => Field::Status,
+            "http_user_agent" => Field::UserAgent,
+            "http_referer" => Field::Referrer,
+            "time_local" | "time_iso8601" | "msec" => Field::Timestamp,
+            "host" | "http_host" => Field::Host,
+            _ => Field::Skip,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Item {
+    Literal(Vec<u8>),
+    Var(Field),
+}
+
+/// Compiled log format
+#[derive(Clone, Debug)]
+pub struct LogFormat {
+    items: Vec<Item>,
+}
+
+impl LogFormat {
+    /// Compiles nginx `log_format` string
+    ///
+    /// # Examples:
+    ///
+    /// ```
+    /// use logut::access_log::LogFormat;
+    /// assert!(LogFormat::compile("$remote_addr [$time_local] \"$request\" $status").is_ok());
+    /// assert!(LogFormat::compile("${host}:$status").is_ok());
+    /// assert!(LogFormat::compile("$host$status").is_err());
+    /// assert!(LogFormat::compile("${host").is_err());
+    /// ```
+    pub fn compile(format: &str) -> Result<LogFormat, String> {
+        let mut items = Vec::new();
+        let mut literal = Vec::new();
+        let bytes = format.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] != b'$' {
+                literal.push(bytes[i]);
+                i += 1;
+                continue;
+            }
+            let (name, next) = if bytes.get(i + 1) == Some(&b'{') {
+                match bytes[i + 2..].iter().position(|b| *b == b'}') {
+                    Some(len) => (&format[i + 2..i + 2 + len], i + 3 + len),
+                    None => return Err(format!("unclosed '{{' at position {}", i + 1)),
+                }
+            } else {
+                let len = bytes[i + 1..].iter().position(|b| !(b.is_ascii_alphanumeric() || *b == b'_')).unwrap_or(bytes.len() - i - 1);
+                (&format[i + 1..i + 1 + len], i + 1 + len)
+            };
+            if name.is_empty() {
+                literal.push(b'$');
+                i += 1;
+                continue;
+            }
+            if !literal.is_empty() {
+                items.push(Item::Literal(literal));
+                literal = Vec::new();
+            } else if let Some(&Item::Var(_)) = items.last() {
+                return Err(format!("variable ${} follows another variable without separator", name));
+            }
+            items.push(Item::Var(Field::from_name(name)));
+            i = next;
+        }
+        if !literal.is_empty() {
+            items.push(Item::Literal(literal));
+        }
+        Ok(LogFormat { items: items })
+    }
+
+    /// Extracts fields from line, returns None if line doesn't match the format
+    ///
+    /// # Examples:
+    ///
+    /// ```
+    /// use logut::access_log::LogFormat;
+    /// let format = LogFormat::compile("$host [$time_iso8601] $request_method $uri?$args $status").unwrap();
+    /// let rec = format.parse(b"example.com [2017-01-01T00:00:00+03:00] POST /api?x=1 200").unwrap();
+    /// assert_eq!(rec.host, b"example.com");
+    /// assert_eq!(rec.timestamp, b"2017-01-01T00:00:00+03:00");
+    /// assert_eq!(rec.method, b"POST");
+    /// assert_eq!(rec.url, b"/api?x=1");
+    /// assert_eq!(rec.status, b"200");
+    /// assert!(format.parse(b"example.com 2017-01-01 POST /api?x=1 200").is_none());
+    /// ```
+    pub fn parse<'a>(&self, line: &'a [u8]) -> Option<LogRecord<'a>> {
+        let mut rec = LogRecord::default();
+        let mut uri: Option<(usize, usize)> = None;
+        let mut args_end: Option<usize> = None;
+        let mut pos = 0;
+        for (i, item) in self.items.iter().enumerate() {
+            let field = match *item {
+                Item::Literal(ref literal) => {
+                    if !line[pos..].starts_with(literal) {
+                        return None;
+                    }
+                    pos += literal.len();
+                    continue;
+                },
+                Item::Var(field) => field,
+            };
+            let end = match self.items.get(i + 1) {
+                Some(Item::Literal(literal)) => pos + twoway::find_bytes(&line[pos..], literal)?,
+                _ => line.len(),
+            };
+            let value = &line[pos..end];
+            match field {
+                Field::Request => {
+                    let mut parts = value.splitn(3, |b| *b == b' ');
+                    rec.method = parts.next().unwrap_or(b"");
+                    rec.url = parts.next().unwrap_or(b"");
+                },
+                Field::Method => rec.method = value,
+                Field::Url => rec.url = value,
+                Field::Uri => uri = Some((pos, end)),
+                Field::Args => if !dash_to_empty(value).is_empty() { args_end = Some(end) },
+                Field::Status => rec.status = value,
+                Field::UserAgent => rec.user_agent = dash_to_empty(value),
+                Field::Referrer => rec.referrer = dash_to_empty(value),
+                Field::Timestamp => rec.timestamp = value,
+                Field::Host => rec.host = dash_to_empty(value),
+                Field::Skip => {},
+            }
+            pos = end;
+        }
+        if let Some((start, end)) = uri {
+            if rec.url.is_empty() {
+                rec.url = match args_end {
+                    Some(args_end) if args_end > end + 1 && line[end] == b'?' => &line[start..args_end],
+                    _ => &line[start..end],
+                };
+            }
+        }
+        Some(rec)
+    }
+}
+
+/// nginx writes `-` in place of empty values
+fn dash_to_empty(value: &[u8]) -> &[u8] {
+    if value == b"-" { b"" } else { value }
+}
+
+thread_local! {
+    static COMBINED_FORMAT: RefCell<Option<LogFormat>> = RefCell::new(None);
+    static COMMON_FORMAT: RefCell<Option<LogFormat>> = RefCell::new(None);
+}
+
+fn parse_with<'a>(cache: &'static ::std::thread::LocalKey<RefCell<Option<LogFormat>>>, format: &str, line: &'a [u8]) -> Option<LogRecord<'a>> {
+    cache.with(|cached| {
+        let mut cached = cached.borrow_mut();
+        if cached.is_none() {
+            *cached = Some(LogFormat::compile(format).unwrap());
+        }
+        cached.as_ref().unwrap().parse(line)
+    })
+}
+
+/// Makes LogRecord from nginx or Apache combined log line
+///
+/// # Examples:
+///
+/// ```
+/// use logut::access_log::parse_combined_log_line;
+/// let line = b"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] \"GET /search?text=a HTTP/1.1\" 200 2326 \"http://example.com/\" \"Mozilla/4.08 [en] (Win98)\"";
+/// let rec = parse_combined_log_line(line).unwrap();
+/// assert_eq!(rec.method, b"GET");
+/// assert_eq!(rec.url, b"/search?text=a");
+/// assert_eq!(rec.status, b"200");
+/// assert_eq!(rec.timestamp, b"10/Oct/2000:13:55:36 -0700");
+/// assert_eq!(rec.referrer, b"http://example.com/");
+/// assert_eq!(rec.user_agent, b"Mozilla/4.08 [en] (Win98)");
+///
+/// let rec = parse_combined_log_line(b"::1 - - [10/Oct/2000:13:55:36 -0700] \"GET / HTTP/1.0\" 404 0 \"-\" \"-\"").unwrap();
+/// assert_eq!(rec.referrer, b"");
+/// assert!(parse_combined_log_line(b"http://example.com/").is_none());
+/// ```
+pub fn parse_combined_log_line(line: &[u8]) -> Option<LogRecord<'_>> {
+    parse_with(&COMBINED_FORMAT, COMBINED, line)
+}
+
+/// Makes LogRecord from Apache common log line
+///
+/// # Examples:
+///
+/// ```
+/// use logut::access_log::parse_common_log_line;
+/// let rec = parse_common_log_line(b"127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] \"POST /api HTTP/1.1\" 201 15").unwrap();
+/// assert_eq!(rec.method, b"POST");
+/// assert_eq!(rec.url, b"/api");
+/// assert_eq!(rec.status, b"201");
+/// ```
+pub fn parse_common_log_line(line: &[u8]) -> Option<LogRecord<'_>> {
+    parse_with(&COMMON_FORMAT, COMMON, line)
+}
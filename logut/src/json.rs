@@ -0,0 +1,354 @@
+//! Minimal zero-copy JSON scanner
+//!
+//! It validates JSON text and returns raw slices of values found at given JSON pointers
+//! (RFC 6901, e.g. `/request/url`). Strings are returned as they are written in JSON,
+//! i.e. without quotes but still escaped; use `unescape_string` to decode them.
+
+use std::borrow::Cow;
+use std::fmt;
+
+/// Nesting deeper than this is reported as an error instead of overflowing the stack
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, PartialEq)]
+pub struct JsonError {
+    pub offset: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "malformed JSON at byte {}: {}", self.offset, self.message)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JsonValue<'a> {
+    /// Contents of string between quotes, still escaped
+    String(&'a [u8]),
+    /// Text of array including brackets
+    Array(&'a [u8]),
+    /// Text of object including braces
+    Object(&'a [u8]),
+    /// Number, `true` or `false`
+    Scalar(&'a [u8]),
+    Null,
+}
+
+impl<'a> JsonValue<'a> {
+    /// Raw bytes to put into `LogRecord`: string contents, array contents without brackets,
+    /// objects and scalars as they are, empty slice for null
+    pub fn as_field(&self) -> &'a [u8] {
+        match *self {
+            JsonValue::String(s) | JsonValue::Object(s) | JsonValue::Scalar(s) => s,
+            JsonValue::Array(s) => &s[1..s.len() - 1],
+            JsonValue::Null => b"",
+        }
+    }
+}
+
+/// Splits JSON pointer into reference tokens
+///
+/// # Examples:
+///
+/// ```
+/// use logut::json::parse_pointer;
+/// assert_eq!(parse_pointer("/request/url").unwrap(), vec![b"request".to_vec(), b"url".to_vec()]);
+/// assert_eq!(parse_pointer("/a~1b/m~0n").unwrap(), vec![b"a/b".to_vec(), b"m~n".to_vec()]);
+/// assert_eq!(parse_pointer("").unwrap(), Vec::<Vec<u8>>::new());
+/// assert!(parse_pointer("url").is_err());
+/// ```
+pub fn parse_pointer(pointer: &str) -> Result<Vec<Vec<u8>>, String> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !pointer.starts_with('/') {
+        return Err(format!("JSON pointer '{}' should start with '/'", pointer));
+    }
+    Ok(pointer[1..].split('/').map(|t| t.replace("~1", "/").replace("~0", "~").into_bytes()).collect())
+}
+
+#[derive(Clone, Copy)]
+enum PathToken<'a> {
+    Key(&'a [u8]),
+    Index(usize),
+}
+
+fn token_matches(token: &PathToken, pointer_token: &[u8]) -> bool {
+    match *token {
+        PathToken::Key(key) => key == pointer_token,
+        PathToken::Index(i) => ::std::str::from_utf8(pointer_token).ok().and_then(|t| t.parse::<usize>().ok()) == Some(i),
+    }
+}
+
+struct Scanner<'a, 'p> {
+    text: &'a [u8],
+    pos: usize,
+    path: Vec<PathToken<'a>>,
+    pointers: &'p [Vec<Vec<u8>>],
+    found: Vec<Option<JsonValue<'a>>>,
+    /// Members of top level object if they are needed
+    members: Option<Vec<(&'a [u8], JsonValue<'a>)>>,
+}
+
+impl<'a, 'p> Scanner<'a, 'p> {
+    fn error<T>(&self, message: &'static str) -> Result<T, JsonError> {
+        Err(JsonError { offset: self.pos, message: message })
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.pos < self.text.len() && (self.text[self.pos] == b' ' || self.text[self.pos] == b'\t' || self.text[self.pos] == b'\n' || self.text[self.pos] == b'\r') {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, b: u8, message: &'static str) -> Result<(), JsonError> {
+        self.skip_spaces();
+        if self.peek() != Some(b) {
+            return self.error(message);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn remember(&mut self, value: JsonValue<'a>) {
+        if let (Some(members), 1) = (self.members.as_mut(), self.path.len()) {
+            if let PathToken::Key(key) = self.path[0] {
+                members.push((key, value));
+            }
+        }
+        for (i, pointer) in self.pointers.iter().enumerate() {
+            if self.found[i].is_none() && pointer.len() == self.path.len()
+                && self.path.iter().zip(pointer.iter()).all(|(t, p)| token_matches(t, p)) {
+                self.found[i] = Some(value);
+            }
+        }
+    }
+
+    /// Returns string contents, position is right after closing quote
+    fn string(&mut self) -> Result<&'a [u8], JsonError> {
+        self.expect(b'"', "expected string")?;
+        let start = self.pos;
+        while self.pos < self.text.len() {
+            match self.text[self.pos] {
+                b'"' => {
+                    self.pos += 1;
+                    return Ok(&self.text[start..self.pos - 1]);
+                },
+                b'\\' => self.pos += 2,
+                b if b < 0x20 => return self.error("control character in string"),
+                _ => self.pos += 1,
+            }
+        }
+        self.error("unterminated string")
+    }
+
+    fn value(&mut self) -> Result<(), JsonError> {
+        if self.path.len() > MAX_DEPTH {
+            return self.error("too deep nesting");
+        }
+        self.skip_spaces();
+        let start = self.pos;
+        let value = match self.peek() {
+            Some(b'"') => JsonValue::String(self.string()?),
+            Some(b'{') => {
+                self.pos += 1;
+                self.skip_spaces();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        let key = self.string()?;
+                        self.expect(b':', "expected ':'")?;
+                        self.path.push(PathToken::Key(key));
+                        self.value()?;
+                        self.path.pop();
+                        self.skip_spaces();
+                        match self.peek() {
+                            Some(b',') => self.pos += 1,
+                            Some(b'}') => { self.pos += 1; break; },
+                            _ => return self.error("expected ',' or '}'"),
+                        }
+                    }
+                }
+                JsonValue::Object(&self.text[start..self.pos])
+            },
+            Some(b'[') => {
+                self.pos += 1;
+                self.skip_spaces();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                } else {
+                    let mut index = 0;
+                    loop {
+                        self.path.push(PathToken::Index(index));
+                        self.value()?;
+                        self.path.pop();
+                        index += 1;
+                        self.skip_spaces();
+                        match self.peek() {
+                            Some(b',') => self.pos += 1,
+                            Some(b']') => { self.pos += 1; break; },
+                            _ => return self.error("expected ',' or ']'"),
+                        }
+                    }
+                }
+                JsonValue::Array(&self.text[start..self.pos])
+            },
+            Some(b) if b == b'-' || b.is_ascii_alphanumeric() => {
+                while self.pos < self.text.len() && (self.text[self.pos].is_ascii_alphanumeric() || b"+-.".contains(&self.text[self.pos])) {
+                    self.pos += 1;
+                }
+                match &self.text[start..self.pos] {
+                    b"null" => JsonValue::Null,
+                    b"true" | b"false" => JsonValue::Scalar(&self.text[start..self.pos]),
+                    s if s[0] == b'-' || s[0].is_ascii_digit() => JsonValue::Scalar(s),
+                    _ => return Err(JsonError { offset: start, message: "unexpected literal" }),
+                }
+            },
+            _ => return self.error("expected value"),
+        };
+        self.remember(value);
+        Ok(())
+    }
+}
+
+/// Checks that text is single JSON value and returns values found at given pointers
+///
+/// # Examples:
+///
+/// ```
+/// use logut::json::{find_values, parse_pointer, JsonValue};
+/// let pointers = vec![parse_pointer("/req/url").unwrap(), parse_pointer("/tags/1").unwrap(), parse_pointer("/none").unwrap()];
+/// let values = find_values(br#"{"req": {"url": "/search?text=\"a\""}, "tags": ["x", "y"], "n": -1.5e3}"#, &pointers).unwrap();
+/// assert_eq!(values[0], Some(JsonValue::String(br#"/search?text=\"a\""#)));
+/// assert_eq!(values[1], Some(JsonValue::String(b"y")));
+/// assert_eq!(values[2], None);
+///
+/// assert!(find_values(b"{\"a\": 1", &pointers).is_err());
+/// assert!(find_values(b"{\"a\": 1} x", &pointers).is_err());
+/// assert!(find_values(b"{\"a\": tru}", &pointers).is_err());
+/// ```
+pub fn find_values<'a>(text: &'a [u8], pointers: &[Vec<Vec<u8>>]) -> Result<Vec<Option<JsonValue<'a>>>, JsonError> {
+    let mut scanner = Scanner {
+        text: text,
+        pos: 0,
+        path: Vec::new(),
+        pointers: pointers,
+        found: vec![None; pointers.len()],
+        members: None,
+    };
+    scanner.value()?;
+    scanner.skip_spaces();
+    if scanner.pos != text.len() {
+        return scanner.error("unexpected data after JSON value");
+    }
+    Ok(scanner.found)
+}
+
+/// Returns members of JSON object with raw keys
+///
+/// # Examples:
+///
+/// ```
+/// use logut::json::{object_members, JsonValue};
+/// let members = object_members(br#"{"Cookie": "a=b", "X-Count": 2}"#).unwrap();
+/// assert_eq!(members, vec![(b"Cookie".as_ref(), JsonValue::String(b"a=b")), (b"X-Count".as_ref(), JsonValue::Scalar(b"2"))]);
+/// assert!(object_members(b"[1]").is_err());
+/// assert!(object_members(b"{\"a\": }").is_err());
+/// ```
+pub fn object_members(text: &[u8]) -> Result<Vec<(&[u8], JsonValue<'_>)>, JsonError> {
+    let mut scanner = Scanner {
+        text: text,
+        pos: 0,
+        path: Vec::new(),
+        pointers: &[],
+        found: Vec::new(),
+        members: Some(Vec::new()),
+    };
+    scanner.skip_spaces();
+    if scanner.peek() != Some(b'{') {
+        return scanner.error("expected object");
+    }
+    scanner.value()?;
+    scanner.skip_spaces();
+    if scanner.pos != text.len() {
+        return scanner.error("unexpected data after JSON value");
+    }
+    Ok(scanner.members.unwrap_or_default())
+}
+
+/// Decodes JSON string escapes including `\uXXXX`, malformed escapes are left as they are
+///
+/// # Examples:
+///
+/// ```
+/// use logut::json::unescape_string;
+/// assert_eq!(unescape_string(b"plain").as_ref(), b"plain");
+/// assert_eq!(unescape_string(br#"a\"b\\c\/d\n"#).as_ref(), b"a\"b\\c/d\n");
+/// assert_eq!(unescape_string(br#"\u0430\ud83d\ude00"#).as_ref(), "а😀".as_bytes());
+/// assert_eq!(unescape_string(br#"\x\u12"#).as_ref(), br#"\x\u12"#);
+/// ```
+pub fn unescape_string(raw: &[u8]) -> Cow<'_, [u8]> {
+    if !raw.contains(&b'\\') {
+        return Cow::Borrowed(raw);
+    }
+    let hex4 = |s: &[u8]| -> Option<u32> {
+        if s.len() < 4 {
+            return None;
+        }
+        ::std::str::from_utf8(&s[..4]).ok().and_then(|h| u32::from_str_radix(h, 16).ok())
+    };
+    let mut result = Vec::with_capacity(raw.len());
+    let mut i = 0;
+    while i < raw.len() {
+        if raw[i] != b'\\' || i + 1 == raw.len() {
+            result.push(raw[i]);
+            i += 1;
+            continue;
+        }
+        let simple = match raw[i + 1] {
+            b'"' => Some(b'"'),
+            b'\\' => Some(b'\\'),
+            b'/' => Some(b'/'),
+            b'b' => Some(8),
+            b'f' => Some(12),
+            b'n' => Some(b'\n'),
+            b'r' => Some(b'\r'),
+            b't' => Some(b'\t'),
+            _ => None,
+        };
+        if let Some(b) = simple {
+            result.push(b);
+            i += 2;
+            continue;
+        }
+        if raw[i + 1] == b'u' {
+            if let Some(code) = hex4(&raw[i + 2..]) {
+                let mut len = 6;
+                let mut code = code;
+                if code >= 0xd800 && code < 0xdc00 && raw[i + 6..].starts_with(b"\\u") {
+                    if let Some(low) = hex4(&raw[i + 8..]) {
+                        if low >= 0xdc00 && low < 0xe000 {
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            len = 12;
+                        }
+                    }
+                }
+                if let Some(c) = ::std::char::from_u32(code) {
+                    let mut buf = [0u8; 4];
+                    result.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    i += len;
+                    continue;
+                }
+            }
+        }
+        result.push(b'\\');
+        i += 1;
+    }
+    Cow::Owned(result)
+}
@@ -2,11 +2,49 @@ extern crate flate2;
 extern crate twoway;
 
 pub mod read;
+pub mod url;
+pub mod access_log;
+pub mod json;
+
+use std::borrow::Cow;
+use std::fmt;
 
 /// View to log line with essential fields extracted
+///
+/// `headers` and `body` are escaped as in tskv whatever the log format is, so they are
+/// copied only when the log escapes them some other way. Use `unescape_tskv_value`
+/// to get raw bytes. Headers are separated by newlines.
+#[derive(Default)]
 pub struct LogRecord<'a> {
     pub url: &'a [u8],
     pub wizards: &'a [u8],
+    pub method: &'a [u8],
+    pub headers: Cow<'a, [u8]>,
+    pub body: Cow<'a, [u8]>,
+    pub status: &'a [u8],
+    pub user_agent: &'a [u8],
+    pub referrer: &'a [u8],
+    pub timestamp: &'a [u8],
+    /// Host for logs where url has no host part
+    pub host: &'a [u8],
+    /// Query for logs where it is written apart from url
+    pub query: &'a [u8],
+}
+
+/// Log line which can't be parsed
+#[derive(Debug, PartialEq)]
+pub struct ParseError(pub String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<json::JsonError> for ParseError {
+    fn from(e: json::JsonError) -> ParseError {
+        ParseError(e.to_string())
+    }
 }
 
 /// Make LogRecord from line containing only url
@@ -20,7 +58,7 @@ pub struct LogRecord<'a> {
 /// assert_eq!(rec.wizards, b"");
 /// ```
 pub fn make_record_from_plain_line(line: &[u8]) -> LogRecord {
-    LogRecord { url: line, wizards: b"" }
+    LogRecord { url: line, ..Default::default() }
 }
 
 /// Make LogRecord from tab-separated log line
@@ -34,17 +72,25 @@ pub fn make_record_from_plain_line(line: &[u8]) -> LogRecord {
 /// assert_eq!(rec.wizards, b"bebebe,zz");
 /// ```
 pub fn parse_tab_separated_log_line(line: &[u8]) -> LogRecord {
+    parse_tsv_columns(line, 1, 12)
+}
+
+fn parse_tsv_columns(line: &[u8], url_column: usize, wizards_column: usize) -> LogRecord<'_> {
     let mut url = None;
     let mut wizards = None;
+    let last = url_column.max(wizards_column);
     for (i, value) in line.split(|b| *b == b'\t').enumerate() {
-        match i {
-            1 => url = Some(value),
-            12 => wizards = Some(value),
-            13 => break,
-            _ => {},
+        if i == url_column {
+            url = Some(value);
+        }
+        if i == wizards_column {
+            wizards = Some(value);
+        }
+        if i == last {
+            break;
         }
     }
-    LogRecord { url: url.unwrap_or(b""), wizards: wizards.unwrap_or(b"") }
+    LogRecord { url: url.unwrap_or(b""), wizards: wizards.unwrap_or(b""), ..Default::default() }
 }
 
 /// Make LogRecord from tskv-formated log line
@@ -55,29 +101,448 @@ pub fn parse_tab_separated_log_line(line: &[u8]) -> LogRecord {
 /// let rec = parse_tskv_log_line(b"tskv\turl=http://example.com\twizards=bebebe,zz");
 /// assert_eq!(rec.url, b"http://example.com");
 /// assert_eq!(rec.wizards, b"bebebe,zz");
+///
+/// let rec = parse_tskv_log_line(b"tskv\turl=/api\tmethod=POST\theaders=Cookie: a=b\\nContent-Type: text/plain\tbody=hello");
+/// assert_eq!(rec.method, b"POST");
+/// assert_eq!(rec.headers.as_ref(), b"Cookie: a=b\\nContent-Type: text/plain".as_ref());
+/// assert_eq!(rec.body.as_ref(), b"hello");
 /// ```
 pub fn parse_tskv_log_line(line: &[u8]) -> LogRecord {
-    let mut url: Option<&[u8]> = None;
-    let mut wizards: Option<&[u8]> = None;
+    parse_tskv_keys(line, &TSKV_FIELD_NAMES)
+}
+
+const TSKV_FIELD_NAMES: [&str; 5] = ["url", "wizards", "method", "headers", "body"];
+
+/// `keys` are tskv keys of fields listed in TSKV_FIELD_NAMES
+fn parse_tskv_keys<'a, K: AsRef<[u8]>>(line: &'a [u8], keys: &[K]) -> LogRecord<'a> {
+    let mut rec = LogRecord::default();
     for item in line.split(|b| *b == b'\t') {
         let (key, value) = {
             let mut iter = item.splitn(2, |b| *b == b'=');
             (iter.next().unwrap_or(b""), iter.next().unwrap_or(b""))
         };
-        match key {
-            b"url" => url = Some(value),
-            b"wizards" => wizards = Some(value),
+        match keys.iter().position(|k| k.as_ref() == key) {
+            Some(0) => rec.url = value,
+            Some(1) => rec.wizards = value,
+            Some(2) => rec.method = value,
+            Some(3) => rec.headers = Cow::Borrowed(value),
+            Some(4) => rec.body = Cow::Borrowed(value),
             _ => {},
         }
     }
-    LogRecord {
-        url: url.unwrap_or(b""),
-        wizards: wizards.unwrap_or(b""),
+    rec
+}
+
+/// Returns raw (escaped) value of given key of tskv-formated log line
+///
+/// # Examples:
+///
+/// ```
+/// use logut::get_tskv_value;
+/// let line = b"tskv\turl=http://example.com\tcount=12\tempty=";
+/// assert_eq!(get_tskv_value(line, b"count").unwrap(), b"12");
+/// assert_eq!(get_tskv_value(line, b"empty").unwrap(), b"");
+/// assert_eq!(get_tskv_value(line, b"cnt"), None);
+/// ```
+pub fn get_tskv_value<'a>(line: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    for item in line.split(|b| *b == b'\t') {
+        if item.len() > key.len() && item.starts_with(key) && item[key.len()] == b'=' {
+            return Some(&item[key.len() + 1..]);
+        }
+    }
+    None
+}
+
+/// Returns column of tab-separated log line, columns are numbered from 0
+///
+/// # Examples:
+///
+/// ```
+/// use logut::get_tsv_column;
+/// let line = b"[date]\thttp://example.com\t3";
+/// assert_eq!(get_tsv_column(line, 1).unwrap(), b"http://example.com");
+/// assert_eq!(get_tsv_column(line, 2).unwrap(), b"3");
+/// assert_eq!(get_tsv_column(line, 3), None);
+/// ```
+pub fn get_tsv_column(line: &[u8], index: usize) -> Option<&[u8]> {
+    line.split(|b| *b == b'\t').nth(index)
+}
+
+/// Unescapes tskv value: `\t`, `\n`, `\r`, `\0`, `\\`, escaped quotes and `=`
+///
+/// # Examples:
+///
+/// ```
+/// use logut::unescape_tskv_value;
+/// assert_eq!(unescape_tskv_value(b"plain").as_ref(), b"plain");
+/// assert_eq!(unescape_tskv_value(b"a\\tb\\nc").as_ref(), b"a\tb\nc");
+/// assert_eq!(unescape_tskv_value(b"x\\=y\\\\z").as_ref(), b"x=y\\z");
+/// assert_eq!(unescape_tskv_value(b"\\x\\").as_ref(), b"\\x\\");
+/// ```
+pub fn unescape_tskv_value(value: &[u8]) -> Cow<'_, [u8]> {
+    if !value.contains(&b'\\') {
+        return Cow::Borrowed(value);
+    }
+    let mut result = Vec::with_capacity(value.len());
+    let mut iter = value.iter();
+    while let Some(&b) = iter.next() {
+        if b != b'\\' {
+            result.push(b);
+            continue;
+        }
+        match iter.next() {
+            Some(&b't') => result.push(b'\t'),
+            Some(&b'n') => result.push(b'\n'),
+            Some(&b'r') => result.push(b'\r'),
+            Some(&b'0') => result.push(b'\0'),
+            Some(&c) if c == b'\\' || c == b'=' || c == b'"' || c == b'\'' => result.push(c),
+            Some(&c) => { result.push(b'\\'); result.push(c); },
+            None => result.push(b'\\'),
+        }
+    }
+    Cow::Owned(result)
+}
+
+/// Escapes value to be put into tskv line, reverse of `unescape_tskv_value`
+///
+/// # Examples:
+///
+/// ```
+/// use logut::{escape_tskv_value, unescape_tskv_value};
+/// assert_eq!(escape_tskv_value(b"plain").as_ref(), b"plain");
+/// assert_eq!(escape_tskv_value(b"a\tb\r\nc\\").as_ref(), b"a\\tb\\r\\nc\\\\");
+/// let raw = b"x=\"y\"\0\n";
+/// assert_eq!(unescape_tskv_value(&escape_tskv_value(raw)).as_ref(), raw.as_ref());
+/// ```
+pub fn escape_tskv_value(value: &[u8]) -> Cow<'_, [u8]> {
+    if !value.iter().any(|b| b"\t\n\r\0\\".contains(b)) {
+        return Cow::Borrowed(value);
+    }
+    let mut result = Vec::with_capacity(value.len() + 8);
+    for &b in value {
+        match b {
+            b'\t' => result.extend_from_slice(b"\\t"),
+            b'\n' => result.extend_from_slice(b"\\n"),
+            b'\r' => result.extend_from_slice(b"\\r"),
+            b'\0' => result.extend_from_slice(b"\\0"),
+            b'\\' => result.extend_from_slice(b"\\\\"),
+            _ => result.push(b),
+        }
+    }
+    Cow::Owned(result)
+}
+
+const JSON_FIELD_NAMES: [&str; 7] = ["url", "path", "query", "method", "headers", "body", "wizards"];
+
+/// JSON pointers of LogRecord fields in JSON log lines
+///
+/// Fields are `url`, `path` and `query` (used when there is no url), `method`,
+/// `headers` (string with one header per line or object), `body` and `wizards`
+/// (comma separated string or array of strings). By default they are taken from
+/// top level keys with the same names.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonFields {
+    pointers: Vec<Vec<Vec<u8>>>,
+}
+
+impl Default for JsonFields {
+    fn default() -> Self {
+        JsonFields { pointers: JSON_FIELD_NAMES.iter().map(|name| vec![name.as_bytes().to_vec()]).collect() }
+    }
+}
+
+impl JsonFields {
+    /// Sets JSON pointer of LogRecord field given by name
+    ///
+    /// # Examples:
+    ///
+    /// ```
+    /// use logut::JsonFields;
+    /// let mut fields = JsonFields::default();
+    /// assert!(fields.set("url", "/request/uri").is_ok());
+    /// assert!(fields.set("status", "/status").is_err());
+    /// assert!(fields.set("url", "request").is_err());
+    /// ```
+    pub fn set(&mut self, field: &str, pointer: &str) -> Result<(), String> {
+        let pointer = json::parse_pointer(pointer)?;
+        match JSON_FIELD_NAMES.iter().position(|name| *name == field) {
+            Some(i) => self.pointers[i] = pointer,
+            None => return Err(format!("unknown field '{}', expected one of {}", field, JSON_FIELD_NAMES.join(", "))),
+        }
+        Ok(())
+    }
+}
+
+/// Make LogRecord from JSON log line
+///
+/// Headers and body strings are unescaped and escaped again as in tskv, other values
+/// are taken as they are written in JSON. Headers may also be an object, it is kept as is.
+///
+/// # Examples:
+///
+/// ```
+/// use logut::{parse_json_log_line, JsonFields};
+/// let line = br#"{"url": "http://example.com/search?text=a", "method": "POST", "headers": {"Cookie": "a=b"}, "body": "{\"x\": 1}", "wizards": ["wiz1", "wiz2"]}"#;
+/// let rec = parse_json_log_line(line, &JsonFields::default()).unwrap();
+/// assert_eq!(rec.url, b"http://example.com/search?text=a");
+/// assert_eq!(rec.method, b"POST");
+/// assert_eq!(rec.headers.as_ref(), br#"{"Cookie": "a=b"}"#.as_ref());
+/// assert_eq!(rec.body.as_ref(), br#"{"x": 1}"#.as_ref());
+/// assert_eq!(rec.wizards, br#""wiz1", "wiz2""#.as_ref());
+///
+/// let rec = parse_json_log_line(br#"{"headers": "A: b\nC: \/d", "body": "caf\u00e9\t\\"}"#, &JsonFields::default()).unwrap();
+/// assert_eq!(rec.headers.as_ref(), b"A: b\\nC: /d".as_ref());
+/// assert_eq!(rec.body.as_ref(), "caf\u{e9}\\t\\\\".as_bytes());
+///
+/// let mut fields = JsonFields::default();
+/// fields.set("path", "/req/path").unwrap();
+/// let rec = parse_json_log_line(br#"{"req": {"path": "/search"}, "query": "text=a"}"#, &fields).unwrap();
+/// assert_eq!(rec.url, b"/search");
+/// assert_eq!(rec.query, b"text=a");
+///
+/// assert!(parse_json_log_line(br#"{"url": "/search""#, &fields).is_err());
+/// ```
+pub fn parse_json_log_line<'a>(line: &'a [u8], fields: &JsonFields) -> Result<LogRecord<'a>, ParseError> {
+    let values = json::find_values(line, &fields.pointers)?;
+    let field = |i: usize| values[i].map_or(b"".as_ref(), |v| v.as_field());
+    let mut rec = LogRecord {
+        url: field(0),
+        method: field(3),
+        headers: match values[4] {
+            Some(json::JsonValue::Object(headers)) => Cow::Borrowed(headers),
+            value => json_to_tskv(value),
+        },
+        body: json_to_tskv(values[5]),
+        wizards: field(6),
+        ..Default::default()
+    };
+    if values[0].is_none() {
+        rec.url = field(1);
+        rec.query = field(2);
+    }
+    Ok(rec)
+}
+
+/// Makes raw bytes of JSON value escaped as in tskv: strings are unescaped, other
+/// values are taken as they are written
+fn json_to_tskv(value: Option<json::JsonValue>) -> Cow<[u8]> {
+    match value {
+        Some(json::JsonValue::String(s)) => match json::unescape_string(s) {
+            Cow::Borrowed(raw) => escape_tskv_value(raw),
+            Cow::Owned(raw) => Cow::Owned(escape_tskv_value(&raw).into_owned()),
+        },
+        Some(value) => escape_tskv_value(value.as_field()),
+        None => Cow::Borrowed(b""),
     }
 }
 
+/// Format of log lines, see `LineParser`
+#[derive(Clone, Debug)]
+pub enum InputFormat {
+    /// Guess format of each line, see `parse_log_line`
+    Auto,
+    Tskv,
+    Tsv,
+    Plain,
+    Json,
+    Combined,
+    Common,
+    /// nginx `log_format`, see `access_log` module
+    Custom(access_log::LogFormat),
+}
+
+impl InputFormat {
+    /// Parses format name: `auto`, `tskv`, `tsv`, `plain`, `json`, `combined`, `common` or `log_format:FORMAT`
+    ///
+    /// # Examples:
+    ///
+    /// ```
+    /// use logut::InputFormat;
+    /// assert!(InputFormat::parse("tsv").is_ok());
+    /// assert!(InputFormat::parse("log_format:$host \"$request\"").is_ok());
+    /// assert!(InputFormat::parse("log_format:$host$request").is_err());
+    /// assert!(InputFormat::parse("csv").is_err());
+    /// ```
+    pub fn parse(name: &str) -> Result<InputFormat, String> {
+        if name.starts_with("log_format:") {
+            return access_log::LogFormat::compile(&name["log_format:".len()..]).map(InputFormat::Custom);
+        }
+        match name {
+            "auto" => Ok(InputFormat::Auto),
+            "tskv" => Ok(InputFormat::Tskv),
+            "tsv" => Ok(InputFormat::Tsv),
+            "plain" => Ok(InputFormat::Plain),
+            "json" => Ok(InputFormat::Json),
+            "combined" => Ok(InputFormat::Combined),
+            "common" => Ok(InputFormat::Common),
+            _ => Err(format!("unknown input format '{}'", name)),
+        }
+    }
+}
+
+/// Parser of log lines of given format with configurable field mapping
+///
+/// Mapping applies to tskv keys, TSV columns and JSON pointers, so the same
+/// parser works for any format, including `InputFormat::Auto`.
+#[derive(Clone, Debug)]
+pub struct LineParser {
+    pub format: InputFormat,
+    tskv_keys: Vec<Vec<u8>>,
+    url_column: usize,
+    wizards_column: usize,
+    json_fields: JsonFields,
+}
+
+impl Default for LineParser {
+    fn default() -> Self {
+        LineParser::new(InputFormat::Auto)
+    }
+}
+
+impl LineParser {
+    pub fn new(format: InputFormat) -> LineParser {
+        LineParser {
+            format: format,
+            tskv_keys: TSKV_FIELD_NAMES.iter().map(|name| name.as_bytes().to_vec()).collect(),
+            url_column: 1,
+            wizards_column: 12,
+            json_fields: JsonFields::default(),
+        }
+    }
+
+    /// Takes field from tskv key or JSON key given by name
+    ///
+    /// Names starting with `/` are JSON pointers. Fields are the ones of `JsonFields`,
+    /// `path` and `query` are used by JSON only.
+    ///
+    /// # Examples:
+    ///
+    /// ```
+    /// use logut::{LineParser, InputFormat};
+    /// let mut parser = LineParser::new(InputFormat::Auto);
+    /// parser.set_field("url", "request_uri").unwrap();
+    /// assert_eq!(parser.parse(b"tskv\trequest_uri=/search\turl=/other").unwrap().url, b"/search");
+    /// assert_eq!(parser.parse(br#"{"request_uri": "/search"}"#).unwrap().url, b"/search");
+    ///
+    /// parser.set_field("url", "/request/uri").unwrap();
+    /// assert_eq!(parser.parse(br#"{"request": {"uri": "/search"}}"#).unwrap().url, b"/search");
+    ///
+    /// assert!(parser.set_field("status", "code").is_err());
+    /// ```
+    pub fn set_field(&mut self, field: &str, name: &str) -> Result<(), String> {
+        let pointer = if name.starts_with('/') {
+            name.to_string()
+        } else {
+            format!("/{}", name.replace('~', "~0").replace('/', "~1"))
+        };
+        self.json_fields.set(field, &pointer)?;
+        if let Some(i) = TSKV_FIELD_NAMES.iter().position(|f| *f == field) {
+            self.tskv_keys[i] = name.as_bytes().to_vec();
+        }
+        Ok(())
+    }
+
+    /// Takes `url` or `wizards` field from TSV column, columns are numbered from 0
+    ///
+    /// # Examples:
+    ///
+    /// ```
+    /// use logut::{LineParser, InputFormat};
+    /// let mut parser = LineParser::new(InputFormat::Tsv);
+    /// parser.set_column("url", 0).unwrap();
+    /// parser.set_column("wizards", 2).unwrap();
+    /// let rec = parser.parse(b"http://example.com\t200\twiz1,wiz2").unwrap();
+    /// assert_eq!(rec.url, b"http://example.com");
+    /// assert_eq!(rec.wizards, b"wiz1,wiz2");
+    /// assert!(parser.set_column("method", 3).is_err());
+    /// ```
+    pub fn set_column(&mut self, field: &str, index: usize) -> Result<(), String> {
+        match field {
+            "url" => self.url_column = index,
+            "wizards" => self.wizards_column = index,
+            _ => return Err(format!("unknown column field '{}', expected url or wizards", field)),
+        }
+        Ok(())
+    }
+
+    /// Makes LogRecord from log line, fails on lines which don't match the format
+    ///
+    /// # Examples:
+    ///
+    /// ```
+    /// use logut::{LineParser, InputFormat};
+    /// // auto detection takes it for plain url
+    /// let line = b"2017-01-01\thttp://example.com";
+    /// assert_eq!(LineParser::default().parse(line).unwrap().url, line.as_ref());
+    /// let tsv = LineParser::new(InputFormat::Tsv);
+    /// assert_eq!(tsv.parse(line).unwrap().url, b"http://example.com");
+    ///
+    /// // and this one for TSV
+    /// let line = b"[2001:db8::1]/search";
+    /// assert_eq!(LineParser::default().parse(line).unwrap().url, b"");
+    /// assert_eq!(LineParser::new(InputFormat::Plain).parse(line).unwrap().url, line.as_ref());
+    ///
+    /// let common = LineParser::new(InputFormat::Common);
+    /// assert_eq!(common.parse(b"::1 - - [10/Oct/2000:13:55:36 -0700] \"GET /a HTTP/1.0\" 200 1").unwrap().url, b"/a");
+    /// assert!(common.parse(b"http://example.com").is_err());
+    /// ```
+    pub fn parse<'a>(&self, line: &'a [u8]) -> Result<LogRecord<'a>, ParseError> {
+        let no_match = |format: &str| ParseError(format!("line doesn't match {} log format", format));
+        match self.format {
+            InputFormat::Auto => self.parse_auto(line),
+            InputFormat::Tskv => Ok(parse_tskv_keys(line, &self.tskv_keys)),
+            InputFormat::Tsv => Ok(parse_tsv_columns(line, self.url_column, self.wizards_column)),
+            InputFormat::Plain => Ok(make_record_from_plain_line(line)),
+            InputFormat::Json => parse_json_log_line(line, &self.json_fields),
+            InputFormat::Combined => access_log::parse_combined_log_line(line).ok_or_else(|| no_match("combined")),
+            InputFormat::Common => access_log::parse_common_log_line(line).ok_or_else(|| no_match("common")),
+            InputFormat::Custom(ref format) => format.parse(line).ok_or_else(|| no_match("custom")),
+        }
+    }
+
+    fn parse_auto<'a>(&self, line: &'a [u8]) -> Result<LogRecord<'a>, ParseError> {
+        if line.starts_with(b"{") {
+            return parse_json_log_line(line, &self.json_fields);
+        }
+        if !line.starts_with(b"tskv") && !line.starts_with(b"[") {
+            if twoway::find_bytes(line, b"] \"").is_some() {
+                if let Some(rec) = access_log::parse_combined_log_line(line) {
+                    return Ok(rec);
+                }
+            }
+            Ok(make_record_from_plain_line(line))
+        } else if line.split(|b| *b == b'\t').next().unwrap_or(b"") == b"tskv" {
+            Ok(parse_tskv_keys(line, &self.tskv_keys))
+        } else {
+            Ok(parse_tsv_columns(line, self.url_column, self.wizards_column))
+        }
+    }
+}
+
+thread_local! {
+    static DEFAULT_PARSER: LineParser = LineParser::default();
+}
+
+/// Make LogRecord from log line of variety of formats, fails on malformed JSON lines
+///
+/// # Examples:
+///
+/// ```
+/// use logut::try_parse_log_line;
+/// assert_eq!(try_parse_log_line(br#"{"url": "/search"}"#).unwrap().url, b"/search");
+/// assert!(try_parse_log_line(br#"{"url": "/search"#).is_err());
+/// assert_eq!(try_parse_log_line(b"http://example.com").unwrap().url, b"http://example.com");
+/// ```
+pub fn try_parse_log_line(line: &[u8]) -> Result<LogRecord<'_>, ParseError> {
+    DEFAULT_PARSER.with(|parser| parser.parse(line))
+}
+
 /// Make LogRecord from log line of variety of formats
 ///
+/// Lines starting with `tskv` are tskv, lines starting with `[` are tab-separated,
+/// lines starting with `{` are JSON, the others are access logs or plain urls.
+/// Use `LineParser` when the guess doesn't fit.
+///
 /// # Examples:
 ///
 /// ```
@@ -93,20 +558,21 @@ pub fn parse_tskv_log_line(line: &[u8]) -> LogRecord {
 /// let rec = parse_log_line(b"http://example.com");
 /// assert_eq!(rec.url, b"http://example.com");
 /// assert_eq!(rec.wizards, b"");
+///
+/// let rec = parse_log_line(b"::1 - - [10/Oct/2000:13:55:36 -0700] \"GET /search HTTP/1.1\" 200 10 \"-\" \"curl\"");
+/// assert_eq!(rec.url, b"/search");
+/// assert_eq!(rec.user_agent, b"curl");
+///
+/// let rec = parse_log_line(br#"{"url": "http://example.com", "wizards": "bebebe,zz"}"#);
+/// assert_eq!(rec.url, b"http://example.com");
+/// assert_eq!(rec.wizards, b"bebebe,zz");
 /// ```
 pub fn parse_log_line(line: &[u8]) -> LogRecord {
-    if !line.starts_with(b"tskv") && !line.starts_with(b"[") {
-        make_record_from_plain_line(line)
-    } else {
-        if line.split(|b| *b == b'\t').next().unwrap_or(b"") == b"tskv" {
-            parse_tskv_log_line(line)
-        } else {
-            parse_tab_separated_log_line(line)
-        }
-    }
+    // malformed JSON lines give empty record, use try_parse_log_line to tell them apart
+    try_parse_log_line(line).unwrap_or_default()
 }
 
-/// Returns value of CGI param using naive but quite fast approach
+/// Returns raw (not percent-decoded) value of the first occurrence of CGI param
 ///
 /// # Examples:
 ///
@@ -122,17 +588,140 @@ pub fn parse_log_line(line: &[u8]) -> LogRecord {
 /// assert_eq!(get_cgi_param_value_naive(b"search?pp=18&&text=place=jopa&place=dubai&sdfd", b"place").unwrap(), b"dubai");
 /// assert_eq!(get_cgi_param_value_naive(b"", b"place"), None);
 /// assert_eq!(get_cgi_param_value_naive(b"", b""), None);
+/// assert_eq!(get_cgi_param_value_naive(b"http://example.com/a?b?place=x", b"place"), None);
+/// assert_eq!(get_cgi_param_value_naive(b"http://example.com/?place=x#place=y", b"place").unwrap(), b"x");
 /// ```
 pub fn get_cgi_param_value_naive<'a>(url: &'a [u8], param: &[u8]) -> Option<&'a [u8]> {
-    for part in url.split(|b| *b==b'?' || *b==b'&') {
-        if part.starts_with(param) && part.get(param.len()) == Some(&b'=') {
-            return Some(&part[param.len()+1..]);
+    url::Url::parse(url).param(param)
+}
+
+/// Splits url into part before query, query params and fragment (with leading `#`)
+fn split_cgi_params(url: &[u8]) -> (&[u8], Vec<&[u8]>, &[u8]) {
+    let (url, fragment) = match url.iter().position(|b| *b == b'#') {
+        Some(pos) => (&url[..pos], &url[pos..]),
+        None => (url, b"".as_ref()),
+    };
+    match url.iter().position(|b| *b == b'?') {
+        Some(pos) => (&url[..pos], url[pos + 1..].split(|b| *b == b'&').filter(|p| !p.is_empty()).collect(), fragment),
+        None => (url, Vec::new(), fragment),
+    }
+}
+
+fn join_cgi_params<T: AsRef<[u8]>>(base: &[u8], params: &[T], fragment: &[u8]) -> Vec<u8> {
+    let mut result = base.to_vec();
+    for (i, param) in params.iter().enumerate() {
+        result.push(if i == 0 { b'?' } else { b'&' });
+        result.extend_from_slice(param.as_ref());
+    }
+    result.extend_from_slice(fragment);
+    result
+}
+
+fn cgi_param_name(param: &[u8]) -> &[u8] {
+    param.split(|b| *b == b'=').next().unwrap_or(b"")
+}
+
+/// Removes all occurrences of CGI param
+///
+/// # Examples:
+///
+/// ```
+/// use logut::remove_cgi_param;
+/// assert_eq!(remove_cgi_param(b"search?reqid=1&text=a&reqid=2", b"reqid"), b"search?text=a".to_vec());
+/// assert_eq!(remove_cgi_param(b"search?reqid=1#top", b"reqid"), b"search#top".to_vec());
+/// assert_eq!(remove_cgi_param(b"search?xreqid=1", b"reqid"), b"search?xreqid=1".to_vec());
+/// assert_eq!(remove_cgi_param(b"search", b"reqid"), b"search".to_vec());
+/// ```
+pub fn remove_cgi_param(url: &[u8], name: &[u8]) -> Vec<u8> {
+    let (base, mut params, fragment) = split_cgi_params(url);
+    params.retain(|p| cgi_param_name(p) != name);
+    join_cgi_params(base, &params, fragment)
+}
+
+/// Sets value of CGI param: the first occurrence is replaced and the rest are removed,
+/// param is appended if url has none
+///
+/// # Examples:
+///
+/// ```
+/// use logut::set_cgi_param;
+/// assert_eq!(set_cgi_param(b"search?base=prod&text=a", b"base", b"test"), b"search?base=test&text=a".to_vec());
+/// assert_eq!(set_cgi_param(b"search?nocache=0&nocache=1", b"nocache", b"1"), b"search?nocache=1".to_vec());
+/// assert_eq!(set_cgi_param(b"search?text=a", b"nocache", b"1"), b"search?text=a&nocache=1".to_vec());
+/// assert_eq!(set_cgi_param(b"search", b"nocache", b"1"), b"search?nocache=1".to_vec());
+/// assert_eq!(set_cgi_param(b"http://example.com/?a=1#x", b"a", b""), b"http://example.com/?a=#x".to_vec());
+/// ```
+pub fn set_cgi_param(url: &[u8], name: &[u8], value: &[u8]) -> Vec<u8> {
+    let (base, params, fragment) = split_cgi_params(url);
+    let mut new_param = name.to_vec();
+    new_param.push(b'=');
+    new_param.extend_from_slice(value);
+    let mut result: Vec<&[u8]> = Vec::with_capacity(params.len() + 1);
+    let mut is_set = false;
+    for param in params {
+        if cgi_param_name(param) != name {
+            result.push(param);
+        } else if !is_set {
+            result.push(&new_param);
+            is_set = true;
         }
     }
-    None
+    if !is_set {
+        result.push(&new_param);
+    }
+    join_cgi_params(base, &result, fragment)
 }
 
-/// Extracts host, url and resource parts from given URL
+/// Renames all occurrences of CGI param
+///
+/// # Examples:
+///
+/// ```
+/// use logut::rename_cgi_param;
+/// assert_eq!(rename_cgi_param(b"search?text=a&lr=2", b"text", b"query"), b"search?query=a&lr=2".to_vec());
+/// assert_eq!(rename_cgi_param(b"search?text&texts=1", b"text", b"query"), b"search?query&texts=1".to_vec());
+/// assert_eq!(rename_cgi_param(b"search?lr=2", b"text", b"query"), b"search?lr=2".to_vec());
+/// ```
+pub fn rename_cgi_param(url: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    map_cgi_params(url, from, |param| {
+        let mut renamed = to.to_vec();
+        renamed.extend_from_slice(&param[from.len()..]);
+        renamed
+    })
+}
+
+/// Replaces value of all occurrences of CGI param with result of `f`
+///
+/// # Examples:
+///
+/// ```
+/// use logut::map_cgi_param_values;
+/// let url = map_cgi_param_values(b"search?text=a&lr=2&text=b", b"text", |v| v.to_ascii_uppercase());
+/// assert_eq!(url, b"search?text=A&lr=2&text=B".to_vec());
+/// let url = map_cgi_param_values(b"search?text", b"text", |v| [v, b"x"].concat());
+/// assert_eq!(url, b"search?text=x".to_vec());
+/// ```
+pub fn map_cgi_param_values<F: FnMut(&[u8]) -> Vec<u8>>(url: &[u8], name: &[u8], mut f: F) -> Vec<u8> {
+    map_cgi_params(url, name, |param| {
+        let value = if param.len() > name.len() { &param[name.len() + 1..] } else { b"".as_ref() };
+        let mut result = name.to_vec();
+        result.push(b'=');
+        result.extend(f(value));
+        result
+    })
+}
+
+fn map_cgi_params<F: FnMut(&[u8]) -> Vec<u8>>(url: &[u8], name: &[u8], mut f: F) -> Vec<u8> {
+    let (base, params, fragment) = split_cgi_params(url);
+    let params: Vec<Cow<[u8]>> = params.into_iter()
+        .map(|p| if cgi_param_name(p) == name { Cow::Owned(f(p)) } else { Cow::Borrowed(p) })
+        .collect();
+    join_cgi_params(base, &params, fragment)
+}
+
+/// Extracts host, port and resource parts from given URL
+///
+/// Resource is path (without leading `/`) and query, fragment is dropped.
 ///
 /// # Examples:
 ///
@@ -145,24 +734,14 @@ pub fn get_cgi_param_value_naive<'a>(url: &'a [u8], param: &[u8]) -> Option<&'a
 /// assert_eq!(get_host_port_resource_from_url(b"http://example.com:80"), (b"example.com".as_ref(), b"80".as_ref(), b"".as_ref()));
 /// assert_eq!(get_host_port_resource_from_url(b"http://example.com:80/"), (b"example.com".as_ref(), b"80".as_ref(), b"".as_ref()));
 /// assert_eq!(get_host_port_resource_from_url(b"example.com"), (b"example.com".as_ref(), b"".as_ref(), b"".as_ref()));
+/// assert_eq!(get_host_port_resource_from_url(b"https://u:p@example.com/a?b=1#c"), (b"example.com".as_ref(), b"".as_ref(), b"a?b=1".as_ref()));
+/// assert_eq!(get_host_port_resource_from_url(b"http://[::1]:80/a"), (b"[::1]".as_ref(), b"80".as_ref(), b"a".as_ref()));
 /// ```
 pub fn get_host_port_resource_from_url(url: &[u8]) -> (&[u8], &[u8], &[u8]) {
-    let url = match url.starts_with(b"http://") {
-        true => &url[7..],
-        false => url,
-    };
-
-    let (host_port, resource) = match twoway::find_bytes(url, b"/") {
-        Some(pos) => (&url[..pos], &url[pos+1 ..]),
-        None => (url, b"".as_ref()),
-    };
-
-    let (host, port) = match twoway::find_bytes(host_port, b":") {
-        Some(pos) => (&host_port[..pos], &host_port[pos+1 ..]),
-        None => (host_port, b"".as_ref()),
-    };
-
-    (host, port, resource)
+    let url = url::Url::parse(url);
+    let target = url.request_target();
+    let resource = if target.starts_with(b"/") { &target[1..] } else { target };
+    (url.host, url.port.unwrap_or(b""), resource)
 }
 
 
@@ -8,8 +8,8 @@ pub trait ReadByLine {
     fn process_lines(&mut self, feed_to: &mut FnMut(&[u8])) -> io::Result<()>;
 }
 
-/// Detects file encoding and calls feed_to for each line
-fn process_lines(raw: &mut BufRead, feed_to: &mut FnMut(&[u8])) -> io::Result<()>
+/// Detects file encoding and returns reader of decompressed content
+pub fn decompressed<'a, R: BufRead + 'a>(mut raw: R) -> io::Result<Box<BufRead + 'a>>
 {
     let prefetched = {
         let mut v: Vec<u8> = vec![0; 128];
@@ -18,10 +18,17 @@ fn process_lines(raw: &mut BufRead, feed_to: &mut FnMut(&[u8])) -> io::Result<()
         v
     };
 
-    let mut reader: Box<BufRead> = match GzDecoder::new(Cursor::new(&prefetched)) {
-        Err(_) => { Box::new(Cursor::new(&prefetched).chain(raw)) },
-        Ok(_) => { Box::new(BufReader::new(GzDecoder::new(Cursor::new(&prefetched).chain(raw))?)) },
+    let reader: Box<BufRead + 'a> = match GzDecoder::new(Cursor::new(&prefetched)) {
+        Err(_) => { Box::new(Cursor::new(prefetched).chain(raw)) },
+        Ok(_) => { Box::new(BufReader::new(GzDecoder::new(Cursor::new(prefetched).chain(raw))?)) },
     };
+    Ok(reader)
+}
+
+/// Detects file encoding and calls feed_to for each line
+fn process_lines(raw: &mut BufRead, feed_to: &mut FnMut(&[u8])) -> io::Result<()>
+{
+    let mut reader = decompressed(raw)?;
 
     let mut line = Vec::new();
     while reader.read_until(b'\n', &mut line)? > 0 {
@@ -0,0 +1,255 @@
+//! Zero-copy URL parser
+//!
+//! URLs from logs are often incomplete: `example.com/search?text=a` or even `/search?text=a`.
+//! Input without scheme is taken as authority followed by path, so both forms work.
+
+use std::borrow::Cow;
+
+/// Parts of URL as slices of the original bytes
+///
+/// Host of IPv6 literal keeps its brackets (`[::1]`), so it can be used as `Host` header value.
+#[derive(Debug, PartialEq, Default)]
+pub struct Url<'a> {
+    pub scheme: Option<&'a [u8]>,
+    pub userinfo: Option<&'a [u8]>,
+    pub host: &'a [u8],
+    pub port: Option<&'a [u8]>,
+    /// Path with leading `/` if it has one
+    pub path: &'a [u8],
+    pub query: Option<&'a [u8]>,
+    pub fragment: Option<&'a [u8]>,
+    target: &'a [u8],
+}
+
+impl<'a> Url<'a> {
+    /// Splits URL into parts
+    ///
+    /// # Examples:
+    ///
+    /// ```
+    /// use logut::url::Url;
+    /// let url = Url::parse(b"https://user:pw@example.com:8443/search?text=a&lr=2#top");
+    /// assert_eq!(url.scheme, Some(b"https".as_ref()));
+    /// assert_eq!(url.userinfo, Some(b"user:pw".as_ref()));
+    /// assert_eq!(url.host, b"example.com");
+    /// assert_eq!(url.port, Some(b"8443".as_ref()));
+    /// assert_eq!(url.path, b"/search");
+    /// assert_eq!(url.query, Some(b"text=a&lr=2".as_ref()));
+    /// assert_eq!(url.fragment, Some(b"top".as_ref()));
+    ///
+    /// let url = Url::parse(b"http://[::1]:80/");
+    /// assert_eq!(url.host, b"[::1]");
+    /// assert_eq!(url.port, Some(b"80".as_ref()));
+    ///
+    /// let url = Url::parse(b"/search?text=a");
+    /// assert_eq!(url.scheme, None);
+    /// assert_eq!(url.host, b"");
+    /// assert_eq!(url.path, b"/search");
+    ///
+    /// let url = Url::parse(b"example.com?text=a");
+    /// assert_eq!(url.host, b"example.com");
+    /// assert_eq!(url.path, b"");
+    /// assert_eq!(url.query, Some(b"text=a".as_ref()));
+    /// ```
+    pub fn parse(url: &'a [u8]) -> Url<'a> {
+        let mut result = Url::default();
+        let url = match url.iter().position(|b| *b == b'#') {
+            Some(pos) => {
+                result.fragment = Some(&url[pos + 1..]);
+                &url[..pos]
+            },
+            None => url,
+        };
+        let url = match scheme_len(url) {
+            Some(len) => {
+                result.scheme = Some(&url[..len]);
+                &url[len + 3..]
+            },
+            None => url,
+        };
+        let authority_end = url.iter().position(|b| *b == b'/' || *b == b'?').unwrap_or(url.len());
+        let (authority, target) = url.split_at(authority_end);
+        result.target = target;
+        let (path, query) = match target.iter().position(|b| *b == b'?') {
+            Some(pos) => (&target[..pos], Some(&target[pos + 1..])),
+            None => (target, None),
+        };
+        result.path = path;
+        result.query = query;
+
+        let host_port = match authority.iter().rposition(|b| *b == b'@') {
+            Some(pos) => {
+                result.userinfo = Some(&authority[..pos]);
+                &authority[pos + 1..]
+            },
+            None => authority,
+        };
+        let host_end = if host_port.starts_with(b"[") {
+            host_port.iter().position(|b| *b == b']').map_or(host_port.len(), |pos| pos + 1)
+        } else {
+            host_port.iter().position(|b| *b == b':').unwrap_or(host_port.len())
+        };
+        result.host = &host_port[..host_end];
+        if host_port.get(host_end) == Some(&b':') {
+            result.port = Some(&host_port[host_end + 1..]);
+        }
+        result
+    }
+
+    /// Path and query as they are sent in request line, e.g. `/search?text=a`
+    pub fn request_target(&self) -> &'a [u8] {
+        self.target
+    }
+
+    /// Iterates over raw name/value pairs of query, empty pairs are skipped
+    ///
+    /// # Examples:
+    ///
+    /// ```
+    /// use logut::url::Url;
+    /// let url = Url::parse(b"/search?text=a%20b&&flag&x=1=2");
+    /// let pairs: Vec<_> = url.query_pairs().collect();
+    /// assert_eq!(pairs, vec![(b"text".as_ref(), b"a%20b".as_ref()), (b"flag".as_ref(), b"".as_ref()), (b"x".as_ref(), b"1=2".as_ref())]);
+    /// ```
+    pub fn query_pairs(&self) -> QueryPairs<'a> {
+        QueryPairs { rest: self.query.unwrap_or(b"") }
+    }
+
+    /// Returns raw value of the first occurrence of query param
+    ///
+    /// # Examples:
+    ///
+    /// ```
+    /// use logut::url::{Url, decode_query_component};
+    /// let url = Url::parse(b"http://example.com/search?text=a+b%21&lr=2");
+    /// assert_eq!(url.param(b"text"), Some(b"a+b%21".as_ref()));
+    /// assert_eq!(decode_query_component(url.param(b"text").unwrap()).as_ref(), b"a b!");
+    /// assert_eq!(url.param(b"place"), None);
+    /// ```
+    pub fn param(&self, name: &[u8]) -> Option<&'a [u8]> {
+        self.query_pairs().find(|&(n, _)| n == name).map(|(_, value)| value)
+    }
+}
+
+/// Length of scheme if url starts with `scheme://`
+fn scheme_len(url: &[u8]) -> Option<usize> {
+    let len = url.iter().position(|b| !(b.is_ascii_alphanumeric() || *b == b'+' || *b == b'-' || *b == b'.'))?;
+    if len > 0 && url[0].is_ascii_alphabetic() && url[len..].starts_with(b"://") {
+        Some(len)
+    } else {
+        None
+    }
+}
+
+/// Iterator over query params, see `Url::query_pairs`
+pub struct QueryPairs<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for QueryPairs<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.rest.is_empty() {
+            let end = self.rest.iter().position(|b| *b == b'&').unwrap_or(self.rest.len());
+            let pair = &self.rest[..end];
+            self.rest = if end < self.rest.len() { &self.rest[end + 1..] } else { b"" };
+            if pair.is_empty() {
+                continue;
+            }
+            return Some(match pair.iter().position(|b| *b == b'=') {
+                Some(pos) => (&pair[..pos], &pair[pos + 1..]),
+                None => (pair, b"".as_ref()),
+            });
+        }
+        None
+    }
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn decode(s: &[u8], plus_is_space: bool) -> Cow<'_, [u8]> {
+    if !s.iter().any(|b| *b == b'%' || (plus_is_space && *b == b'+')) {
+        return Cow::Borrowed(s);
+    }
+    let mut result = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        match s[i] {
+            b'%' if i + 2 < s.len() && hex_value(s[i + 1]).is_some() && hex_value(s[i + 2]).is_some() => {
+                result.push(hex_value(s[i + 1]).unwrap() << 4 | hex_value(s[i + 2]).unwrap());
+                i += 3;
+                continue;
+            },
+            b'+' if plus_is_space => result.push(b' '),
+            b => result.push(b),
+        }
+        i += 1;
+    }
+    Cow::Owned(result)
+}
+
+/// Decodes `%XX` sequences, malformed ones are left as they are
+///
+/// # Examples:
+///
+/// ```
+/// use logut::url::percent_decode;
+/// assert_eq!(percent_decode(b"/a%20b+c").as_ref(), b"/a b+c");
+/// assert_eq!(percent_decode(b"%d0%b0%zz%4").as_ref(), "а%zz%4".as_bytes());
+/// assert_eq!(percent_decode(b"plain").as_ref(), b"plain");
+/// ```
+pub fn percent_decode(s: &[u8]) -> Cow<'_, [u8]> {
+    decode(s, false)
+}
+
+/// Decodes query param name or value: like `percent_decode`, but `+` means space
+///
+/// # Examples:
+///
+/// ```
+/// use logut::url::decode_query_component;
+/// assert_eq!(decode_query_component(b"a+b%2B").as_ref(), b"a b+");
+/// ```
+pub fn decode_query_component(s: &[u8]) -> Cow<'_, [u8]> {
+    decode(s, true)
+}
+
+/// Whether byte can't be put in query param value as is
+fn needs_encoding(b: u8) -> bool {
+    b <= b' ' || b >= 0x7f || b"\"#&<>\\^`{|}".contains(&b)
+}
+
+/// Encodes bytes which can't appear in query param value as `%XX`. `%`, `+` and `=` are
+/// kept, so already encoded values pass unchanged.
+///
+/// # Examples:
+///
+/// ```
+/// use logut::url::percent_encode;
+/// assert_eq!(percent_encode(b"a b&c=\"d\"").as_ref(), b"a%20b%26c=%22d%22");
+/// assert_eq!(percent_encode("а".as_bytes()).as_ref(), b"%D0%B0");
+/// assert_eq!(percent_encode(b"a%20b+c").as_ref(), b"a%20b+c");
+/// ```
+pub fn percent_encode(s: &[u8]) -> Cow<'_, [u8]> {
+    if !s.iter().any(|b| needs_encoding(*b)) {
+        return Cow::Borrowed(s);
+    }
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    let mut result = Vec::with_capacity(s.len() + 8);
+    for &b in s {
+        if needs_encoding(b) {
+            result.extend_from_slice(&[b'%', HEX[(b >> 4) as usize], HEX[(b & 0xf) as usize]]);
+        } else {
+            result.push(b);
+        }
+    }
+    Cow::Owned(result)
+}
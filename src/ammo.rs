@@ -3,6 +3,10 @@ use logut::LogRecord;
 use std::io::prelude::*;
 use std;
 use std::io::Cursor;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use twoway;
+use error::{LineError, ProcError};
 
 /// View to ammo data with essential fields extracted
 pub struct BulletData<'a> {
@@ -10,6 +14,28 @@ pub struct BulletData<'a> {
     pub host: &'a [u8],
     pub place: &'a [u8],
     pub wizards: &'a [u8],
+    pub method: &'a [u8],
+    /// Escaped as in tskv, one header per line
+    pub headers: &'a [u8],
+    /// Escaped as in tskv
+    pub body: &'a [u8],
+    /// How many requests this bullet stands for, used by weighted sampling
+    pub weight: f64,
+}
+
+impl<'a> Default for BulletData<'a> {
+    fn default() -> Self {
+        BulletData {
+            resource: b"",
+            host: b"",
+            place: b"",
+            wizards: b"",
+            method: b"",
+            headers: b"",
+            body: b"",
+            weight: 1.0,
+        }
+    }
 }
 
 /// If we need to own data
@@ -18,15 +44,66 @@ pub struct StoredBullet {
     pub host: Vec<u8>,
     pub place: Vec<u8>,
     pub wizards: Vec<u8>,
+    pub method: Vec<u8>,
+    pub headers: Vec<u8>,
+    pub body: Vec<u8>,
+    pub weight: f64,
+}
+
+impl<'a> BulletData<'a> {
+    /// Writes bullet in simple binary form which can be read back with `StoredBullet::read_from`
+    pub fn write_to<W: Write>(&self, to: &mut W) -> std::io::Result<()> {
+        for field in &[self.resource, self.host, self.place, self.wizards, self.method, self.headers, self.body] {
+            let len = field.len() as u32;
+            to.write_all(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8])?;
+            to.write_all(field)?;
+        }
+        let weight = self.weight.to_bits();
+        let mut bytes = [0u8; 8];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = (weight >> (8 * i)) as u8;
+        }
+        to.write_all(&bytes)
+    }
 }
 
 impl StoredBullet {
+    /// Reads bullet written by `BulletData::write_to`
+    pub fn read_from<R: Read>(from: &mut R) -> std::io::Result<StoredBullet> {
+        let mut read_field = || -> std::io::Result<Vec<u8>> {
+            let mut len = [0u8; 4];
+            from.read_exact(&mut len)?;
+            let len = len[0] as usize | (len[1] as usize) << 8 | (len[2] as usize) << 16 | (len[3] as usize) << 24;
+            let mut field = vec![0; len];
+            from.read_exact(&mut field)?;
+            Ok(field)
+        };
+        let mut bullet = StoredBullet {
+            resource: read_field()?,
+            host: read_field()?,
+            place: read_field()?,
+            wizards: read_field()?,
+            method: read_field()?,
+            headers: read_field()?,
+            body: read_field()?,
+            weight: 0.0,
+        };
+        let mut bytes = [0u8; 8];
+        from.read_exact(&mut bytes)?;
+        bullet.weight = f64::from_bits(bytes.iter().rev().fold(0u64, |acc, b| acc << 8 | *b as u64));
+        Ok(bullet)
+    }
+
     pub fn from_data(data: &BulletData) -> StoredBullet {
         StoredBullet {
             resource: data.resource.to_vec(),
             host: data.host.to_vec(),
             place: data.place.to_vec(),
             wizards: data.wizards.to_vec(),
+            method: data.method.to_vec(),
+            headers: data.headers.to_vec(),
+            body: data.body.to_vec(),
+            weight: data.weight,
         }
     }
 
@@ -36,31 +113,175 @@ impl StoredBullet {
             host: &self.host,
             place: &self.place,
             wizards: &self.wizards,
+            method: &self.method,
+            headers: &self.headers,
+            body: &self.body,
+            weight: self.weight,
         }
     }
 }
 
-pub fn write_bullet<W: Write>(bullet: &BulletData, buff: &mut Cursor<Vec<u8>>, to: &mut W) -> std::io::Result<()> {
-    buff.write(b"GET /")?;
-    buff.write(bullet.resource)?;
-    buff.write(
-        b" HTTP/1.0\r\n\
-        User-Agent: tank\r\n\
-        Connection: close\r\n\
-        \r\n")?;
-    write!(to, "{} ", buff.position())?;
-    // write tags
-    {
-        if bullet.place.len() > 0 {
-            to.write(bullet.place)?;
+/// Unescapes headers found in log: tskv-escaped lines or JSON object
+pub fn unescape_headers(headers: &[u8]) -> Cow<'_, [u8]> {
+    if !headers.starts_with(b"{") {
+        return logut::unescape_tskv_value(headers);
+    }
+    match logut::json::object_members(headers) {
+        Ok(members) => {
+            let mut result = Vec::with_capacity(headers.len());
+            for (name, value) in members {
+                result.extend_from_slice(&logut::json::unescape_string(name));
+                result.extend_from_slice(b": ");
+                result.extend_from_slice(&logut::json::unescape_string(value.as_field()));
+                result.push(b'\n');
+            }
+            Cow::Owned(result)
+        },
+        Err(_) => logut::unescape_tskv_value(headers),
+    }
+}
+
+/// Returns non-empty wizard names, names may be quoted as in JSON array
+pub fn wizard_names(wizards: &[u8]) -> Vec<&[u8]> {
+    wizards.split(|b| *b == b',')
+        .map(|w| {
+            let w = trim_spaces(w);
+            if w.len() >= 2 && w[0] == b'"' && w[w.len() - 1] == b'"' { &w[1..w.len() - 1] } else { w }
+        })
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+/// Returns non-empty header lines, each split into name and value
+pub fn header_lines(headers: &[u8]) -> Vec<(&[u8], &[u8])> {
+    headers.split(|b| *b == b'\n')
+        .map(|line| if line.last() == Some(&b'\r') { &line[..line.len() - 1] } else { line })
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.iter().position(|b| *b == b':') {
+            Some(pos) => (&line[..pos], trim_spaces(&line[pos + 1..])),
+            None => (line, b"".as_ref()),
+        })
+        .collect()
+}
+
+fn trim_spaces(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|b| *b != b' ').unwrap_or(s.len());
+    let end = s.iter().rposition(|b| *b != b' ').map_or(start, |p| p + 1);
+    &s[start..end]
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+}
+
+/// Where value of Host header comes from
+#[derive(Clone, PartialEq, Debug)]
+pub enum HostHeader {
+    /// Don't send Host header
+    Omit,
+    /// Host from request url (or from Host header found in log if url has no host)
+    Original,
+    Override(Vec<u8>),
+}
+
+/// Parts of request which are not taken from log
+#[derive(Clone, Debug)]
+pub struct RequestTemplate {
+    pub http_version: HttpVersion,
+    pub host: HostHeader,
+    pub keep_alive: bool,
+    pub user_agent: Vec<u8>,
+}
+
+impl Default for RequestTemplate {
+    fn default() -> Self {
+        RequestTemplate {
+            http_version: HttpVersion::Http10,
+            host: HostHeader::Original,
+            keep_alive: false,
+            user_agent: b"tank".to_vec(),
         }
-        // TODO: shorten wizards names using re.sub(r'([aeiouy])', '', w, flags=re.IGNORECASE)
-        // or map well-known wizard names to some predefined short names
-        for wzrd in bullet.wizards.split(|b| *b == b',').filter(|x| x.len() > 0) {
-            to.write(b"|")?;
-            to.write(wzrd)?;
+    }
+}
+
+impl RequestTemplate {
+    pub fn version_str(&self) -> &'static [u8] {
+        match self.http_version {
+            HttpVersion::Http10 => b"HTTP/1.0",
+            HttpVersion::Http11 => b"HTTP/1.1",
+        }
+    }
+
+    /// Returns value of Host header or None if it shouldn't be sent
+    pub fn host<'a>(&'a self, url_host: &'a [u8], log_headers: &[(&'a [u8], &'a [u8])]) -> Option<&'a [u8]> {
+        match self.host {
+            HostHeader::Omit => None,
+            HostHeader::Override(ref host) => Some(host),
+            HostHeader::Original if url_host.len() > 0 => Some(url_host),
+            HostHeader::Original => log_headers.iter()
+                .find(|&&(name, _)| name.eq_ignore_ascii_case(b"host"))
+                .map(|&(_, value)| value),
         }
     }
+
+    /// Merges template with headers found in log. Host goes first, then User-Agent
+    /// (the one from log wins) and Connection, then the rest of log headers.
+    /// Content-Length is left to serializers.
+    pub fn headers<'a>(&'a self, url_host: &'a [u8], log_headers: &[(&'a [u8], &'a [u8])]) -> Vec<(&'a [u8], &'a [u8])> {
+        let mut result: Vec<(&[u8], &[u8])> = Vec::with_capacity(log_headers.len() + 3);
+        if let Some(host) = self.host(url_host, log_headers) {
+            result.push((b"Host", host));
+        }
+        let user_agent = log_headers.iter().find(|&&(name, _)| name.eq_ignore_ascii_case(b"user-agent"));
+        result.push(match user_agent {
+            Some(&header) => header,
+            None => (b"User-Agent", &self.user_agent),
+        });
+        result.push((b"Connection", if self.keep_alive { b"keep-alive" } else { b"close" }));
+        for &(name, value) in log_headers {
+            // these are ours
+            if name.eq_ignore_ascii_case(b"host") || name.eq_ignore_ascii_case(b"user-agent") ||
+                name.eq_ignore_ascii_case(b"connection") || name.eq_ignore_ascii_case(b"content-length") {
+                continue;
+            }
+            result.push((name, value));
+        }
+        result
+    }
+}
+
+/// Writes bullet in phantom format. HTTP/1.1 requests without host (e.g. path-only log
+/// lines with no Host in their headers) are rejected with `ProcError::Config`.
+pub fn write_bullet<W: Write>(bullet: &BulletData, template: &RequestTemplate, tags: &TagRules, buff: &mut Cursor<Vec<u8>>, to: &mut W) -> Result<(), ProcError> {
+    let method = if bullet.method.len() > 0 { bullet.method } else { b"GET" };
+    let log_headers = unescape_headers(bullet.headers);
+    let log_headers = header_lines(&log_headers);
+    if template.http_version == HttpVersion::Http11 && template.host(bullet.host, &log_headers).is_none() {
+        return Err(ProcError::Config(format!("HTTP/1.1 request '/{}' has no host, set --host-header or use --http-version 1.0",
+            String::from_utf8_lossy(bullet.resource))));
+    }
+    let body = logut::unescape_tskv_value(bullet.body);
+    buff.write_all(method)?;
+    buff.write_all(b" /")?;
+    buff.write_all(bullet.resource)?;
+    buff.write_all(b" ")?;
+    buff.write_all(template.version_str())?;
+    buff.write_all(b"\r\n")?;
+    for (name, value) in template.headers(bullet.host, &log_headers) {
+        buff.write_all(name)?;
+        buff.write_all(b": ")?;
+        buff.write_all(value)?;
+        buff.write_all(b"\r\n")?;
+    }
+    if body.len() > 0 || method == b"POST" || method == b"PUT" {
+        write!(buff, "Content-Length: {}\r\n", body.len())?;
+    }
+    buff.write_all(b"\r\n")?;
+    buff.write_all(&body)?;
+    write!(to, "{} ", buff.position())?;
+    write_tags(bullet, tags, to)?;
     to.write(b"\r\n")?;
     buff.set_position(0);
     std::io::copy(buff, to)?;
@@ -68,14 +289,365 @@ pub fn write_bullet<W: Write>(bullet: &BulletData, buff: &mut Cursor<Vec<u8>>, t
     Ok(())
 }
 
-pub fn make_bullet_data_from_log_record(rec: LogRecord) -> BulletData {
+/// How bullet tags are made of place and wizards names
+#[derive(Clone, Default)]
+pub struct TagRules {
+    /// Short aliases for well-known names
+    pub aliases: HashMap<Vec<u8>, Vec<u8>>,
+    /// Remove vowels from names which have no alias
+    pub strip_vowels: bool,
+    /// Max count of tags per bullet including place
+    pub max_tags: Option<usize>,
+}
+
+impl TagRules {
+    /// Reads aliases from text with `name alias` pair per line. Empty lines and lines
+    /// starting with `#` are ignored.
+    pub fn parse_aliases(text: &str) -> Result<HashMap<Vec<u8>, Vec<u8>>, LineError> {
+        let mut aliases = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(name), Some(alias), None) => {
+                    aliases.insert(name.as_bytes().to_vec(), alias.as_bytes().to_vec());
+                },
+                _ => return Err(LineError::new(n, format!("expected 'name alias' but got '{}'", line))),
+            }
+        }
+        Ok(aliases)
+    }
+
+    pub fn shorten<'a>(&'a self, name: &'a [u8]) -> Cow<'a, [u8]> {
+        if let Some(alias) = self.aliases.get(name) {
+            return Cow::Borrowed(alias);
+        }
+        if self.strip_vowels {
+            let stripped: Vec<u8> = name.iter()
+                .filter(|b| !b"aeiouyAEIOUY".contains(b))
+                .cloned()
+                .collect();
+            if stripped.len() > 0 {
+                return Cow::Owned(stripped);
+            }
+        }
+        Cow::Borrowed(name)
+    }
+}
+
+fn write_tags<W: Write>(bullet: &BulletData, rules: &TagRules, to: &mut W) -> std::io::Result<()> {
+    let mut count = 0;
+    let max_tags = rules.max_tags.unwrap_or(usize::max_value());
+    if bullet.place.len() > 0 && max_tags > 0 {
+        to.write_all(&rules.shorten(bullet.place))?;
+        count += 1;
+    }
+    for wzrd in wizard_names(bullet.wizards) {
+        if count >= max_tags {
+            break;
+        }
+        to.write_all(b"|")?;
+        to.write_all(&rules.shorten(wzrd))?;
+        count += 1;
+    }
+    Ok(())
+}
+
+/// Ammo file formats understood by Yandex.Tank
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutputFormat {
+    /// Size line with tags followed by raw request
+    Phantom,
+    /// `[Header: value]` lines and `/uri tag` lines
+    Uri,
+    /// JSON object per line, as Pandora likes it
+    Json,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Phantom
+    }
+}
+
+/// Headers already written to uri-style ammo. Tank applies header line to all uris
+/// below it and a header can't be unset, so the headers of the first bullet are written
+/// at the top and all other bullets must have the same header names. Changed values
+/// are written before the uri they belong to.
+#[derive(Default)]
+pub struct UriStyleState {
+    headers: Vec<(Vec<u8>, Vec<u8>)>,
+    started: bool,
+}
+
+/// Bullet can't be written as uri, output format has to be changed
+fn uri_style_error(message: String) -> ProcError {
+    ProcError::Config(format!("{}, use phantom format instead", message))
+}
+
+/// Writes bullet in uri-style format. Such ammo can't carry method and body, so only
+/// GET requests without body are accepted. Headers which appear or disappear between
+/// requests can't be expressed either, such bullets are rejected with `ProcError::Config`.
+pub fn write_uri_bullet<W: Write>(bullet: &BulletData, template: &RequestTemplate, tags: &TagRules, state: &mut UriStyleState, to: &mut W) -> Result<(), ProcError> {
+    if (bullet.method.len() > 0 && bullet.method != b"GET") || bullet.body.len() > 0 {
+        return Err(uri_style_error("uri-style ammo can't carry request method and body".to_string()));
+    }
+    let log_headers = unescape_headers(bullet.headers);
+    let log_headers = header_lines(&log_headers);
+    let headers = template.headers(bullet.host, &log_headers);
+    if state.started {
+        let find = |headers: &[(&[u8], &[u8])], name: &[u8]| headers.iter().any(|&(n, _)| n.eq_ignore_ascii_case(name));
+        if let Some(&(ref name, _)) = state.headers.iter().find(|&&(ref name, _)| !find(&headers, name)) {
+            return Err(uri_style_error(format!("uri-style ammo can't unset header '{}' of previous requests", String::from_utf8_lossy(name))));
+        }
+        if let Some(&(name, _)) = headers.iter().find(|&&(name, _)| !state.headers.iter().any(|&(ref n, _)| n.eq_ignore_ascii_case(name))) {
+            return Err(uri_style_error(format!("uri-style ammo can't add header '{}' missing in previous requests", String::from_utf8_lossy(name))));
+        }
+    }
+    state.started = true;
+    for (name, value) in headers {
+        let pos = state.headers.iter().position(|&(ref n, _)| n.eq_ignore_ascii_case(name));
+        match pos {
+            Some(pos) if state.headers[pos].1 == value => continue,
+            Some(pos) => state.headers[pos] = (name.to_vec(), value.to_vec()),
+            None => state.headers.push((name.to_vec(), value.to_vec())),
+        }
+        to.write_all(b"[")?;
+        to.write_all(name)?;
+        to.write_all(b": ")?;
+        to.write_all(value)?;
+        to.write_all(b"]\n")?;
+    }
+    to.write_all(b"/")?;
+    to.write_all(bullet.resource)?;
+    // tags may all be cut off by max_tags
+    let mut tag = Vec::new();
+    write_tags(bullet, tags, &mut tag)?;
+    if !tag.is_empty() {
+        to.write_all(b" ")?;
+        to.write_all(&tag)?;
+    }
+    to.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Writes bullet as a JSON line for Pandora's jsonline ammo provider.
+/// Host goes to separate field and not to headers.
+pub fn write_json_bullet<W: Write>(bullet: &BulletData, template: &RequestTemplate, tags: &TagRules, to: &mut W) -> std::io::Result<()> {
+    let method = if bullet.method.len() > 0 { bullet.method } else { b"GET" };
+    let log_headers = unescape_headers(bullet.headers);
+    let log_headers = header_lines(&log_headers);
+    let mut tag = Vec::new();
+    write_tags(bullet, tags, &mut tag)?;
+
+    to.write_all(b"{\"host\":")?;
+    write_json_string(template.host(bullet.host, &log_headers).unwrap_or(b""), to)?;
+    to.write_all(b",\"method\":")?;
+    write_json_string(method, to)?;
+    to.write_all(b",\"uri\":\"/")?;
+    write_json_string_content(bullet.resource, to)?;
+    to.write_all(b"\",\"headers\":{")?;
+    let mut first = true;
+    for (name, value) in template.headers(bullet.host, &log_headers) {
+        if name == b"Host" {
+            continue;
+        }
+        if !first {
+            to.write_all(b",")?;
+        }
+        first = false;
+        write_json_string(name, to)?;
+        to.write_all(b":")?;
+        write_json_string(value, to)?;
+    }
+    to.write_all(b"},\"tag\":")?;
+    write_json_string(&tag, to)?;
+    to.write_all(b",\"body\":")?;
+    write_json_string(&logut::unescape_tskv_value(bullet.body), to)?;
+    to.write_all(b"}\n")?;
+    Ok(())
+}
+
+pub fn write_json_string<W: Write>(s: &[u8], to: &mut W) -> std::io::Result<()> {
+    to.write_all(b"\"")?;
+    write_json_string_content(s, to)?;
+    to.write_all(b"\"")
+}
+
+/// Writes string escaped for JSON without quotes. Invalid UTF-8 sequences are replaced.
+fn write_json_string_content<W: Write>(s: &[u8], to: &mut W) -> std::io::Result<()> {
+    let s = String::from_utf8_lossy(s);
+    let bytes = s.as_bytes();
+    let mut start = 0;
+    for (i, &b) in bytes.iter().enumerate() {
+        let escaped: &[u8] = match b {
+            b'"' => b"\\\"",
+            b'\\' => b"\\\\",
+            b'\n' => b"\\n",
+            b'\r' => b"\\r",
+            b'\t' => b"\\t",
+            0..=0x1f => b"",
+            _ => continue,
+        };
+        to.write_all(&bytes[start..i])?;
+        if escaped.len() > 0 {
+            to.write_all(escaped)?;
+        } else {
+            write!(to, "\\u{:04x}", b)?;
+        }
+        start = i + 1;
+    }
+    to.write_all(&bytes[start..])
+}
+
+/// Reads phantom ammo (size line with tags, then raw request) back into bullets
+///
+/// Tag `place|wizard|...` gives place and wizards. Request line, Host header and
+/// body go to their fields, the other headers except Connection and Content-Length
+/// are kept, so bullet written again makes the same request.
+pub struct PhantomReader<R: BufRead> {
+    reader: R,
+    offset: u64,
+    lines: u64,
+    bullet_line: u64,
+}
+
+impl<R: BufRead> PhantomReader<R> {
+    pub fn new(reader: R) -> PhantomReader<R> {
+        PhantomReader {
+            reader: reader,
+            offset: 0,
+            lines: 0,
+            bullet_line: 0,
+        }
+    }
+
+    /// Number of line where the last read bullet starts, counting from 1
+    pub fn line(&self) -> u64 {
+        self.bullet_line
+    }
+
+    /// Reads next bullet along with its raw request, returns None at the end of input
+    pub fn read_bullet(&mut self) -> std::io::Result<Option<(Vec<u8>, StoredBullet)>> {
+        let mut size_line = Vec::new();
+        let start = loop {
+            let start = self.offset;
+            size_line.clear();
+            let count = self.reader.read_until(b'\n', &mut size_line)?;
+            if count == 0 {
+                return Ok(None);
+            }
+            self.offset += count as u64;
+            self.lines += 1;
+            if !trim_crlf(&size_line).is_empty() {
+                break start;
+            }
+        };
+        self.bullet_line = self.lines;
+        let size_line = trim_crlf(&size_line);
+        let (size, tag) = parse_size_line(size_line)
+            .ok_or_else(|| invalid_ammo(start, format!("bad size line '{}'", String::from_utf8_lossy(size_line))))?;
+        // size comes from input, so request grows as it is read instead of being allocated up front
+        let mut request = Vec::new();
+        self.reader.by_ref().take(size as u64).read_to_end(&mut request)?;
+        if request.len() < size {
+            return Err(invalid_ammo(start, format!("unexpected end of input, bullet should have {} bytes", size)));
+        }
+        self.offset += size as u64;
+        self.lines += request.iter().filter(|b| **b == b'\n').count() as u64;
+        let bullet = parse_request(&request, tag).map_err(|e| invalid_ammo(start, e))?;
+        Ok(Some((request, bullet)))
+    }
+}
+
+fn invalid_ammo(offset: u64, message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("bullet at offset {}: {}", offset, message))
+}
+
+/// Strips line end, CRLF or LF
+pub fn trim_crlf(line: &[u8]) -> &[u8] {
+    let end = line.iter().rposition(|b| *b != b'\n' && *b != b'\r').map_or(0, |p| p + 1);
+    &line[..end]
+}
+
+/// Splits size line of phantom bullet without line end into request size and tag,
+/// returns None if size is not a number
+pub fn parse_size_line(line: &[u8]) -> Option<(usize, &[u8])> {
+    let (size, tag) = match line.iter().position(|b| *b == b' ') {
+        Some(pos) => (&line[..pos], &line[pos + 1..]),
+        None => (line, b"".as_ref()),
+    };
+    std::str::from_utf8(size).ok().and_then(|s| s.parse::<usize>().ok()).map(|size| (size, tag))
+}
+
+/// Makes bullet of raw HTTP request and phantom tag
+fn parse_request(request: &[u8], tag: &[u8]) -> Result<StoredBullet, String> {
+    let (head, body) = match twoway::find_bytes(request, b"\r\n\r\n") {
+        Some(pos) => (&request[..pos], &request[pos + 4..]),
+        None => match twoway::find_bytes(request, b"\n\n") {
+            Some(pos) => (&request[..pos], &request[pos + 2..]),
+            None => (request, b"".as_ref()),
+        },
+    };
+    let request_line = trim_crlf(head.split(|b| *b == b'\n').next().unwrap_or(b""));
+    let mut parts = request_line.split(|b| *b == b' ');
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) if !method.is_empty() && !target.is_empty() => (method, target),
+        _ => return Err(format!("bad request line '{}'", String::from_utf8_lossy(request_line))),
+    };
+    let (url_host, _, resource) = logut::get_host_port_resource_from_url(target);
+
+    let mut host = url_host;
+    let mut headers: Vec<u8> = Vec::new();
+    for (name, value) in header_lines(&head[request_line.len()..]) {
+        if name.eq_ignore_ascii_case(b"host") {
+            if host.is_empty() {
+                host = value;
+            }
+        } else if !name.eq_ignore_ascii_case(b"connection") && !name.eq_ignore_ascii_case(b"content-length") {
+            if !headers.is_empty() {
+                headers.push(b'\n');
+            }
+            headers.extend_from_slice(name);
+            headers.extend_from_slice(b": ");
+            headers.extend_from_slice(value);
+        }
+    }
+
+    let mut tags = tag.split(|b| *b == b'|');
+    let place = match tags.next() {
+        Some(place) if !tag.is_empty() => place,
+        _ => logut::get_cgi_param_value_naive(resource, b"place").unwrap_or(b""),
+    };
+    let wizards: Vec<&[u8]> = tags.filter(|w| !w.is_empty()).collect();
+    Ok(StoredBullet {
+        resource: resource.to_vec(),
+        host: host.to_vec(),
+        place: place.to_vec(),
+        wizards: wizards.join(&b',').to_vec(),
+        method: method.to_vec(),
+        headers: logut::escape_tskv_value(&headers).into_owned(),
+        body: logut::escape_tskv_value(body).into_owned(),
+        weight: 1.0,
+    })
+}
+
+pub fn make_bullet_data_from_log_record<'a>(rec: &'a LogRecord) -> BulletData<'a> {
     let (host, _, resource) = logut::get_host_port_resource_from_url(rec.url);
+    let host = if host.is_empty() { rec.host } else { host };
     let place = logut::get_cgi_param_value_naive(resource, b"place").unwrap_or(b"");
     BulletData {
         resource: resource,
         host: host,
         place: place,
-        wizards: rec.wizards
+        wizards: rec.wizards,
+        method: rec.method,
+        headers: &rec.headers,
+        body: &rec.body,
+        ..Default::default()
     }
 }
 
@@ -91,8 +663,9 @@ mod tests {
             let rec = LogRecord {
                 url: b"http://aaaa.bazar.bububu.net:12022/search?base=default.bazar-exp.fro01ht.bububu.ru&ip=&ip-xxds=1203&bububuuid=449823&puid=3975&currency=RUR&fuid=&place=prime&history_itemsts=",
                 wizards: b"wiz1,wiz2,wiz3",
+                ..Default::default()
             };
-            let data = super::make_bullet_data_from_log_record(rec);
+            let data = super::make_bullet_data_from_log_record(&rec);
             assert_eq!(data.resource, b"search?base=default.bazar-exp.fro01ht.bububu.ru&ip=&ip-xxds=1203&bububuuid=449823&puid=3975&currency=RUR&fuid=&place=prime&history_itemsts=".as_ref());
             assert_eq!(data.place, b"prime".as_ref());
             assert_eq!(data.host, b"aaaa.bazar.bububu.net".as_ref());
@@ -102,13 +675,81 @@ mod tests {
             let rec = LogRecord {
                 url: b"",
                 wizards: b"",
+                ..Default::default()
             };
-            let data = super::make_bullet_data_from_log_record(rec);
+            let data = super::make_bullet_data_from_log_record(&rec);
             assert_eq!(data.resource, b"".as_ref());
             assert_eq!(data.place, b"".as_ref());
             assert_eq!(data.host, b"".as_ref());
             assert_eq!(data.wizards, b"".as_ref());
         }
+        {
+            let rec = LogRecord { url: b"/search?place=prime", host: b"example.com", ..Default::default() };
+            let data = super::make_bullet_data_from_log_record(&rec);
+            assert_eq!(data.host, b"example.com".as_ref());
+            assert_eq!(data.resource, b"search?place=prime".as_ref());
+            assert_eq!(data.place, b"prime".as_ref());
+        }
+        {
+            let rec = logut::parse_tskv_log_line(b"tskv\turl=http://example.com/api\tmethod=POST\theaders=Cookie: a=b\tbody={}");
+            let data = super::make_bullet_data_from_log_record(&rec);
+            assert_eq!(data.method, b"POST".as_ref());
+            assert_eq!(data.headers, b"Cookie: a=b".as_ref());
+            assert_eq!(data.body, b"{}".as_ref());
+        }
+    }
+
+    #[test]
+    fn test_stored_bullet_binary_form() {
+        use std::io::Cursor;
+        use super::StoredBullet;
+        let b = BulletData {
+            host: b"localhost",
+            resource: b"search?place=dubai",
+            place: b"dubai",
+            wizards: b"wiz1,wiz2",
+            method: b"POST",
+            headers: b"Cookie: a=b",
+            body: b"",
+            weight: 2.5,
+        };
+        let mut buff = Cursor::new(vec![]);
+        b.write_to(&mut buff).unwrap();
+        b.write_to(&mut buff).unwrap();
+        buff.set_position(0);
+        for _ in 0..2 {
+            let stored = StoredBullet::read_from(&mut buff).unwrap();
+            let data = stored.get_data();
+            assert_eq!(data.host, b.host);
+            assert_eq!(data.resource, b.resource);
+            assert_eq!(data.place, b.place);
+            assert_eq!(data.wizards, b.wizards);
+            assert_eq!(data.method, b.method);
+            assert_eq!(data.headers, b.headers);
+            assert_eq!(data.body, b.body);
+            assert_eq!(data.weight, b.weight);
+        }
+        assert!(StoredBullet::read_from(&mut buff).is_err());
+    }
+
+    #[test]
+    fn test_json_headers_and_wizards() {
+        use std::io::Cursor;
+        let b = BulletData {
+            resource: b"api",
+            method: b"POST",
+            headers: br#"{"Cookie": "a=\"b\"", "Content-Type": "application/json"}"#,
+            wizards: br#""wiz1", "wiz2""#,
+            ..Default::default()
+        };
+        assert_eq!(super::wizard_names(b.wizards), vec![b"wiz1".as_ref(), b"wiz2".as_ref()]);
+        assert_eq!(super::wizard_names(b",a,,b "), vec![b"a".as_ref(), b"b".as_ref()]);
+        let mut buff = Cursor::new(vec![]);
+        let mut dest = Cursor::new(vec![]);
+        super::write_bullet(&b, &Default::default(), &Default::default(), &mut buff, &mut dest).unwrap();
+        let result = String::from_utf8(dest.into_inner()).unwrap();
+        assert!(result.contains(" |wiz1|wiz2\r\n"), "{}", result);
+        assert!(result.contains("\r\nCookie: a=\"b\"\r\nContent-Type: application/json\r\n"), "{}", result);
     }
 
     #[test]
@@ -116,14 +757,266 @@ mod tests {
         use std::io::Cursor;
         let b = BulletData {
             host: b"localhost",
-            resource: b"/search?place=dubai",
+            resource: b"search?place=dubai",
             place: b"dubai",
-            wizards: b"",
+            wizards: b"wiz1,wiz2",
+            ..Default::default()
+        };
+        let mut buff = Cursor::new(vec![]);
+        let mut dest = Cursor::new(vec![]);
+        super::write_bullet(&b, &Default::default(), &Default::default(), &mut buff, &mut dest).unwrap();
+        let request = "GET /search?place=dubai HTTP/1.0\r\nHost: localhost\r\nUser-Agent: tank\r\nConnection: close\r\n\r\n";
+        assert_eq!(String::from_utf8(dest.into_inner()).unwrap(), format!("{} dubai|wiz1|wiz2\r\n{}\r\n", request.len(), request));
+    }
+
+    #[test]
+    fn test_request_template() {
+        use std::io::Cursor;
+        use super::{RequestTemplate, HttpVersion, HostHeader};
+        let b = BulletData {
+            host: b"localhost",
+            resource: b"search",
+            headers: b"Host: example.com\\nConnection: close",
+            ..Default::default()
+        };
+        let write = |template: &RequestTemplate| -> String {
+            let mut buff = Cursor::new(vec![]);
+            let mut dest = Cursor::new(vec![]);
+            super::write_bullet(&b, template, &Default::default(), &mut buff, &mut dest).unwrap();
+            let result = String::from_utf8(dest.into_inner()).unwrap();
+            result[result.find('\n').unwrap() + 1..].to_string()
+        };
+        let mut template = RequestTemplate {
+            http_version: HttpVersion::Http10,
+            host: HostHeader::Omit,
+            keep_alive: true,
+            user_agent: b"yandex-tank".to_vec(),
         };
-        let mut buff = Cursor::new(vec![0; 15]);
-        let mut dest = Cursor::new(vec![0; 15]);
-        super::write_bullet(&b, &mut buff, &mut dest).unwrap();
+        assert_eq!(write(&template), "GET /search HTTP/1.0\r\nUser-Agent: yandex-tank\r\nConnection: keep-alive\r\n\r\n\r\n");
+        template.host = HostHeader::Override(b"test.example.com".to_vec());
+        assert_eq!(write(&template), "GET /search HTTP/1.0\r\nHost: test.example.com\r\nUser-Agent: yandex-tank\r\nConnection: keep-alive\r\n\r\n\r\n");
+        template.host = HostHeader::Original;
+        template.http_version = HttpVersion::Http11;
+        assert_eq!(write(&template), "GET /search HTTP/1.1\r\nHost: localhost\r\nUser-Agent: yandex-tank\r\nConnection: keep-alive\r\n\r\n\r\n");
+        // host from log headers is used only if url has no host
+        let log_headers = [(b"Host".as_ref(), b"example.com".as_ref())];
+        assert_eq!(template.host(b"", &log_headers), Some(b"example.com".as_ref()));
+        assert_eq!(template.host(b"", &[]), None);
+    }
+
+    #[test]
+    fn path_only_bullet() {
+        use std::io::Cursor;
+        // e.g. tskv url=/search without Host in headers
+        let b = BulletData { resource: b"search", ..Default::default() };
+        let mut buff = Cursor::new(vec![]);
+        let mut dest = Cursor::new(vec![]);
+        super::write_bullet(&b, &Default::default(), &Default::default(), &mut buff, &mut dest).unwrap();
+        let ammo = String::from_utf8(dest.into_inner()).unwrap();
+        assert!(ammo.contains("\r\nGET /search HTTP/1.0\r\nUser-Agent: tank\r\n"), "{}", ammo);
+        let mut problems = Vec::new();
+        ::validate::validate_phantom(Cursor::new(ammo.as_bytes()), &mut |p| problems.push(p)).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        // HTTP/1.1 requires Host header
+        let template = super::RequestTemplate { http_version: super::HttpVersion::Http11, ..Default::default() };
+        let err = super::write_bullet(&b, &template, &Default::default(), &mut buff, &mut Cursor::new(vec![])).unwrap_err();
+        assert_eq!(err.exit_code(), 78);
+    }
+
+    #[test]
+    fn test_tag_rules() {
+        use super::{TagRules, write_tags};
+        let b = BulletData {
+            place: b"prime",
+            wizards: b"images,video_wizard,market,,news",
+            ..Default::default()
+        };
+        let tags = |rules: &TagRules| -> String {
+            let mut dest = Vec::new();
+            write_tags(&b, rules, &mut dest).unwrap();
+            String::from_utf8(dest).unwrap()
+        };
+        let mut rules = TagRules::default();
+        assert_eq!(tags(&rules), "prime|images|video_wizard|market|news");
+        rules.aliases = TagRules::parse_aliases("# comment\n\nvideo_wizard  vw\nprime\tP\n").unwrap();
+        assert_eq!(tags(&rules), "P|images|vw|market|news");
+        rules.strip_vowels = true;
+        assert_eq!(tags(&rules), "P|mgs|vw|mrkt|nws");
+        rules.max_tags = Some(3);
+        assert_eq!(tags(&rules), "P|mgs|vw");
+        rules.max_tags = Some(0);
+        assert_eq!(tags(&rules), "");
+        assert_eq!(rules.shorten(b"aye").as_ref(), b"aye");
+        assert!(TagRules::parse_aliases("video_wizard").is_err());
+        assert!(TagRules::parse_aliases("a b c").is_err());
+    }
+
+    #[test]
+    fn test_write_uri_bullet() {
+        use std::io::Cursor;
+        use super::{write_uri_bullet, UriStyleState, TagRules};
+        let mut state = UriStyleState::default();
+        let mut dest = Cursor::new(vec![]);
+        let b1 = BulletData { resource: b"search?place=dubai", place: b"dubai", wizards: b"wiz1", headers: b"Cookie: a=b", ..Default::default() };
+        let b2 = BulletData { resource: b"search", headers: b"Cookie: a=b\\nConnection: keep-alive", ..Default::default() };
+        let b3 = BulletData { resource: b"search?x=2", headers: b"cookie: c=d", ..Default::default() };
+        for b in &[&b1, &b2, &b3] {
+            write_uri_bullet(b, &Default::default(), &Default::default(), &mut state, &mut dest).unwrap();
+        }
+        assert_eq!(String::from_utf8(dest.into_inner()).unwrap(),
+            "[User-Agent: tank]\n[Connection: close]\n[Cookie: a=b]\n/search?place=dubai dubai|wiz1\n/search\n[cookie: c=d]\n/search?x=2\n");
+
+        let no_tags = TagRules { max_tags: Some(0), ..Default::default() };
+        let mut dest = Cursor::new(vec![]);
+        write_uri_bullet(&b1, &Default::default(), &no_tags, &mut UriStyleState::default(), &mut dest).unwrap();
+        assert!(String::from_utf8(dest.into_inner()).unwrap().ends_with("\n/search?place=dubai\n"));
+
+        let post = BulletData { resource: b"api", method: b"POST", ..Default::default() };
+        assert!(write_uri_bullet(&post, &Default::default(), &Default::default(), &mut state, &mut Cursor::new(vec![])).is_err());
+
+        // cookie of the first request would be sent with the second one
+        let with_cookie = BulletData { resource: b"a", headers: b"Cookie: a=b", ..Default::default() };
+        let without_cookie = BulletData { resource: b"b", ..Default::default() };
+        let mut state = UriStyleState::default();
+        write_uri_bullet(&with_cookie, &Default::default(), &Default::default(), &mut state, &mut Cursor::new(vec![])).unwrap();
+        let err = write_uri_bullet(&without_cookie, &Default::default(), &Default::default(), &mut state, &mut Cursor::new(vec![])).unwrap_err();
+        assert_eq!(err.exit_code(), 78);
+        assert!(err.to_string().contains("can't unset header 'Cookie'"), "{}", err);
+        let mut state = UriStyleState::default();
+        write_uri_bullet(&without_cookie, &Default::default(), &Default::default(), &mut state, &mut Cursor::new(vec![])).unwrap();
+        assert!(write_uri_bullet(&with_cookie, &Default::default(), &Default::default(), &mut state, &mut Cursor::new(vec![])).is_err());
+    }
+
+    #[test]
+    fn test_write_json_bullet() {
+        use std::io::Cursor;
+        let b = BulletData {
+            host: b"example.com",
+            resource: b"search?text=\"a\"",
+            place: b"dubai",
+            wizards: b"wiz1,wiz2",
+            ..Default::default()
+        };
+        let mut dest = Cursor::new(vec![]);
+        super::write_json_bullet(&b, &Default::default(), &Default::default(), &mut dest).unwrap();
+        assert_eq!(String::from_utf8(dest.into_inner()).unwrap(),
+            "{\"host\":\"example.com\",\"method\":\"GET\",\"uri\":\"/search?text=\\\"a\\\"\",\"headers\":{\"User-Agent\":\"tank\",\"Connection\":\"close\"},\"tag\":\"dubai|wiz1|wiz2\",\"body\":\"\"}\n");
 
-        //TODO: check content
+        let b = BulletData {
+            resource: b"api",
+            method: b"POST",
+            headers: b"User-Agent: curl\\nX-Ctl: \x01\\nConnection: close",
+            body: b"line1\\nline2\\t\xff",
+            ..Default::default()
+        };
+        let mut dest = Cursor::new(vec![]);
+        super::write_json_bullet(&b, &Default::default(), &Default::default(), &mut dest).unwrap();
+        assert_eq!(String::from_utf8(dest.into_inner()).unwrap(),
+            "{\"host\":\"\",\"method\":\"POST\",\"uri\":\"/api\",\"headers\":{\"User-Agent\":\"curl\",\"Connection\":\"close\",\"X-Ctl\":\"\\u0001\"},\"tag\":\"\",\"body\":\"line1\\nline2\\t\u{fffd}\"}\n");
+    }
+
+    #[test]
+    fn test_write_post_bullet() {
+        use std::io::Cursor;
+        let b = BulletData {
+            resource: b"api/v1",
+            method: b"POST",
+            headers: b"Content-Type: application/json\\nUser-Agent: curl\\nContent-Length: 100500",
+            body: b"{\"a\":\\t1}",
+            ..Default::default()
+        };
+        let mut buff = Cursor::new(vec![]);
+        let mut dest = Cursor::new(vec![]);
+        super::write_bullet(&b, &Default::default(), &Default::default(), &mut buff, &mut dest).unwrap();
+        let request = "POST /api/v1 HTTP/1.0\r\nUser-Agent: curl\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: 8\r\n\r\n{\"a\":\t1}";
+        assert_eq!(String::from_utf8(dest.into_inner()).unwrap(), format!("{} \r\n{}\r\n", request.len(), request));
+    }
+
+    #[test]
+    fn test_json_log_body() {
+        use std::io::Cursor;
+        let parser = LineParser::new(InputFormat::Json);
+        let lines: [(&[u8], &str, &str); 2] = [
+            (br#"{"url":"/api","method":"POST","headers":"Host: a.ru","body":"caf\u00e9"}"#, "café", r#""body":"café""#),
+            (br#"{"url":"/api","method":"POST","headers":"Host: a.ru","body":"{\"x\":\"A\/b\"}"}"#, r#"{"x":"A/b"}"#, r#""body":"{\"x\":\"A/b\"}""#),
+        ];
+        for &(line, body, json_body) in &lines {
+            let rec = parser.parse(line).unwrap();
+            let b = super::make_bullet_data_from_log_record(&rec);
+            let mut buff = Cursor::new(vec![]);
+            let mut dest = Cursor::new(vec![]);
+            super::write_bullet(&b, &Default::default(), &Default::default(), &mut buff, &mut dest).unwrap();
+            let result = String::from_utf8(dest.into_inner()).unwrap();
+            assert!(result.ends_with(&format!("\r\nContent-Length: {}\r\n\r\n{}\r\n", body.len(), body)), "{}", result);
+            let mut dest = Cursor::new(vec![]);
+            super::write_json_bullet(&b, &Default::default(), &Default::default(), &mut dest).unwrap();
+            let result = String::from_utf8(dest.into_inner()).unwrap();
+            assert!(result.contains(json_body), "{}", result);
+        }
+    }
+
+    #[test]
+    fn test_read_phantom_ammo() {
+        use std::io::Cursor;
+        use super::PhantomReader;
+        let bullets = [
+            BulletData { host: b"localhost", resource: b"search?place=dubai", place: b"dubai", wizards: b"wiz1,wiz2", ..Default::default() },
+            BulletData { resource: b"api/v1", method: b"POST", headers: b"Content-Type: application/json\\nUser-Agent: curl", body: b"{\"a\":\\t1}\\r\\n", ..Default::default() },
+        ];
+        let write = |b: &BulletData, to: &mut Cursor<Vec<u8>>| {
+            let mut buff = Cursor::new(vec![]);
+            super::write_bullet(b, &Default::default(), &Default::default(), &mut buff, to).unwrap();
+        };
+        let mut ammo = Cursor::new(vec![]);
+        for b in &bullets {
+            write(b, &mut ammo);
+        }
+        let ammo = ammo.into_inner();
+
+        let mut reader = PhantomReader::new(Cursor::new(ammo.clone()));
+        let mut rewritten = Cursor::new(vec![]);
+        let mut count = 0;
+        while let Some((request, b)) = reader.read_bullet().unwrap() {
+            let b = b.get_data();
+            assert!(request.starts_with(b"GET /search") || request.starts_with(b"POST /api/v1"));
+            if count == 0 {
+                assert_eq!(b.host, b"localhost");
+                assert_eq!(b.place, b"dubai");
+                assert_eq!(b.wizards, b"wiz1,wiz2");
+            } else {
+                assert_eq!(b.method, b"POST");
+                assert_eq!(b.body, b"{\"a\":\\t1}\\r\\n");
+            }
+            count += 1;
+            write(&b, &mut rewritten);
+        }
+        assert_eq!(count, 2);
+        assert_eq!(String::from_utf8(rewritten.into_inner()).unwrap(), String::from_utf8(ammo.clone()).unwrap());
+
+        // Tank also accepts LF line ends
+        let mut reader = PhantomReader::new(Cursor::new(b"\n41 tag\nGET /a?place=x HTTP/1.0\nHost: example.com\n\n\n".to_vec()));
+        let (_, b) = reader.read_bullet().unwrap().unwrap();
+        assert_eq!((b.host.as_ref(), b.resource.as_ref(), b.place.as_ref()), (b"example.com".as_ref(), b"a?place=x".as_ref(), b"tag".as_ref()));
+        assert_eq!(reader.line(), 2);
+        assert!(reader.read_bullet().unwrap().is_none());
+        let mut reader = PhantomReader::new(Cursor::new(b"5\r\nGET /\r\n\r\n3 x\r\nabc\r\n".to_vec()));
+        assert!(reader.read_bullet().unwrap().is_some());
+        assert!(reader.read_bullet().is_err());
+        assert_eq!(reader.line(), 4);
+
+        let mut reader = PhantomReader::new(Cursor::new(ammo[..ammo.len() - 10].to_vec()));
+        assert!(reader.read_bullet().unwrap().is_some());
+        let error = reader.read_bullet().err().unwrap();
+        let mut first = Cursor::new(vec![]);
+        write(&bullets[0], &mut first);
+        assert!(error.to_string().starts_with(&format!("bullet at offset {}: unexpected end of input", first.get_ref().len())), "{}", error);
+        assert!(PhantomReader::new(Cursor::new(b"12x\r\n".to_vec())).read_bullet().is_err());
+        assert!(PhantomReader::new(Cursor::new(b"3\r\nGET\r\n".to_vec())).read_bullet().is_err());
+        for huge in &["99999999999999999", "18446744073709551615"] {
+            let error = PhantomReader::new(Cursor::new(format!("{} t\r\nGET / HTTP/1.0\r\n\r\n", huge).into_bytes())).read_bullet().err().unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), format!("bullet at offset 0: unexpected end of input, bullet should have {} bytes", huge));
+        }
     }
 }
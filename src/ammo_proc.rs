@@ -1,19 +1,42 @@
 use rand;
-use rand::Rng;
-use error::ProcError;
+use rand::{Rng, SeedableRng};
+use rand::isaac::Isaac64Rng;
+use error::{ProcError, LineError};
 use ammo::*;
+use logut;
 use std::io;
 use std::io::prelude::*;
-use std::fs::File;
-use std::io::BufWriter;
-use std::process::{Command, Stdio, Child};
-use std::path::Path;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::env;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, BinaryHeap, VecDeque};
+use std::cmp::Ordering as CmpOrdering;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+/// Makes random numbers generator for samplers. Generators made with the same seed
+/// produce the same sequences, so sampling becomes reproducible.
+pub fn make_rng(seed: Option<u64>) -> Box<rand::Rng> {
+    match seed {
+        Some(seed) => Box::new(Isaac64Rng::from_seed(&[seed])),
+        None => Box::new(rand::thread_rng()),
+    }
+}
 
 pub trait AmmoProcessor {
     fn process(&mut self, bullet: &BulletData) -> Result<(), ProcError>;
+    /// Tells that the following bullets come from input with given index. Stages which
+    /// pass bullets on have to pass it on too, samplers may keep inputs apart.
+    fn begin_source(&mut self, _index: usize) {}
     fn finish(&mut self) -> Result<(), ProcError> {
         Ok(())
     }
+    /// Adds lines of summary to be shown after `finish`. Stages which pass bullets on
+    /// have to pass it on too.
+    fn report(&self, _lines: &mut Vec<String>) {}
 }
 
 pub struct ReserviorSampling {
@@ -25,12 +48,12 @@ pub struct ReserviorSampling {
 }
 
  impl ReserviorSampling {
-    pub fn new(set_size: usize, subprocessor: Box<AmmoProcessor>) -> ReserviorSampling {
+    pub fn new(set_size: usize, rng: Box<rand::Rng>, subprocessor: Box<AmmoProcessor>) -> ReserviorSampling {
         ReserviorSampling {
             target_set_size: set_size,
             selected: Vec::with_capacity(set_size),
             index: 0,
-            rng: Box::new(rand::thread_rng()),
+            rng: rng,
             subprocessor: subprocessor
         }
     }
@@ -49,9 +72,12 @@ impl AmmoProcessor for ReserviorSampling {
         self.index += 1;
         Ok(())
     }
+    fn report(&self, lines: &mut Vec<String>) {
+        self.subprocessor.report(lines)
+    }
     fn finish(&mut self) -> Result<(), ProcError> {
         if self.selected.len() < self.target_set_size {
-            Err(ProcError::Logic(format!("Not enough input lines: have seen {} but at least {} were expected", self.index, self.target_set_size)))
+            Err(ProcError::NotEnoughInput(format!("Not enough input lines: have seen {} but at least {} were expected", self.index, self.target_set_size)))
         } else {
             for bullet in &self.selected {
                 try!(self.subprocessor.process(&bullet.get_data()));
@@ -71,19 +97,19 @@ pub struct MethodS {
 }
 
 impl MethodS {
-    pub fn new(input_lines_count: usize, target_set_size: usize, subprocessor: Box<AmmoProcessor>) -> Box<AmmoProcessor> {
+    pub fn new(input_lines_count: usize, target_set_size: usize, rng: Box<rand::Rng>, subprocessor: Box<AmmoProcessor>) -> Result<Box<AmmoProcessor>, ProcError> {
         if input_lines_count < target_set_size {
-            panic!("Not enough input lines: have {} but at least {} is needed", input_lines_count, target_set_size)
+            return Err(ProcError::NotEnoughInput(format!("Not enough input lines: have {} but at least {} is needed", input_lines_count, target_set_size)));
         }
         let p = MethodS {
             input_lines_count: input_lines_count,
             target_set_size: target_set_size,
             already_processed: 0,
             already_selected: 0,
-            rng: Box::new(rand::thread_rng()),
+            rng: rng,
             subprocessor: subprocessor
         };
-        Box::new(p)
+        Ok(Box::new(p))
     }
 }
 
@@ -99,11 +125,761 @@ impl AmmoProcessor for MethodS {
         self.already_processed += 1;
         Ok(())
     }
+    fn report(&self, lines: &mut Vec<String>) {
+        self.subprocessor.report(lines)
+    }
+    fn finish(&mut self) -> Result<(), ProcError> {
+        self.subprocessor.finish()
+    }
+}
+
+/// Single pass reservoir sampling (Li's Algorithm L). It skips lines which would not get into
+/// the reservoir without even looking at them and keeps the reservoir itself in temporary file,
+/// so memory usage doesn't depend on bullet sizes and input may be read only once (e.g. from stdin).
+/// Selected bullets are written in the order they appeared in the input.
+pub struct StreamSampling {
+    target_set_size: usize,
+    index: usize,
+    next_pick: usize,
+    w: f64,
+    offsets: Vec<u64>,
+    storage: Option<BufWriter<File>>,
+    storage_path: PathBuf,
+    storage_len: u64,
+    record: Vec<u8>,
+    rng: Box<rand::Rng>,
+    subprocessor: Box<AmmoProcessor>,
+}
+
+static STORAGE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl StreamSampling {
+    pub fn new(set_size: usize, rng: Box<rand::Rng>, subprocessor: Box<AmmoProcessor>) -> io::Result<StreamSampling> {
+        let n = STORAGE_COUNTER.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("gen_ammo-{}-{}.reservoir", process::id(), n));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        Ok(StreamSampling {
+            target_set_size: set_size,
+            index: 0,
+            next_pick: 0,
+            w: 1.0,
+            offsets: Vec::with_capacity(set_size),
+            storage: Some(BufWriter::new(file)),
+            storage_path: path,
+            storage_len: 0,
+            record: Vec::new(),
+            rng: rng,
+            subprocessor: subprocessor,
+        })
+    }
+
+    /// Random number from (0, 1]
+    fn random(&mut self) -> f64 {
+        1.0 - self.rng.gen::<f64>()
+    }
+
+    fn schedule_next_pick(&mut self) {
+        let k = self.target_set_size as f64;
+        let r = self.random();
+        self.w *= (r.ln() / k).exp();
+        let r = self.random();
+        let skip = (r.ln() / (1.0 - self.w).ln()).floor();
+        self.next_pick = self.index.saturating_add(skip as usize).saturating_add(1);
+    }
+
+    fn store(&mut self, bullet: &BulletData) -> io::Result<u64> {
+        self.record.clear();
+        bullet.write_to(&mut self.record)?;
+        match self.storage.as_mut() {
+            Some(storage) => storage.write_all(&self.record)?,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "sampling is already finished")),
+        }
+        let offset = self.storage_len;
+        self.storage_len += self.record.len() as u64;
+        Ok(offset)
+    }
+}
+
+impl AmmoProcessor for StreamSampling {
+    fn process(&mut self, bullet: &BulletData) -> Result<(), ProcError> {
+        if self.index < self.target_set_size {
+            let offset = self.store(bullet)?;
+            self.offsets.push(offset);
+            if self.offsets.len() == self.target_set_size {
+                self.schedule_next_pick();
+            }
+        } else if self.index == self.next_pick {
+            let offset = self.store(bullet)?;
+            let slot = self.rng.gen_range(0, self.target_set_size);
+            self.offsets[slot] = offset;
+            self.schedule_next_pick();
+        }
+        self.index += 1;
+        Ok(())
+    }
+    fn report(&self, lines: &mut Vec<String>) {
+        self.subprocessor.report(lines)
+    }
     fn finish(&mut self) -> Result<(), ProcError> {
+        if self.offsets.len() < self.target_set_size {
+            return Err(ProcError::NotEnoughInput(format!("Not enough input lines: have seen {} but at least {} were expected", self.index, self.target_set_size)));
+        }
+        let storage = match self.storage.take() {
+            Some(storage) => storage,
+            None => return Ok(()),
+        };
+        let file = storage.into_inner().map_err(io::Error::from)?;
+        let mut reader = BufReader::new(file);
+        self.offsets.sort();
+        for &offset in &self.offsets {
+            reader.seek(SeekFrom::Start(offset))?;
+            let bullet = StoredBullet::read_from(&mut reader)?;
+            self.subprocessor.process(&bullet.get_data())?;
+        }
         self.subprocessor.finish()
     }
 }
 
+impl Drop for StreamSampling {
+    fn drop(&mut self) {
+        self.storage.take();
+        let _ = fs::remove_file(&self.storage_path);
+    }
+}
+
+/// Splits total into parts proportional to weights (largest remainder method)
+pub fn split_proportionally(total: usize, weights: &[f64]) -> Vec<usize> {
+    let sum: f64 = weights.iter().sum();
+    if sum <= 0.0 {
+        return vec![0; weights.len()];
+    }
+    let exact: Vec<f64> = weights.iter().map(|w| total as f64 * w / sum).collect();
+    let mut parts: Vec<usize> = exact.iter().map(|x| x.floor() as usize).collect();
+    let mut order: Vec<usize> = (0..weights.len()).collect();
+    order.sort_by(|&a, &b| (exact[b] - parts[b] as f64).partial_cmp(&(exact[a] - parts[a] as f64)).unwrap());
+    let assigned: usize = parts.iter().sum();
+    for &i in order.iter().take(total.saturating_sub(assigned)) {
+        parts[i] += 1;
+    }
+    parts
+}
+
+/// What bullets are grouped by in stratified sampling and sharding
+#[derive(Clone, PartialEq, Debug)]
+pub enum StratumKey {
+    Place,
+    Host,
+    CgiParam(Vec<u8>),
+    /// The first wizard of request
+    Wizard,
+    /// Path with query string
+    Resource,
+}
+
+impl Default for StratumKey {
+    fn default() -> Self {
+        StratumKey::Place
+    }
+}
+
+impl StratumKey {
+    pub fn get<'a>(&self, bullet: &BulletData<'a>) -> &'a [u8] {
+        match *self {
+            StratumKey::Place => bullet.place,
+            StratumKey::Host => bullet.host,
+            StratumKey::CgiParam(ref name) => logut::get_cgi_param_value_naive(bullet.resource, name).unwrap_or(b""),
+            StratumKey::Wizard => wizard_names(bullet.wizards).first().cloned().unwrap_or(b""),
+            StratumKey::Resource => bullet.resource,
+        }
+    }
+}
+
+/// How sample is split among strata
+pub enum Quotas {
+    /// Stratum gets share equal to its share in input
+    Proportional,
+    /// Equal shares (strata which are too small give the rest to others)
+    Uniform,
+    /// Shares of listed strata are given explicitly, other strata are dropped
+    Explicit(HashMap<Vec<u8>, f64>),
+}
+
+/// Reads explicit quotas from text with `key share` pair per line. Shares are
+/// relative: `a 1` and `b 3` put 25% of the sample to stratum `a`.
+pub fn parse_quotas(text: &str) -> Result<HashMap<Vec<u8>, f64>, LineError> {
+    let mut quotas = HashMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let share = match (parts.next(), parts.next().map(|x| x.parse::<f64>()), parts.next()) {
+            (Some(key), Some(Ok(share)), None) if share >= 0.0 => (key, share),
+            _ => return Err(LineError::new(n, format!("expected 'key share' but got '{}'", line))),
+        };
+        quotas.insert(share.0.as_bytes().to_vec(), share.1);
+    }
+    Ok(quotas)
+}
+
+struct Stratum {
+    seen: usize,
+    capacity: usize,
+    reservoir: Vec<StoredBullet>,
+}
+
+/// Keeps separate reservoir for each stratum and mixes them up according to quotas.
+/// With proportional and uniform quotas each reservoir may grow up to the whole sample size.
+pub struct StratifiedSampling {
+    key: StratumKey,
+    quotas: Quotas,
+    target_set_size: usize,
+    explicit_sizes: HashMap<Vec<u8>, usize>,
+    strata: HashMap<Vec<u8>, Stratum>,
+    rng: Box<rand::Rng>,
+    subprocessor: Box<AmmoProcessor>,
+}
+
+impl StratifiedSampling {
+    pub fn new(set_size: usize, key: StratumKey, quotas: Quotas, rng: Box<rand::Rng>, subprocessor: Box<AmmoProcessor>) -> StratifiedSampling {
+        let mut explicit_sizes = HashMap::new();
+        if let Quotas::Explicit(ref shares) = quotas {
+            let mut keys: Vec<&Vec<u8>> = shares.keys().collect();
+            keys.sort();
+            let weights: Vec<f64> = keys.iter().map(|k| shares[*k]).collect();
+            for (key, size) in keys.iter().zip(split_proportionally(set_size, &weights)) {
+                explicit_sizes.insert((*key).clone(), size);
+            }
+        }
+        StratifiedSampling {
+            key: key,
+            quotas: quotas,
+            target_set_size: set_size,
+            explicit_sizes: explicit_sizes,
+            strata: HashMap::new(),
+            rng: rng,
+            subprocessor: subprocessor,
+        }
+    }
+
+    /// Returns (stratum key, sample size) pairs
+    fn allocate(&self) -> Result<Vec<(Vec<u8>, usize)>, ProcError> {
+        let mut keys: Vec<&Vec<u8>> = self.strata.keys().collect();
+        keys.sort();
+        let allocation: Vec<(Vec<u8>, usize)> = match self.quotas {
+            Quotas::Proportional => {
+                let weights: Vec<f64> = keys.iter().map(|k| self.strata[*k].seen as f64).collect();
+                keys.iter().map(|k| (*k).clone()).zip(split_proportionally(self.target_set_size, &weights)).collect()
+            },
+            Quotas::Uniform => {
+                // the smallest strata go first, so what they can't fill is shared by the rest
+                keys.sort_by_key(|k| self.strata[*k].reservoir.len());
+                let mut left = self.target_set_size;
+                let mut result = Vec::new();
+                for (i, k) in keys.iter().enumerate() {
+                    let share = left / (keys.len() - i);
+                    let size = share.min(self.strata[*k].reservoir.len());
+                    left -= size;
+                    result.push(((*k).clone(), size));
+                }
+                result
+            },
+            Quotas::Explicit(_) => {
+                let mut explicit: Vec<(&Vec<u8>, &usize)> = self.explicit_sizes.iter().collect();
+                explicit.sort();
+                explicit.iter().map(|&(k, size)| (k.clone(), *size)).collect()
+            },
+        };
+        let total: usize = self.strata.values().map(|s| s.seen).sum();
+        for &(ref key, size) in &allocation {
+            let available = self.strata.get(key).map_or(0, |s| s.reservoir.len());
+            if available < size {
+                return Err(ProcError::NotEnoughInput(format!("Not enough input lines in stratum '{}': have seen {} but at least {} were expected (total lines seen: {})",
+                    String::from_utf8_lossy(key), available, size, total)));
+            }
+        }
+        let allocated: usize = allocation.iter().map(|&(_, size)| size).sum();
+        if allocated < self.target_set_size {
+            return Err(ProcError::NotEnoughInput(format!("Not enough input lines: have seen {} but at least {} were expected", total, self.target_set_size)));
+        }
+        Ok(allocation)
+    }
+}
+
+impl AmmoProcessor for StratifiedSampling {
+    fn process(&mut self, bullet: &BulletData) -> Result<(), ProcError> {
+        let key = self.key.get(bullet);
+        if !self.strata.contains_key(key) {
+            let capacity = match self.quotas {
+                Quotas::Explicit(_) => match self.explicit_sizes.get(key) {
+                    Some(&size) => size,
+                    None => return Ok(()),
+                },
+                _ => self.target_set_size,
+            };
+            self.strata.insert(key.to_vec(), Stratum { seen: 0, capacity: capacity, reservoir: Vec::new() });
+        }
+        let stratum = self.strata.get_mut(key).unwrap();
+        if stratum.reservoir.len() < stratum.capacity {
+            stratum.reservoir.push(StoredBullet::from_data(bullet));
+        } else {
+            let r = self.rng.gen_range(0, stratum.seen + 1);
+            if r < stratum.capacity {
+                stratum.reservoir[r] = StoredBullet::from_data(bullet);
+            }
+        }
+        stratum.seen += 1;
+        Ok(())
+    }
+    fn report(&self, lines: &mut Vec<String>) {
+        self.subprocessor.report(lines)
+    }
+    fn finish(&mut self) -> Result<(), ProcError> {
+        let allocation = self.allocate()?;
+        let mut selected = Vec::with_capacity(self.target_set_size);
+        for (key, size) in allocation {
+            if let Some(mut stratum) = self.strata.remove(&key) {
+                // reservoir is uniform sample, so its random part is uniform sample too
+                self.rng.shuffle(&mut stratum.reservoir);
+                stratum.reservoir.truncate(size);
+                selected.extend(stratum.reservoir);
+            }
+        }
+        self.rng.shuffle(&mut selected);
+        for bullet in &selected {
+            self.subprocessor.process(&bullet.get_data())?;
+        }
+        self.subprocessor.finish()
+    }
+}
+
+
+/// Bullet in weighted reservoir. Keys are kept as logarithms, otherwise keys of light
+/// bullets would underflow. Ordering is reversed, so heap top is the bullet with minimal key.
+struct KeyedBullet {
+    key: f64,
+    bullet: StoredBullet,
+}
+
+impl PartialEq for KeyedBullet {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for KeyedBullet {}
+
+impl PartialOrd for KeyedBullet {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for KeyedBullet {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other.key.partial_cmp(&self.key).unwrap_or(CmpOrdering::Equal)
+    }
+}
+
+/// Weighted reservoir sampling with exponential jumps (Efraimidis and Spirakis A-ExpJ).
+/// Probability of bullet to be selected is proportional to `BulletData::weight`,
+/// bullets with zero or negative weight are never selected.
+pub struct WeightedSampling {
+    target_set_size: usize,
+    index: usize,
+    reservoir: BinaryHeap<KeyedBullet>,
+    weight_to_skip: f64,
+    rng: Box<rand::Rng>,
+    subprocessor: Box<AmmoProcessor>,
+}
+
+impl WeightedSampling {
+    pub fn new(set_size: usize, rng: Box<rand::Rng>, subprocessor: Box<AmmoProcessor>) -> WeightedSampling {
+        WeightedSampling {
+            target_set_size: set_size,
+            index: 0,
+            reservoir: BinaryHeap::with_capacity(set_size),
+            weight_to_skip: 0.0,
+            rng: rng,
+            subprocessor: subprocessor,
+        }
+    }
+
+    /// Random number from (0, 1]
+    fn random(&mut self) -> f64 {
+        1.0 - self.rng.gen::<f64>()
+    }
+
+    fn min_key(&self) -> f64 {
+        self.reservoir.peek().map_or(0.0, |top| top.key)
+    }
+
+    fn next_jump(&mut self) {
+        let min_key = self.min_key();
+        self.weight_to_skip = if min_key < 0.0 {
+            self.random().ln() / min_key
+        } else {
+            f64::INFINITY
+        };
+    }
+}
+
+impl AmmoProcessor for WeightedSampling {
+    fn process(&mut self, bullet: &BulletData) -> Result<(), ProcError> {
+        self.index += 1;
+        let weight = bullet.weight;
+        if weight.is_nan() || weight <= 0.0 || self.target_set_size == 0 {
+            return Ok(());
+        }
+        if self.reservoir.len() < self.target_set_size {
+            let key = self.random().ln() / weight;
+            self.reservoir.push(KeyedBullet { key: key, bullet: StoredBullet::from_data(bullet) });
+            if self.reservoir.len() == self.target_set_size {
+                self.next_jump();
+            }
+            return Ok(());
+        }
+        self.weight_to_skip -= weight;
+        if self.weight_to_skip <= 0.0 {
+            // key of this bullet is r^(1/weight) where r is uniform on (exp(min_key)^weight, 1)
+            let threshold = (self.min_key() * weight).exp();
+            let r = threshold + (1.0 - threshold) * self.random();
+            self.reservoir.pop();
+            self.reservoir.push(KeyedBullet { key: r.ln() / weight, bullet: StoredBullet::from_data(bullet) });
+            self.next_jump();
+        }
+        Ok(())
+    }
+    fn report(&self, lines: &mut Vec<String>) {
+        self.subprocessor.report(lines)
+    }
+    fn finish(&mut self) -> Result<(), ProcError> {
+        if self.reservoir.len() < self.target_set_size {
+            return Err(ProcError::NotEnoughInput(format!("Not enough input lines with positive weight: have seen {} lines but only {} of them may be selected, at least {} were expected",
+                self.index, self.reservoir.len(), self.target_set_size)));
+        }
+        let mut selected: Vec<StoredBullet> = self.reservoir.drain().map(|keyed| keyed.bullet).collect();
+        // heap order depends on weights, heavy bullets should not go first
+        self.rng.shuffle(&mut selected);
+        for bullet in &selected {
+            self.subprocessor.process(&bullet.get_data())?;
+        }
+        self.subprocessor.finish()
+    }
+}
+
+/// Sampling with replacement (bootstrap): keeps all input bullets in memory and draws
+/// as many bullets as needed from them, so sample may be bigger than the input.
+/// Popular requests stay popular since every input line is equally likely to be drawn.
+/// If `max_repeats` is set, no input line is used more than that many times.
+pub struct BootstrapSampling {
+    target_set_size: usize,
+    max_repeats: Option<usize>,
+    pool: Vec<StoredBullet>,
+    rng: Box<rand::Rng>,
+    subprocessor: Box<AmmoProcessor>,
+}
+
+impl BootstrapSampling {
+    pub fn new(set_size: usize, max_repeats: Option<usize>, rng: Box<rand::Rng>, subprocessor: Box<AmmoProcessor>) -> BootstrapSampling {
+        BootstrapSampling {
+            target_set_size: set_size,
+            max_repeats: max_repeats,
+            pool: Vec::new(),
+            rng: rng,
+            subprocessor: subprocessor,
+        }
+    }
+}
+
+impl AmmoProcessor for BootstrapSampling {
+    fn process(&mut self, bullet: &BulletData) -> Result<(), ProcError> {
+        self.pool.push(StoredBullet::from_data(bullet));
+        Ok(())
+    }
+    fn report(&self, lines: &mut Vec<String>) {
+        self.subprocessor.report(lines)
+    }
+    fn finish(&mut self) -> Result<(), ProcError> {
+        let available = match self.max_repeats {
+            Some(max_repeats) => self.pool.len().saturating_mul(max_repeats),
+            None if self.pool.is_empty() => 0,
+            None => usize::MAX,
+        };
+        if available < self.target_set_size {
+            return Err(ProcError::NotEnoughInput(match self.max_repeats {
+                Some(max_repeats) => format!("Not enough input lines: have seen {} but {} bullets can't be made with at most {} repeats of each line",
+                    self.pool.len(), self.target_set_size, max_repeats),
+                None => "Not enough input lines: have seen none".to_string(),
+            }));
+        }
+        match self.max_repeats {
+            None => {
+                for _ in 0..self.target_set_size {
+                    let index = self.rng.gen_range(0, self.pool.len());
+                    self.subprocessor.process(&self.pool[index].get_data())?;
+                }
+            },
+            Some(max_repeats) => {
+                // lines which have been used up are removed from the draw
+                let mut repeats = vec![0; self.pool.len()];
+                let mut active: Vec<usize> = (0..self.pool.len()).collect();
+                for _ in 0..self.target_set_size {
+                    let pos = self.rng.gen_range(0, active.len());
+                    let index = active[pos];
+                    repeats[index] += 1;
+                    if repeats[index] == max_repeats {
+                        active.swap_remove(pos);
+                    }
+                    self.subprocessor.process(&self.pool[index].get_data())?;
+                }
+            },
+        }
+        self.subprocessor.finish()
+    }
+}
+
+/// Part of sample taken from input
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SourceQuota {
+    /// Relative share: inputs with weights split what is left after absolute quotas
+    Weight(f64),
+    /// Exact number of bullets
+    Count(usize),
+}
+
+impl Default for SourceQuota {
+    fn default() -> Self {
+        SourceQuota::Weight(1.0)
+    }
+}
+
+struct Source {
+    name: String,
+    seen: usize,
+    size: usize,
+    reservoir: Vec<StoredBullet>,
+}
+
+/// Keeps separate reservoir for each input, so a large input doesn't swamp a small one.
+/// Bullets selected from all inputs are shuffled together.
+pub struct SourceSampling {
+    sources: Vec<Source>,
+    current: usize,
+    rng: Box<rand::Rng>,
+    subprocessor: Box<AmmoProcessor>,
+}
+
+impl SourceSampling {
+    /// Takes names and quotas of inputs in the order they are read
+    pub fn new(set_size: usize, quotas: Vec<(String, SourceQuota)>, rng: Box<rand::Rng>, subprocessor: Box<AmmoProcessor>) -> Result<SourceSampling, ProcError> {
+        let counted: usize = quotas.iter().map(|&(_, quota)| match quota {
+            SourceQuota::Count(count) => count,
+            SourceQuota::Weight(_) => 0,
+        }).sum();
+        let weights: Vec<f64> = quotas.iter().map(|&(_, quota)| match quota {
+            SourceQuota::Weight(weight) => weight,
+            SourceQuota::Count(_) => 0.0,
+        }).collect();
+        let weighted = weights.iter().any(|w| *w > 0.0);
+        if counted > set_size || (!weighted && counted != set_size) {
+            return Err(ProcError::Config(format!("quotas of inputs add up to {} bullets but {} are requested", counted, set_size)));
+        }
+        let shares = split_proportionally(set_size - counted, &weights);
+        let sources = quotas.into_iter().zip(shares).map(|((name, quota), share)| Source {
+            name: name,
+            seen: 0,
+            size: match quota {
+                SourceQuota::Count(count) => count,
+                SourceQuota::Weight(_) => share,
+            },
+            reservoir: Vec::new(),
+        }).collect();
+        Ok(SourceSampling {
+            sources: sources,
+            current: 0,
+            rng: rng,
+            subprocessor: subprocessor,
+        })
+    }
+}
+
+impl AmmoProcessor for SourceSampling {
+    fn process(&mut self, bullet: &BulletData) -> Result<(), ProcError> {
+        let source = match self.sources.get_mut(self.current) {
+            Some(source) => source,
+            None => return Err(ProcError::Config(format!("input {} has no quota", self.current))),
+        };
+        if source.reservoir.len() < source.size {
+            source.reservoir.push(StoredBullet::from_data(bullet));
+        } else {
+            let r = self.rng.gen_range(0, source.seen + 1);
+            if r < source.size {
+                source.reservoir[r] = StoredBullet::from_data(bullet);
+            }
+        }
+        source.seen += 1;
+        Ok(())
+    }
+    fn begin_source(&mut self, index: usize) {
+        self.current = index;
+    }
+    fn report(&self, lines: &mut Vec<String>) {
+        self.subprocessor.report(lines)
+    }
+    fn finish(&mut self) -> Result<(), ProcError> {
+        for source in &self.sources {
+            if source.seen < source.size {
+                return Err(ProcError::NotEnoughInput(format!("Not enough input lines in {}: have seen {} but at least {} were expected",
+                    source.name, source.seen, source.size)));
+            }
+        }
+        let mut selected: Vec<StoredBullet> = self.sources.iter_mut().flat_map(|s| s.reservoir.drain(..)).collect();
+        self.rng.shuffle(&mut selected);
+        for bullet in &selected {
+            self.subprocessor.process(&bullet.get_data())?;
+        }
+        self.subprocessor.finish()
+    }
+}
+
+/// How old repeated bullets are
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Recency {
+    /// Any bullet from the window is repeated with the same probability
+    Uniform,
+    /// Age of repeated bullet (in bullets) is exponentially distributed with given mean,
+    /// so the most recent bullets are repeated most often
+    Exponential(f64),
+}
+
+/// Mixes repeats of recently emitted bullets into the stream, so `ratio` of output
+/// bullets are repeats (cache hits). Every incoming bullet is passed on, so output has
+/// about `ratio / (1 - ratio)` more bullets than input, unless exact numbers of input
+/// bullets and repeats are set with `with_plan`.
+/// Repeats of requests which are already found in the input are not counted.
+pub struct RepeatMixer {
+    ratio: f64,
+    /// Incoming bullets still expected and repeats still to mix in
+    plan: Option<(usize, usize)>,
+    window_size: usize,
+    window: VecDeque<StoredBullet>,
+    recency: Recency,
+    emitted: usize,
+    repeated: usize,
+    rng: Box<rand::Rng>,
+    subprocessor: Box<AmmoProcessor>,
+}
+
+impl RepeatMixer {
+    pub fn new(ratio: f64, window_size: usize, recency: Recency, rng: Box<rand::Rng>, subprocessor: Box<AmmoProcessor>) -> RepeatMixer {
+        RepeatMixer {
+            ratio: ratio,
+            plan: None,
+            window_size: window_size.max(1),
+            window: VecDeque::with_capacity(window_size.max(1)),
+            recency: recency,
+            emitted: 0,
+            repeated: 0,
+            rng: rng,
+            subprocessor: subprocessor,
+        }
+    }
+
+    /// Mixes exactly `repeats` repeats among `incoming` bullets, e.g. to keep output size
+    pub fn with_plan(mut self, incoming: usize, repeats: usize) -> RepeatMixer {
+        self.plan = Some((incoming, repeats));
+        self
+    }
+
+    /// Share of bullets which were repeats
+    pub fn achieved_ratio(&self) -> f64 {
+        if self.emitted == 0 { 0.0 } else { self.repeated as f64 / self.emitted as f64 }
+    }
+
+    /// Age of bullet to repeat, 0 is the most recent one
+    fn pick_age(&mut self) -> usize {
+        let len = self.window.len();
+        match self.recency {
+            Recency::Uniform => self.rng.gen_range(0, len),
+            Recency::Exponential(mean) => {
+                // inverse of exponential distribution truncated to window length
+                let u = self.rng.gen::<f64>();
+                let age = -mean * (1.0 - u * (1.0 - (-(len as f64) / mean).exp())).ln();
+                (age as usize).min(len - 1)
+            },
+        }
+    }
+
+    fn repeat(&mut self) -> Result<(), ProcError> {
+        let age = self.pick_age();
+        let index = self.window.len() - 1 - age;
+        let bullet = self.window.remove(index).unwrap();
+        self.repeated += 1;
+        self.emit(bullet)
+    }
+
+    fn emit(&mut self, bullet: StoredBullet) -> Result<(), ProcError> {
+        self.emitted += 1;
+        self.subprocessor.process(&bullet.get_data())?;
+        self.remember(bullet);
+        Ok(())
+    }
+
+    fn remember(&mut self, bullet: StoredBullet) {
+        if self.window.len() == self.window_size {
+            self.window.pop_front();
+        }
+        self.window.push_back(bullet);
+    }
+}
+
+impl AmmoProcessor for RepeatMixer {
+    fn process(&mut self, bullet: &BulletData) -> Result<(), ProcError> {
+        self.emit(StoredBullet::from_data(bullet))?;
+        match self.plan {
+            Some((incoming, mut repeats)) => {
+                // next bullet is a repeat with probability repeats/(repeats+incoming),
+                // so repeats are spread uniformly between incoming bullets
+                let incoming = incoming.saturating_sub(1);
+                while repeats > 0 && self.rng.gen_range(0, repeats + incoming) < repeats {
+                    self.repeat()?;
+                    repeats -= 1;
+                }
+                self.plan = Some((incoming, repeats));
+            },
+            None => {
+                // each draw adds a repeat with probability ratio, that gives ratio/(1-ratio)
+                // repeats per incoming bullet on average
+                while self.rng.gen::<f64>() < self.ratio {
+                    self.repeat()?;
+                }
+            },
+        }
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), ProcError> {
+        // fewer bullets came than planned
+        if let Some((_, repeats)) = self.plan.take() {
+            for _ in 0..repeats {
+                if self.window.is_empty() {
+                    break;
+                }
+                self.repeat()?;
+            }
+        }
+        self.subprocessor.finish()
+    }
+    fn begin_source(&mut self, index: usize) {
+        self.subprocessor.begin_source(index)
+    }
+    fn report(&self, lines: &mut Vec<String>) {
+        lines.push(format!("Repeated bullets: {} of {} ({:.2}%, target {:.2}%)",
+            self.repeated, self.emitted, self.achieved_ratio() * 100.0, self.ratio * 100.0));
+        self.subprocessor.report(lines)
+    }
+}
 
 // TODO: use std::iter::Cycle; iterator instead! But it isn't so easy!
 pub struct RoundRobin {
@@ -135,62 +911,225 @@ impl AmmoProcessor for RoundRobin {
         }
         Ok(())
     }
+    fn begin_source(&mut self, index: usize) {
+        for consumer in self.subprocessors.iter_mut() {
+            consumer.begin_source(index);
+        }
+    }
+    fn report(&self, lines: &mut Vec<String>) {
+        for consumer in &self.subprocessors {
+            consumer.report(lines);
+        }
+    }
+}
+
+/// FNV-1a hash, unlike `DefaultHasher` it doesn't change between Rust releases
+pub fn stable_hash(data: &[u8]) -> u64 {
+    extend_stable_hash(0xcbf29ce484222325, data)
+}
+
+/// Continues `stable_hash` of some data with more data
+pub fn extend_stable_hash(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Sends bullets with the same key to the same subprocessor, so assignment of
+/// users or urls to output files doesn't depend on input order and run.
+/// Bullets without key all go to one subprocessor.
+pub struct HashSharding {
+    key: StratumKey,
+    subprocessors: Vec<Box<AmmoProcessor>>,
+    sizes: Vec<usize>,
+    without_key: usize,
+}
+
+impl HashSharding {
+    pub fn new(key: StratumKey, subprocessors: Vec<Box<AmmoProcessor>>) -> HashSharding {
+        HashSharding {
+            key: key,
+            sizes: vec![0; subprocessors.len()],
+            subprocessors: subprocessors,
+            without_key: 0,
+        }
+    }
+
+    /// Sizes of shards and how far the largest and the smallest ones are from the mean
+    fn sizes_report(&self) -> String {
+        let total: usize = self.sizes.iter().sum();
+        let mean = total as f64 / self.sizes.len() as f64;
+        let deviation = |size: usize| if total == 0 { 0.0 } else { (size as f64 / mean - 1.0) * 100.0 };
+        let sizes: Vec<String> = self.sizes.iter().map(|size| size.to_string()).collect();
+        let mut report = format!("Shard sizes: {} (max {:+.1}%, min {:+.1}% of mean)", sizes.join(", "),
+            deviation(*self.sizes.iter().max().unwrap_or(&0)), deviation(*self.sizes.iter().min().unwrap_or(&0)));
+        if self.without_key > 0 {
+            report.push_str(&format!(", {} bullets without key", self.without_key));
+        }
+        report
+    }
+}
+
+impl AmmoProcessor for HashSharding {
+    fn process(&mut self, bullet: &BulletData) -> Result<(), ProcError> {
+        let key = self.key.get(bullet);
+        if key.is_empty() {
+            self.without_key += 1;
+        }
+        let shard = (stable_hash(key) % self.subprocessors.len() as u64) as usize;
+        self.sizes[shard] += 1;
+        self.subprocessors[shard].process(bullet)
+    }
+    fn finish(&mut self) -> Result<(), ProcError> {
+        for consumer in self.subprocessors.iter_mut() {
+            consumer.finish()?;
+        }
+        Ok(())
+    }
+    fn begin_source(&mut self, index: usize) {
+        for consumer in self.subprocessors.iter_mut() {
+            consumer.begin_source(index);
+        }
+    }
+    fn report(&self, lines: &mut Vec<String>) {
+        lines.push(self.sizes_report());
+        for consumer in &self.subprocessors {
+            consumer.report(lines);
+        }
+    }
 }
 
 pub struct WriteAmmo {
+    /// File name or `stdout`, used in error messages
+    name: String,
     buff: io::Cursor<Vec<u8>>,
-    writer: Box<Write>,
+    writer: Box<AmmoSink>,
+    format: OutputFormat,
+    template: RequestTemplate,
+    tags: TagRules,
+    uri_state: UriStyleState,
 }
 
 impl WriteAmmo {
-    pub fn to_stdout() -> Result<WriteAmmo, io::Error> {
+    pub fn to_stdout() -> Result<WriteAmmo, ProcError> {
         // TODO: very slow! Locks stdout for each write
-        Ok(WriteAmmo {buff: io::Cursor::new(vec![]), writer: Box::new(StdoutWriter)})
+        Ok(WriteAmmo::new(Box::new(StdoutWriter), "stdout".to_string()))
     }
 
-    pub fn to_file(filename: &Path) -> Result<WriteAmmo, io::Error> {
-        let f = try!(File::create(filename));
-        WriteAmmo::to_stream(Box::new(f))
+    pub fn to_file(filename: &Path) -> Result<WriteAmmo, ProcError> {
+        let name = filename.display().to_string();
+        let f = match File::create(filename) {
+            Ok(f) => f,
+            Err(err) => return Err(ProcError::Output { path: name, err: err }),
+        };
+        let mut writer = WriteAmmo::to_stream(Box::new(f))?;
+        writer.name = name;
+        Ok(writer)
     }
 
-    pub fn to_gzip(filename: &Path) -> Result<WriteAmmo, io::Error> {
-        let gz_command = format!("gzip -c > {}", filename.to_str().unwrap_or(""));
-        let p = Box::new(try!(Command::new("sh")
-            .arg("-c")
-            .arg(gz_command)
-            .stdin(Stdio::piped())
-            .spawn()));
-        Ok(WriteAmmo {buff: io::Cursor::new(vec![]), writer: Box::new(ProcWriter{child: p})} )
+    pub fn to_gzip(filename: &Path, level: Compression) -> Result<WriteAmmo, ProcError> {
+        let name = filename.display().to_string();
+        let f = match File::create(filename) {
+            Ok(f) => f,
+            Err(err) => return Err(ProcError::Output { path: name, err: err }),
+        };
+        let encoder = BufWriter::new(GzEncoder::new(f, level));
+        Ok(WriteAmmo::new(Box::new(GzWriter{encoder: Some(encoder)}), name))
     }
 
-    pub fn to_stream(to: Box<Write>) -> Result<WriteAmmo, io::Error> {
+    pub fn to_stream(to: Box<Write>) -> Result<WriteAmmo, ProcError> {
         let writer = BufWriter::new(to);
-        Ok(WriteAmmo {buff: io::Cursor::new(vec![]), writer: Box::new(writer)} )
+        Ok(WriteAmmo::new(Box::new(writer), "stream".to_string()))
+    }
+
+    fn new(writer: Box<AmmoSink>, name: String) -> WriteAmmo {
+        WriteAmmo {
+            name: name,
+            buff: io::Cursor::new(vec![]),
+            writer: writer,
+            format: OutputFormat::default(),
+            template: RequestTemplate::default(),
+            tags: TagRules::default(),
+            uri_state: UriStyleState::default(),
+        }
+    }
+
+    pub fn with_format(mut self, format: OutputFormat) -> WriteAmmo {
+        self.format = format;
+        self
+    }
+
+    pub fn with_template(mut self, template: RequestTemplate) -> WriteAmmo {
+        self.template = template;
+        self
+    }
+
+    pub fn with_tags(mut self, tags: TagRules) -> WriteAmmo {
+        self.tags = tags;
+        self
     }
 }
 
 impl AmmoProcessor for WriteAmmo {
     fn process(&mut self, bullet: &BulletData) -> Result<(), ProcError> {
-        self.buff.set_position(0);
-        self.buff.get_mut().clear();
-        write_bullet(bullet, &mut self.buff, &mut self.writer)?;
-        Ok(())
+        let result = match self.format {
+            OutputFormat::Phantom => {
+                self.buff.set_position(0);
+                self.buff.get_mut().clear();
+                write_bullet(bullet, &self.template, &self.tags, &mut self.buff, &mut self.writer)
+            },
+            OutputFormat::Uri => write_uri_bullet(bullet, &self.template, &self.tags, &mut self.uri_state, &mut self.writer),
+            OutputFormat::Json => write_json_bullet(bullet, &self.template, &self.tags, &mut self.writer).map_err(ProcError::Io),
+        };
+        match result {
+            Err(ProcError::Io(err)) => Err(ProcError::Output { path: self.name.clone(), err: err }),
+            result => result,
+        }
     }
+    fn finish(&mut self) -> Result<(), ProcError> {
+        match self.writer.close() {
+            Ok(()) => Ok(()),
+            Err(err) => Err(ProcError::Output { path: self.name.clone(), err: err }),
+        }
+    }
+}
+
+/// Output stream which has to be closed explicitly to get all data written
+trait AmmoSink: Write {
+    fn close(&mut self) -> io::Result<()>;
 }
 
-struct ProcWriter {
-    child: Box<Child>,
+impl<W: Write> AmmoSink for BufWriter<W> {
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()
+    }
 }
 
-impl Write for ProcWriter {
+struct GzWriter {
+    encoder: Option<BufWriter<GzEncoder<File>>>,
+}
+
+impl Write for GzWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let stdin = self.child.stdin.as_mut();
-        stdin.unwrap().write(buf)
+        match self.encoder.as_mut() {
+            Some(encoder) => encoder.write(buf),
+            None => Err(io::Error::new(io::ErrorKind::Other, "gzip stream is already closed")),
+        }
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        let stdin = self.child.stdin.as_mut();
-        stdin.unwrap().flush()
+        match self.encoder.as_mut() {
+            Some(encoder) => encoder.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl AmmoSink for GzWriter {
+    fn close(&mut self) -> io::Result<()> {
+        if let Some(buffered) = self.encoder.take() {
+            let encoder = buffered.into_inner()?;
+            encoder.finish()?;
+        }
+        Ok(())
     }
 }
 
@@ -205,3 +1144,457 @@ impl Write for StdoutWriter {
         io::stdout().flush()
     }
 }
+
+impl AmmoSink for StdoutWriter {
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+
+/// Helpers for tests of processors
+#[cfg(test)]
+pub mod testing {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use ammo::{BulletData, StoredBullet};
+    use error::ProcError;
+    use super::AmmoProcessor;
+
+    /// Remembers all bullets it gets and sources they come from, clones share them
+    #[derive(Clone, Default)]
+    pub struct Collect {
+        bullets: Rc<RefCell<Vec<StoredBullet>>>,
+        sources: Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl Collect {
+        pub fn boxed(&self) -> Box<AmmoProcessor> {
+            Box::new(self.clone())
+        }
+
+        pub fn map<T, F: Fn(&BulletData) -> T>(&self, f: F) -> Vec<T> {
+            self.bullets.borrow().iter().map(|bullet| f(&bullet.get_data())).collect()
+        }
+
+        pub fn resources(&self) -> Vec<Vec<u8>> {
+            self.map(|bullet| bullet.resource.to_vec())
+        }
+
+        /// Indexes passed to `begin_source`
+        pub fn sources(&self) -> Vec<usize> {
+            self.sources.borrow().clone()
+        }
+    }
+
+    impl AmmoProcessor for Collect {
+        fn process(&mut self, bullet: &BulletData) -> Result<(), ProcError> {
+            self.bullets.borrow_mut().push(StoredBullet::from_data(bullet));
+            Ok(())
+        }
+        fn begin_source(&mut self, index: usize) {
+            self.sources.borrow_mut().push(index);
+        }
+    }
+
+    /// Passes `count` bullets `search?text=N` to processor and finishes it
+    pub fn run(processor: &mut AmmoProcessor, count: usize) -> Result<(), ProcError> {
+        for i in 0..count {
+            let resource = format!("search?text={}", i);
+            processor.process(&BulletData { resource: resource.as_bytes(), ..Default::default() })?;
+        }
+        processor.finish()
+    }
+
+    /// Runs `count` bullets through processor made by `make` and returns resources it passed on
+    pub fn sample<F>(count: usize, make: F) -> Result<Vec<Vec<u8>>, ProcError>
+        where F: FnOnce(Box<AmmoProcessor>) -> Result<Box<AmmoProcessor>, ProcError>
+    {
+        let collect = Collect::default();
+        run(&mut *make(collect.boxed())?, count)?;
+        Ok(collect.resources())
+    }
+
+    /// Runs bullets of inputs through processor made by `make`, telling it where each
+    /// input begins, and returns resources it passed on
+    pub fn sample_inputs<F>(inputs: &[Vec<StoredBullet>], make: F) -> Result<Vec<Vec<u8>>, ProcError>
+        where F: FnOnce(Box<AmmoProcessor>) -> Result<Box<AmmoProcessor>, ProcError>
+    {
+        let collect = Collect::default();
+        let mut processor = make(collect.boxed())?;
+        for (index, input) in inputs.iter().enumerate() {
+            processor.begin_source(index);
+            for bullet in input {
+                processor.process(&bullet.get_data())?;
+            }
+        }
+        processor.finish()?;
+        Ok(collect.resources())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ammo::BulletData;
+    use super::*;
+    use super::testing::{self, sample, sample_inputs};
+    use std::env;
+    use std::fs;
+    use logut::read::{ReadByLine, FileLinesReader};
+
+    /// N of `search?text=N`
+    fn text_number(resource: &[u8]) -> usize {
+        String::from_utf8_lossy(&resource[12..]).parse().unwrap()
+    }
+
+    #[test]
+    fn stream_sampling() {
+        let selected = sample(100000, |sub| Ok(Box::new(StreamSampling::new(10, make_rng(Some(42)), sub)?))).unwrap();
+        assert_eq!(selected.len(), 10);
+        assert_eq!(selected, sample(100000, |sub| Ok(Box::new(StreamSampling::new(10, make_rng(Some(42)), sub)?))).unwrap());
+        let mut dedup = selected.clone();
+        dedup.dedup();
+        assert_eq!(dedup.len(), 10);
+        // whole input if it is as big as the sample
+        let all = sample(10, |sub| Ok(Box::new(StreamSampling::new(10, make_rng(Some(1)), sub)?))).unwrap();
+        assert_eq!(all[0], b"search?text=0");
+        assert_eq!(all[9], b"search?text=9");
+    }
+
+    #[test]
+    fn stream_sampling_is_uniform() {
+        // each of 10 input bullets has to get into sample of 5 in half of the runs
+        let mut hits = vec![0; 10];
+        for seed in 0..2000 {
+            for resource in sample(10, |sub| Ok(Box::new(StreamSampling::new(5, make_rng(Some(seed)), sub)?))).unwrap() {
+                hits[text_number(&resource)] += 1;
+            }
+        }
+        for h in hits {
+            assert!(h > 850 && h < 1150, "{}", h);
+        }
+    }
+
+    #[test]
+    fn stream_sampling_not_enough_input() {
+        let mut sampler = StreamSampling::new(10, make_rng(None), testing::Collect::default().boxed()).unwrap();
+        sampler.process(&BulletData::default()).unwrap();
+        assert!(sampler.finish().is_err());
+    }
+
+    /// 90 bullets of place 'big', 9 of 'medium', 1 of 'small'
+    fn strata_input() -> Vec<StoredBullet> {
+        (0..100).map(|i| {
+            let place: &[u8] = if i % 10 != 0 { b"big" } else if i != 0 { b"medium" } else { b"small" };
+            let resource = format!("search?place={}&n={}", String::from_utf8_lossy(place), i);
+            StoredBullet::from_data(&BulletData { resource: resource.as_bytes(), place: place, ..Default::default() })
+        }).collect()
+    }
+
+    fn count_place(sample: &[Vec<u8>], place: &str) -> usize {
+        let prefix = format!("search?place={}&", place);
+        sample.iter().filter(|r| r.starts_with(prefix.as_bytes())).count()
+    }
+
+    #[test]
+    fn stratified_sampling() {
+        let input = [strata_input()];
+        let selected = sample_inputs(&input, |sub| Ok(Box::new(StratifiedSampling::new(20, StratumKey::Place, Quotas::Proportional, make_rng(Some(1)), sub)))).unwrap();
+        assert_eq!(selected.len(), 20);
+        assert_eq!(count_place(&selected, "big"), 18);
+        assert_eq!(count_place(&selected, "medium"), 2);
+        assert_eq!(count_place(&selected, "small"), 0);
+
+        let selected = sample_inputs(&input, |sub| Ok(Box::new(StratifiedSampling::new(21, StratumKey::Place, Quotas::Uniform, make_rng(Some(1)), sub)))).unwrap();
+        assert_eq!(selected.len(), 21);
+        assert_eq!(count_place(&selected, "small"), 1);
+        assert_eq!(count_place(&selected, "medium"), 9);
+        assert_eq!(count_place(&selected, "big"), 11);
+
+        let quotas = Quotas::Explicit(parse_quotas("big 1\nmedium 1\nnone 0").unwrap());
+        let selected = sample_inputs(&input, |sub| Ok(Box::new(StratifiedSampling::new(10, StratumKey::Place, quotas, make_rng(Some(1)), sub)))).unwrap();
+        assert_eq!(count_place(&selected, "big"), 5);
+        assert_eq!(count_place(&selected, "medium"), 5);
+
+        // leftover of tied shares goes to the first stratum by key, whatever the hash order is
+        let quotas = Quotas::Explicit(parse_quotas("medium 1\nbig 1").unwrap());
+        let selected = sample_inputs(&input, |sub| Ok(Box::new(StratifiedSampling::new(9, StratumKey::Place, quotas, make_rng(Some(1)), sub)))).unwrap();
+        assert_eq!(count_place(&selected, "big"), 5);
+        assert_eq!(count_place(&selected, "medium"), 4);
+
+        let quotas = Quotas::Explicit(parse_quotas("big 1\nmedium 1").unwrap());
+        assert!(sample_inputs(&input, |sub| Ok(Box::new(StratifiedSampling::new(20, StratumKey::Place, quotas, make_rng(Some(1)), sub)))).is_err());
+        assert!(sample_inputs(&input, |sub| Ok(Box::new(StratifiedSampling::new(101, StratumKey::Place, Quotas::Uniform, make_rng(Some(1)), sub)))).is_err());
+        assert!(parse_quotas("big").is_err());
+        assert!(parse_quotas("big -1").is_err());
+    }
+
+    /// Quotas of inputs 'a' and 'b'
+    fn source_quotas(a: SourceQuota, b: SourceQuota) -> Vec<(String, SourceQuota)> {
+        vec![("a".to_string(), a), ("b".to_string(), b)]
+    }
+
+    #[test]
+    fn source_sampling() {
+        // 90 bullets in input 'a', 10 in 'b'
+        let inputs: Vec<Vec<StoredBullet>> = [("a", 90), ("b", 10)].iter().map(|&(name, count)| {
+            (0..count).map(|i| StoredBullet::from_data(&BulletData { resource: format!("{}{}", name, i).as_bytes(), ..Default::default() })).collect()
+        }).collect();
+        let from = |selected: &[Vec<u8>], name: u8| selected.iter().filter(|r| r[0] == name).count();
+        let quotas = source_quotas(SourceQuota::Weight(40.0), SourceQuota::Weight(60.0));
+        let selected = sample_inputs(&inputs, |sub| Ok(Box::new(SourceSampling::new(10, quotas, make_rng(Some(1)), sub)?))).unwrap();
+        assert_eq!((from(&selected, b'a'), from(&selected, b'b')), (4, 6));
+        // selected bullets are mixed up
+        assert!(selected[..6].iter().any(|r| r[0] == b'a'));
+
+        let quotas = source_quotas(SourceQuota::Count(12), SourceQuota::default());
+        let selected = sample_inputs(&inputs, |sub| Ok(Box::new(SourceSampling::new(20, quotas, make_rng(Some(1)), sub)?))).unwrap();
+        assert_eq!((from(&selected, b'a'), from(&selected, b'b')), (12, 8));
+        let quotas = source_quotas(SourceQuota::Count(5), SourceQuota::Count(5));
+        let selected = sample_inputs(&inputs, |sub| Ok(Box::new(SourceSampling::new(10, quotas, make_rng(Some(1)), sub)?))).unwrap();
+        assert_eq!(selected.len(), 10);
+
+        let quotas = source_quotas(SourceQuota::default(), SourceQuota::default());
+        match sample_inputs(&inputs, |sub| Ok(Box::new(SourceSampling::new(40, quotas, make_rng(Some(1)), sub)?))) {
+            Err(ProcError::NotEnoughInput(message)) => assert_eq!(message, "Not enough input lines in b: have seen 10 but at least 20 were expected"),
+            _ => panic!("expected not enough input error"),
+        }
+        let quotas = source_quotas(SourceQuota::Count(5), SourceQuota::Count(5));
+        assert!(sample_inputs(&inputs, |sub| Ok(Box::new(SourceSampling::new(20, quotas, make_rng(Some(1)), sub)?))).is_err());
+        let quotas = source_quotas(SourceQuota::Count(15), SourceQuota::default());
+        assert!(sample_inputs(&inputs, |sub| Ok(Box::new(SourceSampling::new(10, quotas, make_rng(Some(1)), sub)?))).is_err());
+    }
+
+    #[test]
+    fn proportional_split() {
+        assert_eq!(split_proportionally(10, &[1.0, 1.0, 1.0]), vec![4, 3, 3]);
+        assert_eq!(split_proportionally(10, &[0.0, 2.0, 3.0]), vec![0, 4, 6]);
+        assert_eq!(split_proportionally(10, &[0.0]), vec![0]);
+        assert_eq!(split_proportionally(0, &[1.0, 2.0]), vec![0, 0]);
+    }
+
+    /// Bullets `search?text=N` with the given weights
+    fn weighted_input(weights: &[f64]) -> Vec<StoredBullet> {
+        weights.iter().enumerate().map(|(i, weight)| {
+            let resource = format!("search?text={}", i);
+            StoredBullet::from_data(&BulletData { resource: resource.as_bytes(), weight: *weight, ..Default::default() })
+        }).collect()
+    }
+
+    #[test]
+    fn weighted_sampling() {
+        // bullet i has weight i, so it is selected in i/45 of the runs
+        let input = [weighted_input(&(0..10).map(|i| i as f64).collect::<Vec<_>>())];
+        let mut hits = vec![0; 10];
+        for seed in 0..4500 {
+            for resource in sample_inputs(&input, |sub| Ok(Box::new(WeightedSampling::new(1, make_rng(Some(seed)), sub)))).unwrap() {
+                hits[text_number(&resource)] += 1;
+            }
+        }
+        assert_eq!(hits[0], 0);
+        for (i, h) in hits.iter().enumerate().skip(1) {
+            assert!(*h > 65 * i && *h < 135 * i, "{}: {}", i, h);
+        }
+
+        // the first bullet weighs as much as all others together
+        let mut weights = vec![1.0; 100];
+        weights[0] = 99.0;
+        let input = [weighted_input(&weights)];
+        let mut first = 0;
+        for seed in 0..2000 {
+            let selected = sample_inputs(&input, |sub| Ok(Box::new(WeightedSampling::new(3, make_rng(Some(seed)), sub)))).unwrap();
+            assert_eq!(selected.len(), 3);
+            first += selected.iter().filter(|r| text_number(r) == 0).count();
+        }
+        assert!(first > 1500, "{}", first);
+
+        assert_eq!(sample_inputs(&input, |sub| Ok(Box::new(WeightedSampling::new(5, make_rng(Some(7)), sub)))).unwrap(),
+                   sample_inputs(&input, |sub| Ok(Box::new(WeightedSampling::new(5, make_rng(Some(7)), sub)))).unwrap());
+        let input = [weighted_input(&[1.0, 0.0, 2.0, 0.0, 1.0, 1.0, 1.0])];
+        assert!(sample_inputs(&input, |sub| Ok(Box::new(WeightedSampling::new(6, make_rng(Some(1)), sub)))).is_err());
+    }
+
+    #[test]
+    fn bootstrap_sampling() {
+        let selected = sample(10, |sub| Ok(Box::new(BootstrapSampling::new(1000, None, make_rng(Some(42)), sub)))).unwrap();
+        assert_eq!(selected.len(), 1000);
+        assert_eq!(selected, sample(10, |sub| Ok(Box::new(BootstrapSampling::new(1000, None, make_rng(Some(42)), sub)))).unwrap());
+        for i in 0..10 {
+            let resource = format!("search?text={}", i);
+            let n = selected.iter().filter(|r| *r == resource.as_bytes()).count();
+            assert!(n > 60 && n < 140, "{}: {}", resource, n);
+        }
+
+        let selected = sample(10, |sub| Ok(Box::new(BootstrapSampling::new(1000, Some(100), make_rng(Some(42)), sub)))).unwrap();
+        for i in 0..10 {
+            let resource = format!("search?text={}", i);
+            assert_eq!(selected.iter().filter(|r| *r == resource.as_bytes()).count(), 100);
+        }
+
+        assert!(sample(10, |sub| Ok(Box::new(BootstrapSampling::new(1000, Some(99), make_rng(Some(42)), sub)))).is_err());
+        assert!(sample(0, |sub| Ok(Box::new(BootstrapSampling::new(1, None, make_rng(Some(42)), sub)))).is_err());
+        assert_eq!(sample(0, |sub| Ok(Box::new(BootstrapSampling::new(0, None, make_rng(Some(42)), sub)))).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn repeat_mixer() {
+        let collect = testing::Collect::default();
+        let mut mixer = RepeatMixer::new(0.3, 100, Recency::Uniform, make_rng(Some(3)), collect.boxed());
+        testing::run(&mut mixer, 10000).unwrap();
+        let (output, ratio) = (collect.resources(), mixer.achieved_ratio());
+        let mut unique = output.clone();
+        unique.sort();
+        unique.dedup();
+        // no input bullet is lost
+        assert_eq!(unique.len(), 10000);
+        assert!((ratio - 0.3).abs() < 0.02, "{}", ratio);
+        assert_eq!(output.len() - unique.len(), (ratio * output.len() as f64).round() as usize);
+
+        // recent bullets are repeated: repeat of text=N appears soon after text=N
+        let collect = testing::Collect::default();
+        let mut mixer = RepeatMixer::new(0.5, 100, Recency::Exponential(2.0), make_rng(Some(3)), collect.boxed());
+        testing::run(&mut mixer, 10000).unwrap();
+        let output = collect.resources();
+        let mut last_seen = HashMap::new();
+        let mut ages = Vec::new();
+        for (i, resource) in output.iter().enumerate() {
+            if let Some(prev) = last_seen.insert(resource.clone(), i) {
+                ages.push(i - prev);
+            }
+        }
+        let mean_age = ages.iter().sum::<usize>() as f64 / ages.len() as f64;
+        assert!(mean_age < 10.0, "{}", mean_age);
+
+        let collect = testing::Collect::default();
+        let mut mixer = RepeatMixer::new(0.0, 100, Recency::Uniform, make_rng(Some(3)), collect.boxed());
+        testing::run(&mut mixer, 10000).unwrap();
+        let output = collect.resources();
+        assert_eq!(mixer.achieved_ratio(), 0.0);
+        assert_eq!(output[9999], b"search?text=9999".to_vec());
+
+        let collect = testing::Collect::default();
+        let mut mixer = RepeatMixer::new(0.0, 10, Recency::Uniform, make_rng(Some(3)), collect.boxed());
+        mixer.begin_source(1);
+        testing::run(&mut mixer, 10).unwrap();
+        assert_eq!(collect.sources(), vec![1]);
+        let mut lines = Vec::new();
+        mixer.report(&mut lines);
+        assert_eq!(lines, vec!["Repeated bullets: 0 of 10 (0.00%, target 0.00%)".to_string()]);
+
+        // planned repeats keep output size
+        let collect = testing::Collect::default();
+        let mut mixer = RepeatMixer::new(0.3, 100, Recency::Uniform, make_rng(Some(3)), collect.boxed()).with_plan(700, 300);
+        testing::run(&mut mixer, 700).unwrap();
+        let output = collect.resources();
+        assert_eq!(output.len(), 1000);
+        assert_eq!(output[0], b"search?text=0".to_vec());
+        // repeats are spread over the whole output
+        assert!(output[900..].iter().any(|r| r.as_slice() != b"search?text=699"));
+        assert_eq!(mixer.achieved_ratio(), 0.3);
+
+        // fewer bullets than planned, the rest of repeats is added at the end
+        let collect = testing::Collect::default();
+        let mut mixer = RepeatMixer::new(0.3, 100, Recency::Uniform, make_rng(Some(3)), collect.boxed()).with_plan(700, 300);
+        testing::run(&mut mixer, 10).unwrap();
+        assert_eq!(collect.resources().len(), 310);
+    }
+
+    #[test]
+    fn round_robin() {
+        let shards: Vec<testing::Collect> = (0..2).map(|_| testing::Collect::default()).collect();
+        let mut round_robin = RoundRobin::new(shards.iter().map(testing::Collect::boxed).collect());
+        round_robin.begin_source(1);
+        testing::run(&mut round_robin, 5).unwrap();
+        assert_eq!(shards[0].resources(), vec![b"search?text=0".to_vec(), b"search?text=2".to_vec(), b"search?text=4".to_vec()]);
+        assert_eq!(shards[1].resources().len(), 2);
+        assert!(shards.iter().all(|shard| shard.sources() == vec![1]));
+    }
+
+    #[test]
+    fn hash_sharding() {
+        let shards: Vec<testing::Collect> = (0..3).map(|_| testing::Collect::default()).collect();
+        let mut sharding = HashSharding::new(StratumKey::CgiParam(b"uid".to_vec()), shards.iter().map(testing::Collect::boxed).collect());
+        sharding.begin_source(0);
+        for i in 0..3000 {
+            let resource = format!("search?text={}&uid={}", i, i % 100);
+            sharding.process(&BulletData { resource: resource.as_bytes(), ..Default::default() }).unwrap();
+        }
+        sharding.process(&BulletData { resource: b"search?text=x", ..Default::default() }).unwrap();
+        sharding.finish().unwrap();
+        // each user goes to a single shard
+        let mut user_shards = HashMap::new();
+        for (i, shard) in shards.iter().enumerate() {
+            let shard = shard.resources();
+            assert!(shard.len() > 700 && shard.len() < 1300, "{}", shard.len());
+            for resource in &shard {
+                if let Some(uid) = logut::get_cgi_param_value_naive(resource, b"uid") {
+                    assert_eq!(*user_shards.entry(uid.to_vec()).or_insert(i), i);
+                }
+            }
+        }
+        assert_eq!(user_shards.len(), 100);
+        assert!(shards.iter().all(|shard| shard.sources() == vec![0]));
+        // reference values of FNV-1a
+        assert_eq!(stable_hash(b""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash(b"a"), 0xaf63dc4c8601ec8c);
+        let mut lines = Vec::new();
+        sharding.report(&mut lines);
+        let report = &lines[0];
+        assert!(report.starts_with("Shard sizes: "), "{}", report);
+        assert!(report.ends_with(" of mean), 1 bullets without key"), "{}", report);
+    }
+
+    #[test]
+    fn seeded_sampling_is_reproducible() {
+        let reservoir = sample(1000, |sub| Ok(Box::new(ReserviorSampling::new(10, make_rng(Some(42)), sub)))).unwrap();
+        assert_eq!(reservoir.len(), 10);
+        assert_eq!(reservoir, sample(1000, |sub| Ok(Box::new(ReserviorSampling::new(10, make_rng(Some(42)), sub)))).unwrap());
+        assert!(reservoir != sample(1000, |sub| Ok(Box::new(ReserviorSampling::new(10, make_rng(Some(43)), sub)))).unwrap());
+        let method_s = sample(1000, |sub| MethodS::new(1000, 10, make_rng(Some(42)), sub)).unwrap();
+        assert_eq!(method_s.len(), 10);
+        assert_eq!(method_s, sample(1000, |sub| MethodS::new(1000, 10, make_rng(Some(42)), sub)).unwrap());
+        assert!(method_s != sample(1000, |sub| MethodS::new(1000, 10, make_rng(Some(43)), sub)).unwrap());
+        match MethodS::new(5, 10, make_rng(Some(42)), testing::Collect::default().boxed()) {
+            Err(ProcError::NotEnoughInput(_)) => {},
+            _ => panic!("expected not enough input error"),
+        }
+    }
+
+    #[test]
+    fn write_gzip() {
+        let path = env::temp_dir().join(format!("gen_ammo_write_gzip_{}.gz", ::std::process::id()));
+        {
+            let mut writer = WriteAmmo::to_gzip(&path, Compression::Best).unwrap();
+            let bullet = BulletData { resource: b"search?place=prime", place: b"prime", ..Default::default() };
+            writer.process(&bullet).unwrap();
+            writer.process(&bullet).unwrap();
+            writer.finish().unwrap();
+        }
+        let mut lines: Vec<Vec<u8>> = Vec::new();
+        FileLinesReader { filename: path.clone() }.process_lines(&mut |line: &[u8]| lines.push(line.to_vec())).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(lines.iter().filter(|l| l.starts_with(b"GET /search?place=prime ")).count(), 2);
+    }
+
+    #[test]
+    fn write_errors() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "broken"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let bullet = BulletData { resource: b"search", ..Default::default() };
+        let mut writer = WriteAmmo::to_stream(Box::new(Broken)).unwrap();
+        writer.process(&bullet).unwrap();
+        assert_eq!(writer.finish().unwrap_err().exit_code(), 73);
+
+        // uri ammo can't carry POST requests
+        let mut writer = WriteAmmo::to_stream(Box::new(io::sink())).unwrap().with_format(OutputFormat::Uri);
+        let post = BulletData { resource: b"api", method: b"POST", ..Default::default() };
+        assert_eq!(writer.process(&post).unwrap_err().exit_code(), 78);
+    }
+}
@@ -0,0 +1,210 @@
+//! Deduplication of bullets
+//!
+//! Requests are compared after normalization: host is lowercased, CGI params are
+//! sorted and volatile params (request ids, timestamps and so on) are dropped,
+//! so `search?reqid=1&b=2&a=1` and `search?a=1&b=2&reqid=2` are the same request.
+//! Method and body are compared as they are.
+
+use std::collections::HashSet;
+use error::ProcError;
+use ammo::BulletData;
+use ammo_proc::{self, AmmoProcessor};
+
+/// Returns resource with sorted CGI params, params listed in `volatile` and empty ones are dropped
+pub fn normalize_resource(resource: &[u8], volatile: &[Vec<u8>]) -> Vec<u8> {
+    let (path, query) = match resource.iter().position(|b| *b == b'?') {
+        Some(pos) => (&resource[..pos], &resource[pos + 1..]),
+        None => return resource.to_vec(),
+    };
+    let mut params: Vec<&[u8]> = query.split(|b| *b == b'&')
+        .filter(|param| !param.is_empty())
+        .filter(|param| {
+            let name = param.split(|b| *b == b'=').next().unwrap_or(b"");
+            !volatile.iter().any(|v| v.as_slice() == name)
+        })
+        .collect();
+    params.sort();
+    let mut result = path.to_vec();
+    for (i, param) in params.iter().enumerate() {
+        result.push(if i == 0 { b'?' } else { b'&' });
+        result.extend_from_slice(param);
+    }
+    result
+}
+
+/// Makes key which is equal for requests considered the same
+pub fn request_key(bullet: &BulletData, volatile: &[Vec<u8>]) -> Vec<u8> {
+    let mut key = bullet.method.to_vec();
+    key.push(b' ');
+    key.extend(bullet.host.iter().map(|b| b.to_ascii_lowercase()));
+    key.push(b'/');
+    key.extend(normalize_resource(bullet.resource, volatile));
+    if !bullet.body.is_empty() {
+        key.push(b'\n');
+        key.extend_from_slice(bullet.body);
+    }
+    key
+}
+
+/// Bloom filter with `hashes` hash functions made by double hashing
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    bits_count: u64,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    pub fn new(bits_count: usize, hashes: u32) -> BloomFilter {
+        let bits_count = bits_count.max(64);
+        BloomFilter {
+            bits: vec![0; (bits_count + 63) / 64],
+            bits_count: bits_count as u64,
+            hashes: hashes.max(1),
+        }
+    }
+
+    /// Makes filter for given number of distinct items and false positive rate
+    pub fn with_capacity(items: usize, error_rate: f64) -> BloomFilter {
+        let ln2 = ::std::f64::consts::LN_2;
+        let bits_count = (-(items.max(1) as f64) * error_rate.ln() / (ln2 * ln2)).ceil();
+        let hashes = (bits_count / items.max(1) as f64 * ln2).round();
+        BloomFilter::new(bits_count as usize, hashes as u32)
+    }
+
+    /// Adds item, returns false if it was (probably) added before
+    pub fn insert(&mut self, item: &[u8]) -> bool {
+        let h1 = hash(item, 0);
+        let h2 = hash(item, 1) | 1;
+        let mut is_new = false;
+        for i in 0..self.hashes as u64 {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.bits_count;
+            let (word, mask) = ((bit / 64) as usize, 1u64 << (bit % 64));
+            if self.bits[word] & mask == 0 {
+                self.bits[word] |= mask;
+                is_new = true;
+            }
+        }
+        is_new
+    }
+}
+
+/// Hash is stable across Rust releases, so Bloom filter drops the same requests between runs
+fn hash(item: &[u8], salt: u8) -> u64 {
+    ammo_proc::extend_stable_hash(ammo_proc::stable_hash(&[salt]), item)
+}
+
+pub enum SeenSet {
+    /// Remembers every request, memory grows with the number of distinct requests
+    Exact(HashSet<Vec<u8>>),
+    /// Bounded memory, but some unique requests are taken for duplicates
+    Bloom(BloomFilter),
+}
+
+impl SeenSet {
+    fn insert(&mut self, key: Vec<u8>) -> bool {
+        match *self {
+            SeenSet::Exact(ref mut set) => set.insert(key),
+            SeenSet::Bloom(ref mut filter) => filter.insert(&key),
+        }
+    }
+}
+
+/// Passes only the first occurrence of each request to subprocessor
+pub struct Dedup {
+    seen: SeenSet,
+    volatile: Vec<Vec<u8>>,
+    subprocessor: Box<AmmoProcessor>,
+}
+
+impl Dedup {
+    pub fn new(seen: SeenSet, volatile: Vec<Vec<u8>>, subprocessor: Box<AmmoProcessor>) -> Dedup {
+        Dedup {
+            seen: seen,
+            volatile: volatile,
+            subprocessor: subprocessor,
+        }
+    }
+}
+
+impl AmmoProcessor for Dedup {
+    fn process(&mut self, bullet: &BulletData) -> Result<(), ProcError> {
+        if self.seen.insert(request_key(bullet, &self.volatile)) {
+            self.subprocessor.process(bullet)
+        } else {
+            Ok(())
+        }
+    }
+    fn begin_source(&mut self, index: usize) {
+        self.subprocessor.begin_source(index)
+    }
+    fn report(&self, lines: &mut Vec<String>) {
+        self.subprocessor.report(lines)
+    }
+    fn finish(&mut self) -> Result<(), ProcError> {
+        self.subprocessor.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ammo_proc::testing::Collect;
+
+    #[test]
+    fn normalize() {
+        let volatile = vec![b"reqid".to_vec(), b"ts".to_vec()];
+        assert_eq!(normalize_resource(b"search?text=a&reqid=123&lr=2&ts=", &volatile), b"search?lr=2&text=a".to_vec());
+        assert_eq!(normalize_resource(b"search?reqid=123", &volatile), b"search".to_vec());
+        assert_eq!(normalize_resource(b"search?b=1&&a=2&", &[]), b"search?a=2&b=1".to_vec());
+        assert_eq!(normalize_resource(b"search", &volatile), b"search".to_vec());
+        assert_eq!(normalize_resource(b"", &volatile), b"".to_vec());
+
+        let a = BulletData { host: b"Example.COM", resource: b"search?b=2&a=1&reqid=1", ..Default::default() };
+        let b = BulletData { host: b"example.com", resource: b"search?a=1&reqid=2&b=2", ..Default::default() };
+        let c = BulletData { host: b"example.com", resource: b"search?a=1&b=2", method: b"POST", body: b"x", ..Default::default() };
+        assert_eq!(request_key(&a, &volatile), request_key(&b, &volatile));
+        assert!(request_key(&a, &volatile) != request_key(&c, &volatile));
+        assert!(request_key(&a, &[]) != request_key(&b, &[]));
+    }
+
+    fn dedup(seen: SeenSet) -> Vec<Vec<u8>> {
+        let collect = Collect::default();
+        let mut dedup = Dedup::new(seen, vec![b"reqid".to_vec()], collect.boxed());
+        for i in 0..1000 {
+            let resource = format!("search?text={}&reqid={}", i % 100, i);
+            dedup.process(&BulletData { resource: resource.as_bytes(), ..Default::default() }).unwrap();
+        }
+        dedup.finish().unwrap();
+        collect.resources()
+    }
+
+    #[test]
+    fn exact_dedup() {
+        let result = dedup(SeenSet::Exact(HashSet::new()));
+        assert_eq!(result.len(), 100);
+        assert_eq!(result[0], b"search?text=0&reqid=0".to_vec());
+        assert_eq!(result[99], b"search?text=99&reqid=99".to_vec());
+    }
+
+    #[test]
+    fn bloom_dedup() {
+        let result = dedup(SeenSet::Bloom(BloomFilter::with_capacity(1000, 0.0001)));
+        assert_eq!(result.len(), 100);
+
+        let filter = BloomFilter::with_capacity(1000000, 0.01);
+        let memory = filter.bits.len() * 8;
+        assert!(memory > 1000000 && memory < 1300000, "{}", memory);
+        assert_eq!(filter.hashes, 7);
+
+        // false positive rate holds with the stable hash
+        let mut filter = BloomFilter::with_capacity(10000, 0.01);
+        let dropped = (0..10000).filter(|i| !filter.insert(format!("search?text={}", i).as_bytes())).count();
+        assert!(dropped < 150, "{}", dropped);
+
+        // filter which is too small drops some unique items but never passes duplicates
+        let mut filter = BloomFilter::new(64, 2);
+        let added = (0..1000).filter(|i| filter.insert(format!("{}", i).as_bytes())).count();
+        assert!(added < 1000);
+        assert!(!filter.insert(b"0"));
+    }
+}
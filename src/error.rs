@@ -5,14 +5,38 @@ use std::error;
 #[derive(Debug)]
 pub enum ProcError {
     Io(io::Error),
-    Logic(String),
+    /// Input or rules file can't be parsed, line is unknown for some errors
+    InputParse { path: String, line: Option<u64>, message: String },
+    /// Options don't make sense together
+    Config(String),
+    /// Input has too few lines for the requested sample
+    NotEnoughInput(String),
+    /// Ammo can't be written
+    Output { path: String, err: io::Error },
+}
+
+impl ProcError {
+    /// Exit code of the process failed with this error, values follow sysexits.h
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            ProcError::Io(_) => 74,
+            ProcError::InputParse { .. } => 65,
+            ProcError::Config(_) => 78,
+            ProcError::NotEnoughInput(_) => 66,
+            ProcError::Output { .. } => 73,
+        }
+    }
 }
 
 impl fmt::Display for ProcError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ProcError::Io(ref err) => write!(f, "IO error: {}", err),
-            ProcError::Logic(ref err) => write!(f, "Logic error: {}", err),
+            ProcError::InputParse { ref path, line: Some(line), ref message } => write!(f, "{}:{}: {}", path, line, message),
+            ProcError::InputParse { ref path, line: None, ref message } => write!(f, "{}: {}", path, message),
+            ProcError::Config(ref err) => write!(f, "Configuration error: {}", err),
+            ProcError::NotEnoughInput(ref err) => write!(f, "{}", err),
+            ProcError::Output { ref path, ref err } => write!(f, "Can't write {}: {}", path, err),
         }
     }
 }
@@ -23,7 +47,10 @@ impl error::Error for ProcError {
         // implementations.
         match *self {
             ProcError::Io(ref err) => err.description(),
-            ProcError::Logic(ref message) => &message,
+            ProcError::InputParse { ref message, .. } => message,
+            ProcError::Config(ref message) => message,
+            ProcError::NotEnoughInput(ref message) => message,
+            ProcError::Output { ref err, .. } => err.description(),
         }
     }
 
@@ -34,7 +61,8 @@ impl error::Error for ProcError {
             // to a trait object `&Error`. This works because both error types
             // implement `Error`.
             ProcError::Io(ref err) => Some(err),
-            ProcError::Logic(_) => None,
+            ProcError::Output { ref err, .. } => Some(err),
+            _ => None,
         }
     }
 }
@@ -44,3 +72,28 @@ impl From<io::Error> for ProcError {
         ProcError::Io(err)
     }
 }
+
+/// Error in line of text parsed line by line, such as rules or quotas file
+#[derive(Debug, PartialEq)]
+pub struct LineError {
+    /// Number of line starting from 1
+    pub line: u64,
+    pub message: String,
+}
+
+impl LineError {
+    pub fn new(index: usize, message: String) -> LineError {
+        LineError { line: index as u64 + 1, message: message }
+    }
+
+    /// Adds name of the file the text was read from
+    pub fn in_file(self, path: &str) -> ProcError {
+        ProcError::InputParse { path: path.to_string(), line: Some(self.line), message: self.message }
+    }
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
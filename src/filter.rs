@@ -0,0 +1,443 @@
+//! Include/exclude rules for input lines
+//!
+//! Rule expression syntax:
+//!
+//! ```text
+//! substr:TEXT          line contains TEXT
+//! regex:RE             line matches regular expression RE
+//! cgi:NAME             CGI param NAME is present
+//! cgi:NAME=VALUE       CGI param NAME equals VALUE
+//! nocgi:NAME           CGI param NAME is absent
+//! host:HOST            request host is HOST (case insensitive)
+//! place:PLACE          place is PLACE
+//! wizard:NAME          NAME is one of request wizards
+//! ```
+//!
+//! Rules may be combined with `and`, `or`, `not` and parentheses, e.g.
+//! `(place:prime or place:dubai) and not cgi:debug=1`. Values containing
+//! spaces or unbalanced parentheses should be double-quoted: `regex:"a b)"`.
+//! Inside quotes `\"` and `\\` stand for `"` and `\`, other backslashes are kept.
+//!
+//! Filter file contains one rule per line prefixed with `include` or `exclude`.
+//! Empty lines and lines starting with `#` are ignored.
+
+use std::fmt;
+use regex::bytes::Regex;
+use twoway;
+use logut;
+use ammo;
+use error::LineError;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Action {
+    Include,
+    Exclude,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Action::Include => "include",
+            Action::Exclude => "exclude",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Rule {
+    Substring(Vec<u8>),
+    Regex(Regex),
+    CgiPresent(Vec<u8>),
+    CgiAbsent(Vec<u8>),
+    CgiEquals(Vec<u8>, Vec<u8>),
+    Host(Vec<u8>),
+    Place(Vec<u8>),
+    Wizard(Vec<u8>),
+    And(Vec<Rule>),
+    Or(Vec<Rule>),
+    Not(Box<Rule>),
+}
+
+impl Rule {
+    /// Checks the rule against raw log line and the bullet made from it
+    pub fn matches(&self, line: &[u8], bullet: &ammo::BulletData) -> bool {
+        match *self {
+            Rule::Substring(ref s) => twoway::find_bytes(line, s).is_some(),
+            Rule::Regex(ref re) => re.is_match(line),
+            Rule::CgiPresent(ref name) => logut::get_cgi_param_value_naive(bullet.resource, name).is_some(),
+            Rule::CgiAbsent(ref name) => logut::get_cgi_param_value_naive(bullet.resource, name).is_none(),
+            Rule::CgiEquals(ref name, ref value) => logut::get_cgi_param_value_naive(bullet.resource, name) == Some(value),
+            Rule::Host(ref host) => bullet.host.eq_ignore_ascii_case(host),
+            Rule::Place(ref place) => bullet.place == &place[..],
+            Rule::Wizard(ref wizard) => ammo::wizard_names(bullet.wizards).iter().any(|w| *w == &wizard[..]),
+            Rule::And(ref rules) => rules.iter().all(|r| r.matches(line, bullet)),
+            Rule::Or(ref rules) => rules.iter().any(|r| r.matches(line, bullet)),
+            Rule::Not(ref rule) => !rule.matches(line, bullet),
+        }
+    }
+
+    /// Returns true if rule looks into parsed fields and not only into raw line
+    pub fn needs_bullet(&self) -> bool {
+        match *self {
+            Rule::Substring(_) | Rule::Regex(_) => false,
+            Rule::And(ref rules) | Rule::Or(ref rules) => rules.iter().any(|r| r.needs_bullet()),
+            Rule::Not(ref rule) => rule.needs_bullet(),
+            _ => true,
+        }
+    }
+}
+
+/// Writes value as rule expression parser reads it, quoted if needed
+fn write_value(f: &mut fmt::Formatter, value: &[u8]) -> fmt::Result {
+    let value = String::from_utf8_lossy(value);
+    if !value.chars().any(|c| c.is_whitespace() || c == '(' || c == ')' || c == '"') {
+        return f.write_str(&value);
+    }
+    f.write_str("\"")?;
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            f.write_str("\\")?;
+        }
+        write!(f, "{}", c)?;
+    }
+    f.write_str("\"")
+}
+
+/// Formats rule in the syntax `parse_rule` understands
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (kind, value) = match *self {
+            Rule::Substring(ref s) => ("substr", s.clone()),
+            Rule::Regex(ref re) => ("regex", re.as_str().as_bytes().to_vec()),
+            Rule::CgiPresent(ref name) => ("cgi", name.clone()),
+            Rule::CgiAbsent(ref name) => ("nocgi", name.clone()),
+            Rule::CgiEquals(ref name, ref value) => ("cgi", [&name[..], b"=", &value[..]].concat()),
+            Rule::Host(ref host) => ("host", host.clone()),
+            Rule::Place(ref place) => ("place", place.clone()),
+            Rule::Wizard(ref wizard) => ("wizard", wizard.clone()),
+            Rule::And(ref rules) | Rule::Or(ref rules) => {
+                let separator = if let Rule::And(_) = *self { " and " } else { " or " };
+                for (i, rule) in rules.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(separator)?;
+                    }
+                    match *rule {
+                        Rule::And(_) | Rule::Or(_) => write!(f, "({})", rule)?,
+                        _ => write!(f, "{}", rule)?,
+                    }
+                }
+                return Ok(());
+            },
+            Rule::Not(ref rule) => return match **rule {
+                Rule::And(_) | Rule::Or(_) => write!(f, "not ({})", rule),
+                _ => write!(f, "not {}", rule),
+            },
+        };
+        write!(f, "{}:", kind)?;
+        write_value(f, &value)
+    }
+}
+
+/// Set of include and exclude rules. Line passes the filter if it matches every
+/// include rule and doesn't match any exclude rule.
+#[derive(Clone)]
+pub struct Filter {
+    rules: Vec<(Action, Rule)>,
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Filter { rules: Vec::new() }
+    }
+
+    /// Drops auxiliary requests (rep-outgoing and subrequests)
+    pub fn with_defaults() -> Filter {
+        let mut filter = Filter::new();
+        filter.add(Action::Exclude, Rule::Substring(b"rep-outgoing=1".to_vec()));
+        filter.add(Action::Exclude, Rule::Substring(b"subrequest=1".to_vec()));
+        filter
+    }
+
+    pub fn add(&mut self, action: Action, rule: Rule) {
+        self.rules.push((action, rule));
+    }
+
+    /// Checks log line, `parser` is used by rules which need parsed request
+    pub fn check(&self, line: &[u8], parser: &logut::LineParser) -> bool {
+        if self.rules.iter().any(|&(_, ref r)| r.needs_bullet()) {
+            let rec = parser.parse(line).unwrap_or_default();
+            let bullet = ammo::make_bullet_data_from_log_record(&rec);
+            self.check_bullet(line, &bullet)
+        } else {
+            self.check_bullet(line, &Default::default())
+        }
+    }
+
+    /// Checks line along with bullet made of it, e.g. raw request and bullet read from ammo
+    pub fn check_bullet(&self, line: &[u8], bullet: &ammo::BulletData) -> bool {
+        self.rejecting_rule(line, bullet).is_none()
+    }
+
+    /// Returns the first rule which line with its bullet doesn't pass
+    pub fn rejecting_rule(&self, line: &[u8], bullet: &ammo::BulletData) -> Option<(Action, &Rule)> {
+        self.rules.iter()
+            .find(|&&(action, ref rule)| rule.matches(line, bullet) != (action == Action::Include))
+            .map(|&(action, ref rule)| (action, rule))
+    }
+}
+
+/// Parses rules file (see module docs for the format)
+pub fn parse_filter_file(text: &str) -> Result<Vec<(Action, Rule)>, LineError> {
+    let mut rules = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (action, expr) = match line.find(char::is_whitespace) {
+            Some(pos) => (&line[..pos], &line[pos..]),
+            None => (line, ""),
+        };
+        let action = match action {
+            "include" => Action::Include,
+            "exclude" => Action::Exclude,
+            _ => return Err(LineError::new(n, format!("expected 'include' or 'exclude' but got '{}'", action))),
+        };
+        let rule = parse_rule(expr).map_err(|e| LineError::new(n, e))?;
+        rules.push((action, rule));
+    }
+    Ok(rules)
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Word(String),
+    Quoted(String),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        match chars.peek().cloned() {
+            None => break,
+            Some('(') => { chars.next(); tokens.push(Token::Open); continue; },
+            Some(')') => { chars.next(); tokens.push(Token::Close); continue; },
+            Some(_) => {},
+        }
+        // word: parentheses inside it are allowed while balanced, quoted parts are taken as is
+        let mut word = String::new();
+        let mut quoted = false;
+        let mut depth = 0;
+        while let Some(c) = chars.peek().cloned() {
+            if c.is_whitespace() || (c == ')' && depth == 0) {
+                break;
+            }
+            chars.next();
+            match c {
+                '"' => {
+                    quoted = true;
+                    loop {
+                        match chars.next() {
+                            None => return Err("unterminated quote".to_string()),
+                            Some('"') => break,
+                            Some('\\') if chars.peek() == Some(&'"') || chars.peek() == Some(&'\\') => word.push(chars.next().unwrap()),
+                            Some(q) => word.push(q),
+                        }
+                    }
+                },
+                '(' => { depth += 1; word.push(c); },
+                ')' => { depth -= 1; word.push(c); },
+                _ => word.push(c),
+            }
+        }
+        tokens.push(if quoted { Token::Quoted(word) } else { Token::Word(word) });
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(&Token::Word(ref w)) => w.eq_ignore_ascii_case(keyword),
+            _ => false,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Rule, String> {
+        let mut rules = vec![self.parse_and()?];
+        while self.peek_keyword("or") {
+            self.pos += 1;
+            rules.push(self.parse_and()?);
+        }
+        Ok(if rules.len() == 1 { rules.pop().unwrap() } else { Rule::Or(rules) })
+    }
+
+    fn parse_and(&mut self) -> Result<Rule, String> {
+        let mut rules = vec![self.parse_unary()?];
+        while self.peek_keyword("and") {
+            self.pos += 1;
+            rules.push(self.parse_unary()?);
+        }
+        Ok(if rules.len() == 1 { rules.pop().unwrap() } else { Rule::And(rules) })
+    }
+
+    fn parse_unary(&mut self) -> Result<Rule, String> {
+        if self.peek_keyword("not") {
+            self.pos += 1;
+            return Ok(Rule::Not(Box::new(self.parse_unary()?)));
+        }
+        self.pos += 1;
+        match self.tokens.get(self.pos - 1) {
+            None => Err("unexpected end of expression".to_string()),
+            Some(&Token::Open) => {
+                let rule = self.parse_or()?;
+                match self.tokens.get(self.pos) {
+                    Some(&Token::Close) => { self.pos += 1; Ok(rule) },
+                    _ => Err("missing ')'".to_string()),
+                }
+            },
+            Some(&Token::Close) => Err("unexpected ')'".to_string()),
+            Some(&Token::Word(ref w)) | Some(&Token::Quoted(ref w)) => parse_atom(w),
+        }
+    }
+}
+
+fn parse_atom(atom: &str) -> Result<Rule, String> {
+    let (kind, value) = match atom.find(':') {
+        Some(pos) => (&atom[..pos], &atom[pos + 1..]),
+        None => return Err(format!("expected KIND:VALUE but got '{}'", atom)),
+    };
+    if value.is_empty() {
+        return Err(format!("empty value in '{}'", atom));
+    }
+    let bytes = value.as_bytes().to_vec();
+    match kind {
+        "substr" => Ok(Rule::Substring(bytes)),
+        "regex" => Regex::new(value).map(Rule::Regex).map_err(|e| format!("bad regex '{}': {}", value, e)),
+        "cgi" => match value.find('=') {
+            Some(pos) => Ok(Rule::CgiEquals(bytes[..pos].to_vec(), bytes[pos + 1..].to_vec())),
+            None => Ok(Rule::CgiPresent(bytes)),
+        },
+        "nocgi" => Ok(Rule::CgiAbsent(bytes)),
+        "host" => Ok(Rule::Host(bytes)),
+        "place" => Ok(Rule::Place(bytes)),
+        "wizard" => Ok(Rule::Wizard(bytes)),
+        _ => Err(format!("unknown rule kind '{}'", kind)),
+    }
+}
+
+/// Parses rule expression (see module docs for the syntax)
+pub fn parse_rule(expr: &str) -> Result<Rule, String> {
+    let mut parser = Parser { tokens: tokenize(expr)?, pos: 0 };
+    let rule = parser.parse_or()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(format!("unexpected {:?}", parser.tokens[parser.pos]));
+    }
+    Ok(rule)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(expr: &str, line: &[u8]) -> bool {
+        let mut filter = Filter::new();
+        filter.add(Action::Include, parse_rule(expr).unwrap());
+        filter.check(line, &logut::LineParser::default())
+    }
+
+    #[test]
+    fn atoms() {
+        let line = b"tskv\turl=http://Example.com/search?place=prime&pp=18&text=a+b\twizards=wiz1,wiz2";
+        assert!(check("substr:pp=18", line));
+        assert!(!check("substr:pp=19", line));
+        assert!(check(r"regex:pp=\d+", line));
+        assert!(check(r#"regex:"pp=\d+&text=a\+b\s*""#, line));
+        assert!(!check(r#"regex:"pp=d+""#, line));
+        assert!(check("cgi:text", line));
+        assert!(!check("cgi:nocache", line));
+        assert!(check("nocgi:nocache", line));
+        assert!(check("cgi:place=prime", line));
+        assert!(!check("cgi:place=dubai", line));
+        assert!(check("host:example.com", line));
+        assert!(check("place:prime", line));
+        assert!(check("wizard:wiz2", line));
+        assert!(!check("wizard:wiz", line));
+    }
+
+    #[test]
+    fn combinations() {
+        let line = b"http://example.com/search?place=prime&debug=1";
+        assert!(check("place:prime and cgi:debug", line));
+        assert!(!check("place:prime and not cgi:debug", line));
+        assert!(check("place:dubai or place:prime", line));
+        assert!(check("(place:dubai or place:prime) and not (cgi:debug=2)", line));
+        assert!(check("NOT place:dubai AND host:example.com", line));
+        assert!(check(r#"regex:"place=(prime|dubai)""#, line));
+        assert!(check("(regex:place=(prime|dubai))", line));
+    }
+
+    #[test]
+    fn bad_expressions() {
+        assert!(parse_rule("").is_err());
+        assert!(parse_rule("place").is_err());
+        assert!(parse_rule("place:").is_err());
+        assert!(parse_rule("zzz:xxx").is_err());
+        assert!(parse_rule("(place:prime").is_err());
+        assert!(parse_rule("place:prime)").is_err());
+        assert!(parse_rule("place:prime and").is_err());
+        assert!(parse_rule("regex:(").is_err());
+        assert!(parse_rule("substr:\"abc").is_err());
+    }
+
+    #[test]
+    fn defaults() {
+        let filter = Filter::with_defaults();
+        let parser = logut::LineParser::default();
+        assert!(filter.check(b"http://example.com/search?place=prime", &parser));
+        assert!(!filter.check(b"http://example.com/search?place=prime&subrequest=1", &parser));
+        assert!(!filter.check(b"tskv\turl=/search\trep-outgoing=1", &parser));
+    }
+
+    #[test]
+    fn filter_file() {
+        let text = "# comment\n\ninclude place:prime\nexclude cgi:debug=1 or host:localhost\n";
+        let rules = parse_filter_file(text).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].0, Action::Include);
+        assert_eq!(rules[1].0, Action::Exclude);
+        assert!(parse_filter_file("drop place:prime").is_err());
+        assert!(parse_filter_file("include").is_err());
+    }
+
+    #[test]
+    fn display() {
+        for expr in &["substr:pp=18", "cgi:text", "cgi:pp=18 and not nocgi:x", "(place:prime or place:dubai) and not (host:a.ru or wizard:w)",
+                      "regex:\"a b\\\"c\"", "substr:\"f(x)\""] {
+            let rule = parse_rule(expr).unwrap();
+            assert_eq!(rule.to_string(), *expr);
+            assert_eq!(parse_rule(&rule.to_string()).unwrap().to_string(), *expr);
+        }
+        let rule = parse_rule(r#"regex:"\d+ x""#).unwrap();
+        assert_eq!(rule.to_string(), r#"regex:"\\d+ x""#);
+        assert_eq!(parse_rule(&rule.to_string()).unwrap().to_string(), rule.to_string());
+
+        let filter = Filter::with_defaults();
+        let bullet = Default::default();
+        let (action, rule) = filter.rejecting_rule(b"/search?subrequest=1", &bullet).unwrap();
+        assert_eq!(format!("{} {}", action, rule), "exclude substr:subrequest=1");
+        assert!(filter.rejecting_rule(b"/search", &bullet).is_none());
+    }
+}
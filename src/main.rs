@@ -2,20 +2,34 @@ extern crate rand;
 extern crate logut;
 extern crate clap;
 extern crate twoway;
+extern crate regex;
+extern crate flate2;
 use std::path::{Path, PathBuf};
 use std::io;
-use clap::{Arg, App};
+use std::io::{Read, Write};
+use std::fs::File;
+use clap::{Arg, App, SubCommand};
 use logut::*;
 mod ammo;
 mod error;
 mod ammo_proc;
+mod filter;
+mod dedup;
+mod rewrite;
+mod validate;
+mod stats;
 use ammo_proc::AmmoProcessor;
 use logut::read::{ReadByLine};
 
 #[derive(PartialEq)]
 enum Algo {
     ReserviorSampling,
+    StreamSampling,
     MethodS,
+    Stratified,
+    Weighted,
+    Bootstrap,
+    Sources,
     DoNotRandomize,
 }
 
@@ -25,6 +39,35 @@ impl Default for Algo {
     }
 }
 
+/// Where weight of log line is taken from
+#[derive(Clone, PartialEq, Debug)]
+enum WeightField {
+    TskvKey(Vec<u8>),
+    Column(usize),
+}
+
+/// What to do instead of generating ammo
+#[derive(PartialEq, Debug)]
+enum Command {
+    Generate,
+    /// Check ammo files (stdin if there are none), format is detected if not given
+    Validate { files: Vec<PathBuf>, format: Option<ammo::OutputFormat>, max_problems: usize },
+    /// Report statistics of inputs, optionally compared with ammo given as `--in` specs
+    Stats { top: usize, json: bool, compare: Vec<String> },
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Command::Generate
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum DedupMode {
+    Exact,
+    Bloom { capacity: usize, error_rate: f64 },
+}
+
 type ReaderFabric = Fn() -> Box<ReadByLine>;
 
 pub enum LinesSource {
@@ -32,15 +75,67 @@ pub enum LinesSource {
     Fabric(Box<ReaderFabric>),
 }
 
+/// Format of input, its options and part of sample taken from it
+#[derive(Clone, Default)]
+struct InputOptions {
+    parser: LineParser,
+    /// Input is phantom ammo rather than log
+    phantom: bool,
+    /// Used by `sources` mixing method, inputs without quota get weight 1
+    quota: Option<ammo_proc::SourceQuota>,
+}
+
+struct Input {
+    source: LinesSource,
+    options: InputOptions,
+}
+
 #[derive(Default)]
 struct RunConf {
-    in_files: Vec<LinesSource>,
+    command: Command,
+    in_files: Vec<Input>,
+    /// Options of stdin and base of per-file options
+    input_options: InputOptions,
     out_files: Vec<PathBuf>,
     algo: Algo,
     target_set_size: Option<usize>,
+    no_default_filters: bool,
+    filters: Vec<(filter::Action, filter::Rule)>,
+    filter_files: Vec<PathBuf>,
+    gzip_level: flate2::Compression,
+    seed: Option<u64>,
+    out_format: ammo::OutputFormat,
+    template: ammo::RequestTemplate,
+    tag_dict: Option<PathBuf>,
+    strip_vowels: bool,
+    max_tags: Option<usize>,
+    strata_key: ammo_proc::StratumKey,
+    shard_key: Option<ammo_proc::StratumKey>,
+    strata_uniform: bool,
+    strata_quota_file: Option<PathBuf>,
+    weight_field: Option<WeightField>,
+    max_repeats: Option<usize>,
+    dedup: Option<DedupMode>,
+    volatile_params: Vec<Vec<u8>>,
+    repeat_ratio: Option<f64>,
+    repeat_window: usize,
+    repeat_recency: Option<ammo_proc::Recency>,
+    rewrite_files: Vec<PathBuf>,
+    rewrite_rules: Vec<rewrite::RewriteRule>,
+}
+
+/// Parses `place`, `host`, `wizard`, `resource` or `cgi:NAME`, anything else means place
+fn parse_bullet_key(v: &str) -> ammo_proc::StratumKey {
+    match v {
+        "host" => ammo_proc::StratumKey::Host,
+        "wizard" => ammo_proc::StratumKey::Wizard,
+        "resource" => ammo_proc::StratumKey::Resource,
+        v if v.starts_with("cgi:") => ammo_proc::StratumKey::CgiParam(v.as_bytes()[4..].to_vec()),
+        _ => ammo_proc::StratumKey::Place,
+    }
 }
 
-fn get_conf_from_cli(args: Option<Vec<&'static str>>) -> RunConf {
+fn get_conf_from_cli(args: Option<Vec<&'static str>>) -> Result<RunConf, error::ProcError> {
     let ver = option_env!("CARGO_PKG_VERSION");
 
     fn is_int(v: String) -> Result<(), String> {
@@ -51,34 +146,69 @@ fn get_conf_from_cli(args: Option<Vec<&'static str>>) -> RunConf {
     }
 
     fn is_greater_than_zero(v: String) -> Result<(), String> {
-        match is_int(v.clone()) {
-            Err(s) => Err(s),
-            Ok(_) => if v.parse::<usize>().unwrap() > 0 {
-                    Ok(())
-                } else {
-                    Err("value must be greater than zero".to_string())
-                }
+        match v.parse::<usize>() {
+            Err(_) => Err("not a number".to_string()),
+            Ok(0) => Err("value must be greater than zero".to_string()),
+            Ok(_) => Ok(()),
         }
     }
 
+    fn is_filter_rule(v: String) -> Result<(), String> {
+        filter::parse_rule(&v).map(|_| ())
+    }
+
+    fn is_input_spec(v: String) -> Result<(), String> {
+        parse_input_spec(&v, &InputOptions::default()).map(|_| ())
+    }
+
     let app = App::new("Ammo Generator")
         .version(ver.unwrap_or("unknown"))
         .author("Andrey Mescheryakov")
+        .after_help("EXIT CODES:\n     1  validate found problems in ammo\n    65  input or rules file can't be parsed\n    66  not enough input lines for the sample\n    73  ammo can't be written\n    74  input can't be read\n    78  options don't make sense together")
         .arg(
             Arg::with_name("method")
                 .short("m")
                 .long("method")
                 .takes_value(true)
-                .possible_values(&["stream", "inmem"])
+                .possible_values(&["stream", "inmem", "twopass", "stratified", "weighted", "bootstrap", "sources"])
                 .requires("count")
-                .help("Mixing method"))
+                .help("Mixing method: 'stream' reads input once and keeps sample on disk, 'inmem' keeps sample in memory, 'twopass' reads input twice (doesn't work with stdin), 'stratified' keeps sample per place (see --strata-by), 'weighted' selects lines with probability proportional to their weight (see --weight-key), 'bootstrap' draws lines with replacement, so COUNT may exceed input size, 'sources' keeps inputs apart and takes from each one its part set with 'weight' or 'quota' after '@' (see --in)"))
         .arg(
             Arg::with_name("in")
                 .short("i")
                 .long("in")
                 .takes_value(true)
                 .multiple(true)
-                .help("Use these files as input (you may specify more than one)"))
+                .validator(is_input_spec)
+                .help("Use these files as input (you may specify more than one). Format options of a file follow '@', e.g. 'access.log@format=combined' or 'data.tsv@url-column=0,wizards-column=3'. With '--method sources' 'weight=W' gives the file share of sample proportional to W and 'quota=N' takes exactly N bullets from it, e.g. 'a.log@weight=40 b.log@weight=60'"))
+        .arg(
+            Arg::with_name("input_format")
+                .long("input-format")
+                .takes_value(true)
+                .validator(|v| set_input_option(&mut InputOptions::default(), "format", &v))
+                .help("Format of input lines: auto (default), tskv, tsv, plain, json, combined, common or 'log_format:FORMAT' with nginx log_format string. 'phantom' reads ammo made before, e.g. to resample it"))
+        .arg(
+            Arg::with_name("url_field")
+                .long("url-field")
+                .takes_value(true)
+                .help("Take url from this tskv or JSON key, JSON pointers like /request/uri are also accepted"))
+        .arg(
+            Arg::with_name("wizards_field")
+                .long("wizards-field")
+                .takes_value(true)
+                .help("Take wizards from this tskv or JSON key, JSON pointers like /request/wizards are also accepted"))
+        .arg(
+            Arg::with_name("url_column")
+                .long("url-column")
+                .takes_value(true)
+                .validator(is_int)
+                .help("Take url from this column of tab-separated lines, numbered from 0 (default 1)"))
+        .arg(
+            Arg::with_name("wizards_column")
+                .long("wizards-column")
+                .takes_value(true)
+                .validator(is_int)
+                .help("Take wizards from this column of tab-separated lines, numbered from 0 (default 12)"))
         .arg(
             Arg::with_name("out")
                 .short("o")
@@ -101,6 +231,59 @@ fn get_conf_from_cli(args: Option<Vec<&'static str>>) -> RunConf {
                 .long("gzip")
                 .requires("ammo_prefix")
                 .help("Gzip output files (and use .gz extension for them)"))
+        .arg(
+            Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["phantom", "uri", "json"])
+                .help("Ammo format: 'phantom' (raw requests, default), 'uri' (uri lines with headers on top) or 'json' (JSON lines for Pandora)"))
+        .arg(
+            Arg::with_name("http_version")
+                .long("http-version")
+                .takes_value(true)
+                .possible_values(&["1.0", "1.1"])
+                .help("HTTP version of requests (1.0 by default), 1.1 requests must have host"))
+        .arg(
+            Arg::with_name("host_header")
+                .long("host-header")
+                .takes_value(true)
+                .conflicts_with("no_host_header")
+                .help("Send this Host header instead of the one from request url"))
+        .arg(
+            Arg::with_name("no_host_header")
+                .long("no-host-header")
+                .help("Do not send Host header"))
+        .arg(
+            Arg::with_name("keep_alive")
+                .long("keep-alive")
+                .help("Send 'Connection: keep-alive' instead of 'Connection: close'"))
+        .arg(
+            Arg::with_name("user_agent")
+                .long("user-agent")
+                .takes_value(true)
+                .help("User-Agent for requests which have no one in log ('tank' by default)"))
+        .arg(
+            Arg::with_name("tag_dict")
+                .long("tag-dict")
+                .takes_value(true)
+                .help("File with 'name alias' lines to shorten place and wizard names in tags"))
+        .arg(
+            Arg::with_name("strip_vowels")
+                .long("strip-vowels")
+                .help("Remove vowels from place and wizard names which have no alias"))
+        .arg(
+            Arg::with_name("max_tags")
+                .long("max-tags")
+                .takes_value(true)
+                .validator(is_int)
+                .help("Write at most this count of tags per bullet"))
+        .arg(
+            Arg::with_name("gzip_level")
+                .long("gzip-level")
+                .takes_value(true)
+                .possible_values(&["none", "fast", "default", "best"])
+                .help("Compression level for .gz output files"))
         .arg(
             Arg::with_name("nfiles")
                 .short("n")
@@ -110,13 +293,214 @@ fn get_conf_from_cli(args: Option<Vec<&'static str>>) -> RunConf {
                 .requires("ammo_prefix")
                 .conflicts_with("out")
                 .help("Count of output files"))
+        .arg(
+            Arg::with_name("shard_by")
+                .long("shard-by")
+                .takes_value(true)
+                .validator(|v| if v == "host" || v == "resource" || (v.starts_with("cgi:") && v.len() > 4) {
+                    Ok(())
+                } else {
+                    Err("expected host, resource or cgi:NAME".to_string())
+                })
+                .help("Put bullets with the same host, resource or CGI param (cgi:NAME, e.g. cgi:yandexuid) into the same output file instead of dealing them out in turn. Assignment is stable between runs, shard sizes are reported to stderr"))
         .arg(
             Arg::with_name("count")
                 .short("c")
                 .long("count")
                 .takes_value(true)
+                .validator(is_greater_than_zero)
+                .help("Write COUNT bullets to each output file"))
+        .arg(
+            Arg::with_name("strata_by")
+                .long("strata-by")
+                .takes_value(true)
+                .validator(|v| if v == "place" || v == "host" || v == "wizard" || (v.starts_with("cgi:") && v.len() > 4) {
+                    Ok(())
+                } else {
+                    Err("expected place, host, wizard or cgi:NAME".to_string())
+                })
+                .help("Stratified method: group bullets by place (default), host, first wizard or CGI param (cgi:NAME)"))
+        .arg(
+            Arg::with_name("strata_quota")
+                .long("strata-quota")
+                .takes_value(true)
+                .possible_values(&["proportional", "uniform"])
+                .conflicts_with("strata_quota_file")
+                .help("Stratified method: sample size of each group is proportional to group size (default) or the same for all groups"))
+        .arg(
+            Arg::with_name("strata_quota_file")
+                .long("strata-quota-file")
+                .takes_value(true)
+                .help("Stratified method: read 'key share' lines from this file, groups not listed there are dropped"))
+        .arg(
+            Arg::with_name("weight_key")
+                .long("weight-key")
+                .takes_value(true)
+                .conflicts_with("weight_column")
+                .help("Weighted method: take line weight from this tskv key (lines without valid weight weigh 1)"))
+        .arg(
+            Arg::with_name("weight_column")
+                .long("weight-column")
+                .takes_value(true)
                 .validator(is_int)
-                .help("Write COUNT bullets to each output file"));
+                .help("Weighted method: take line weight from this column of tab-separated log (counting from 0)"))
+        .arg(
+            Arg::with_name("max_repeats")
+                .long("max-repeats")
+                .takes_value(true)
+                .validator(|v| match v.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err("expected positive number".to_string()),
+                })
+                .help("Bootstrap method: use each input line at most this many times"))
+        .arg(
+            Arg::with_name("dedup")
+                .long("dedup")
+                .takes_value(true)
+                .possible_values(&["exact", "bloom"])
+                .help("Keep only unique requests: 'exact' remembers all of them, 'bloom' uses bounded memory but may drop some unique requests. Not supported by 'twopass' method"))
+        .arg(
+            Arg::with_name("dedup_capacity")
+                .long("dedup-capacity")
+                .takes_value(true)
+                .validator(is_int)
+                .help("Bloom dedup: expected number of unique requests (default 10000000)"))
+        .arg(
+            Arg::with_name("dedup_error_rate")
+                .long("dedup-error-rate")
+                .takes_value(true)
+                .validator(|v| match v.parse::<f64>() {
+                    Ok(p) if p > 0.0 && p < 1.0 => Ok(()),
+                    _ => Err("expected number between 0 and 1".to_string()),
+                })
+                .help("Bloom dedup: share of unique requests which may be taken for duplicates (default 0.001)"))
+        .arg(
+            Arg::with_name("volatile_params")
+                .long("volatile-params")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .help("Dedup: ignore these CGI params when comparing requests, e.g. reqid,timestamp"))
+        .arg(
+            Arg::with_name("repeat_ratio")
+                .long("repeat-ratio")
+                .takes_value(true)
+                .validator(|v| match v.parse::<f64>() {
+                    Ok(p) if p >= 0.0 && p < 1.0 => Ok(()),
+                    _ => Err("expected number from 0 to 1".to_string()),
+                })
+                .help("Mix in repeats of recently written bullets, so they make this share of ammo, e.g. 0.3 for 30% cache hits. Repeats are a part of COUNT, so mixing method selects COUNT*(1-RATIO) bullets. Without mixing method all input is kept and ammo gets about RATIO/(1-RATIO) more bullets than input"))
+        .arg(
+            Arg::with_name("repeat_window")
+                .long("repeat-window")
+                .takes_value(true)
+                .validator(is_int)
+                .requires("repeat_ratio")
+                .help("Repeat only bullets among this many most recent ones (default 1000)"))
+        .arg(
+            Arg::with_name("repeat_recency")
+                .long("repeat-recency")
+                .takes_value(true)
+                .requires("repeat_ratio")
+                .validator(|v| if v == "uniform" || v.starts_with("exp:") && v[4..].parse::<f64>().map(|m| m > 0.0).unwrap_or(false) {
+                    Ok(())
+                } else {
+                    Err("expected uniform or exp:MEAN".to_string())
+                })
+                .help("Which recent bullets are repeated: 'uniform' (default) or 'exp:MEAN' where MEAN is average age in bullets"))
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .validator(is_int)
+                .help("Seed random generator to get the same ammo from the same input"))
+        .arg(
+            Arg::with_name("include")
+                .long("include")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(is_filter_rule)
+                .help("Keep only lines matching this rule, e.g. 'place:prime or cgi:text'"))
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(is_filter_rule)
+                .help("Drop lines matching this rule, e.g. 'substr:debug=1 and not host:example.com'"))
+        .arg(
+            Arg::with_name("filters")
+                .long("filters")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Read include/exclude rules from this file"))
+        .arg(
+            Arg::with_name("rewrite")
+                .long("rewrite")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Read request rewrite rules from this file"))
+        .arg(
+            Arg::with_name("rewrite_rule")
+                .long("rewrite-rule")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| rewrite::parse_rewrite_rule(&v).map(|_| ()))
+                .help("Rewrite requests, e.g. 'set base test', 'remove reqid', 'rename FROM TO', 'replace NAME REGEX REPLACEMENT' or 'host HOST'. Quote arguments containing spaces: 'set text \"a b\"'"))
+        .arg(
+            Arg::with_name("no_default_filters")
+                .long("no-default-filters")
+                .help("Do not drop rep-outgoing=1 and subrequest=1 lines"))
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Checks ammo files and reports offsets of bad bullets, exits with code 1 if there are any and 74 if a file can't be read")
+                .arg(
+                    Arg::with_name("files")
+                        .multiple(true)
+                        .help("Ammo files, stdin if none"))
+                .arg(
+                    Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["phantom", "uri"])
+                        .help("Ammo format, detected by the start of file by default"))
+                .arg(
+                    Arg::with_name("max_problems")
+                        .long("max-problems")
+                        .takes_value(true)
+                        .validator(is_int)
+                        .help("Stop reporting after this many problems per file, the rest are only counted (default 100)")))
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Reports what input contains: filtered lines, top places, wizards, hosts and CGI params, url lengths. Filter and input format options go before the subcommand")
+                .arg(
+                    Arg::with_name("files")
+                        .multiple(true)
+                        .validator(is_input_spec)
+                        .help("Input files as for --in, files of --in or stdin if none"))
+                .arg(
+                    Arg::with_name("top")
+                        .long("top")
+                        .takes_value(true)
+                        .validator(is_greater_than_zero)
+                        .help("Length of top lists (default 10)"))
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Write JSON instead of text"))
+                .arg(
+                    Arg::with_name("compare")
+                        .long("compare")
+                        .takes_value(true)
+                        .multiple(true)
+                        .validator(is_input_spec)
+                        .help("Compare input with this ammo, it is read as phantom unless other format is given after '@'")));
 
     let matches = match args {
         None => app.get_matches(),
@@ -124,12 +508,26 @@ fn get_conf_from_cli(args: Option<Vec<&'static str>>) -> RunConf {
     };
 
     let method = match matches.value_of("method") {
-        Some("stream") => Algo::MethodS,
+        Some("stream") => Algo::StreamSampling,
+        Some("twopass") => Algo::MethodS,
+        Some("stratified") => Algo::Stratified,
+        Some("weighted") => Algo::Weighted,
+        Some("bootstrap") => Algo::Bootstrap,
+        Some("sources") => Algo::Sources,
         Some("inmem") => Algo::ReserviorSampling,
         None => Algo::DoNotRandomize,
-        _ => panic!("unknown mixing algorithm"),
+        Some(other) => return Err(error::ProcError::Config(format!("unknown mixing method '{}'", other))),
     };
 
+    /// Parses option value, most of them are already checked by validators
+    fn parse_value<T: std::str::FromStr>(m: &clap::ArgMatches, opt: &str) -> Result<Option<T>, error::ProcError> {
+        match m.value_of(opt) {
+            None => Ok(None),
+            Some(s) => s.parse::<T>().map(Some)
+                .map_err(|_| error::ProcError::Config(format!("bad value '{}' of --{}", s, opt.replace('_', "-")))),
+        }
+    }
+
     fn get_files(m: &clap::ArgMatches, opt: &str) -> Vec<PathBuf> {
         match m.values_of(opt) {
            None => Vec::new(),
@@ -137,62 +535,281 @@ fn get_conf_from_cli(args: Option<Vec<&'static str>>) -> RunConf {
        }
     }
 
+    let out_format = match matches.value_of("format") {
+        Some("uri") => ammo::OutputFormat::Uri,
+        Some("json") => ammo::OutputFormat::Json,
+        _ => ammo::OutputFormat::Phantom,
+    };
+
+    let mut template = ammo::RequestTemplate::default();
+    if matches.value_of("http_version") == Some("1.1") {
+        template.http_version = ammo::HttpVersion::Http11;
+    }
+    if let Some(host) = matches.value_of("host_header") {
+        template.host = ammo::HostHeader::Override(host.as_bytes().to_vec());
+    }
+    if matches.is_present("no_host_header") {
+        if template.http_version == ammo::HttpVersion::Http11 {
+            return Err(error::ProcError::Config("HTTP/1.1 requests must have Host header, use --http-version 1.0 with --no-host-header".to_string()));
+        }
+        template.host = ammo::HostHeader::Omit;
+    }
+    template.keep_alive = matches.is_present("keep_alive");
+    if let Some(user_agent) = matches.value_of("user_agent") {
+        template.user_agent = user_agent.as_bytes().to_vec();
+    }
+
     let gzip_output = matches.is_present("gzip");
+    let gzip_level = match matches.value_of("gzip_level") {
+        Some("none") => flate2::Compression::None,
+        Some("fast") => flate2::Compression::Fast,
+        Some("best") => flate2::Compression::Best,
+        _ => flate2::Compression::Default,
+    };
 
-    let in_files = get_files(&matches, "in");
-    let out_files = match matches.value_of("nfiles") {
+    let mut input_options = InputOptions::default();
+    for &(opt, name) in &[("input_format", "format"), ("url_field", "url-field"), ("wizards_field", "wizards-field"), ("url_column", "url-column"), ("wizards_column", "wizards-column")] {
+        if let Some(value) = matches.value_of(opt) {
+            set_input_option(&mut input_options, name, value).map_err(error::ProcError::Config)?;
+        }
+    }
+    let in_specs = match matches.subcommand_matches("stats") {
+        Some(m) if m.is_present("files") && matches.is_present("in") => {
+            return Err(error::ProcError::Config("stats input files are given either with --in or after the subcommand, not both".to_string()));
+        },
+        Some(m) if m.is_present("files") => m.values_of("files"),
+        _ => matches.values_of("in"),
+    };
+    let in_files = match in_specs {
+        None => Vec::new(),
+        Some(it) => it.map(|x| parse_input_spec(x, &input_options)).collect::<Result<Vec<_>, _>>().map_err(error::ProcError::Config)?,
+    };
+    let out_files = match parse_value::<usize>(&matches, "nfiles")? {
         None => get_files(&matches, "out"),
-        Some(s) => {
-            let files_count = s.parse::<usize>().unwrap();
+        Some(files_count) => {
             let prefix = matches.value_of("ammo_prefix").unwrap_or("");
             let ext = if gzip_output {"gz"} else {"txt"};
             (0..files_count).map(|x| Path::new(&format!("{}-{:02}.{}", prefix, x, ext)).to_path_buf()).collect::<Vec<PathBuf>>()
         }
     };
 
-    let target_set_size = matches.value_of("count").map(|s| {
-        let count = s.parse::<usize>().unwrap();
-        let nfiles = out_files.len();
+    let target_set_size = parse_value::<usize>(&matches, "count")?.map(|count| {
+        // without output files bullets go to stdout
+        let nfiles = out_files.len().max(1);
         count * nfiles
     });
 
-    RunConf {
-        in_files: in_files.iter().map(|x| LinesSource::FileName(x.clone())).collect(),
+    fn get_rules(m: &clap::ArgMatches, opt: &str, action: filter::Action) -> Result<Vec<(filter::Action, filter::Rule)>, error::ProcError> {
+        match m.values_of(opt) {
+            None => Ok(Vec::new()),
+            Some(it) => it.map(|x| filter::parse_rule(x).map(|rule| (action, rule)).map_err(error::ProcError::Config)).collect(),
+        }
+    }
+
+    let mut filters = get_rules(&matches, "include", filter::Action::Include)?;
+    filters.extend(get_rules(&matches, "exclude", filter::Action::Exclude)?);
+
+    let command = match matches.subcommand_matches("validate") {
+        Some(m) => Command::Validate {
+            files: get_files(m, "files"),
+            format: match m.value_of("format") {
+                Some("uri") => Some(ammo::OutputFormat::Uri),
+                Some(_) => Some(ammo::OutputFormat::Phantom),
+                None => None,
+            },
+            max_problems: parse_value(m, "max_problems")?.unwrap_or(100),
+        },
+        None => match matches.subcommand_matches("stats") {
+            Some(m) => Command::Stats {
+                top: parse_value(m, "top")?.unwrap_or(10),
+                json: m.is_present("json"),
+                compare: m.values_of("compare").map_or(Vec::new(), |it| it.map(|x| x.to_string()).collect()),
+            },
+            None => Command::Generate,
+        },
+    };
+
+    Ok(RunConf {
+        command: command,
+        in_files: in_files.into_iter().map(|(path, options)| Input { source: LinesSource::FileName(path), options: options }).collect(),
+        input_options: input_options,
         out_files: out_files,
         algo: method,
         target_set_size: target_set_size,
+        no_default_filters: matches.is_present("no_default_filters"),
+        filters: filters,
+        filter_files: get_files(&matches, "filters"),
+        gzip_level: gzip_level,
+        seed: parse_value(&matches, "seed")?,
+        out_format: out_format,
+        template: template,
+        tag_dict: matches.value_of("tag_dict").map(|x| Path::new(x).to_path_buf()),
+        strip_vowels: matches.is_present("strip_vowels"),
+        max_tags: parse_value(&matches, "max_tags")?,
+        strata_key: matches.value_of("strata_by").map(parse_bullet_key).unwrap_or_default(),
+        shard_key: matches.value_of("shard_by").map(parse_bullet_key),
+        strata_uniform: matches.value_of("strata_quota") == Some("uniform"),
+        strata_quota_file: matches.value_of("strata_quota_file").map(|x| Path::new(x).to_path_buf()),
+        weight_field: match (matches.value_of("weight_key"), matches.value_of("weight_column")) {
+            (Some(key), _) => Some(WeightField::TskvKey(key.as_bytes().to_vec())),
+            (None, Some(_)) => parse_value(&matches, "weight_column")?.map(WeightField::Column),
+            (None, None) => None,
+        },
+        max_repeats: parse_value(&matches, "max_repeats")?,
+        dedup: match matches.value_of("dedup") {
+            Some("exact") => Some(DedupMode::Exact),
+            Some(_) => Some(DedupMode::Bloom {
+                capacity: parse_value(&matches, "dedup_capacity")?.unwrap_or(10000000),
+                error_rate: parse_value(&matches, "dedup_error_rate")?.unwrap_or(0.001),
+            }),
+            None => None,
+        },
+        volatile_params: matches.values_of("volatile_params")
+            .map_or(Vec::new(), |it| it.map(|x| x.as_bytes().to_vec()).collect()),
+        repeat_ratio: parse_value(&matches, "repeat_ratio")?,
+        repeat_window: parse_value(&matches, "repeat_window")?.unwrap_or(1000),
+        repeat_recency: match matches.value_of("repeat_recency") {
+            Some(s) if s.starts_with("exp:") => Some(ammo_proc::Recency::Exponential(s[4..].parse::<f64>()
+                .map_err(|_| error::ProcError::Config(format!("bad mean of --repeat-recency '{}'", &s[4..])))?)),
+            Some(_) => Some(ammo_proc::Recency::Uniform),
+            None => None,
+        },
+        rewrite_files: matches.values_of("rewrite").map_or(Vec::new(), |it| it.map(|x| Path::new(x).to_path_buf()).collect()),
+        rewrite_rules: matches.values_of("rewrite_rule")
+            .map_or(Ok(Vec::new()), |it| it.map(rewrite::parse_rewrite_rule).collect::<Result<Vec<_>, _>>())
+            .map_err(error::ProcError::Config)?,
+    })
+}
+
+/// Sets input format option given by name, e.g. `format` or `url-field`
+fn set_input_option(options: &mut InputOptions, name: &str, value: &str) -> Result<(), String> {
+    if name == "format" {
+        options.phantom = value == "phantom";
+        if !options.phantom {
+            options.parser.format = InputFormat::parse(value)?;
+        }
+        return Ok(());
+    }
+    if name.ends_with("-field") {
+        return options.parser.set_field(&name[..name.len() - "-field".len()], value);
+    }
+    if name.ends_with("-column") {
+        let index = value.parse::<usize>().map_err(|_| format!("{}: not a number", name))?;
+        return options.parser.set_column(&name[..name.len() - "-column".len()], index);
+    }
+    match name {
+        "weight" => match value.parse::<f64>() {
+            Ok(weight) if weight >= 0.0 && weight.is_finite() => options.quota = Some(ammo_proc::SourceQuota::Weight(weight)),
+            _ => return Err(format!("weight: '{}' is not a non-negative number", value)),
+        },
+        "quota" => {
+            let count = value.parse::<usize>().map_err(|_| format!("quota: '{}' is not a number", value))?;
+            options.quota = Some(ammo_proc::SourceQuota::Count(count));
+        },
+        _ => return Err(format!("unknown input option '{}'", name)),
+    }
+    Ok(())
+}
+
+fn is_input_option_name(name: &str) -> bool {
+    name == "format" || name == "weight" || name == "quota" || name.ends_with("-field") || name.ends_with("-column")
+}
+
+/// Splits `--in` value into path and parser of the file
+///
+/// Options follow `@` as comma separated NAME=VALUE pairs, they override the ones of
+/// `base`. `format=log_format:...` takes the rest of the value, so it must be the last one.
+fn parse_input_spec(spec: &str, base: &InputOptions) -> Result<(PathBuf, InputOptions), String> {
+    let mut options = base.clone();
+    let options_start = spec.match_indices('@').map(|(pos, _)| pos + 1).find(|&pos| {
+        spec[pos..].find('=').map_or(false, |eq| is_input_option_name(&spec[pos..pos + eq]))
+    });
+    let (path, mut rest) = match options_start {
+        Some(pos) => (&spec[..pos - 1], &spec[pos..]),
+        None => return Ok((PathBuf::from(spec), options)),
+    };
+    while !rest.is_empty() {
+        let eq = rest.find('=').ok_or_else(|| format!("expected NAME=VALUE, got '{}'", rest))?;
+        let (name, tail) = (&rest[..eq], &rest[eq + 1..]);
+        let (value, next) = match tail.find(',') {
+            Some(comma) if !(name == "format" && tail.starts_with("log_format:")) => (&tail[..comma], &tail[comma + 1..]),
+            _ => (tail, ""),
+        };
+        set_input_option(&mut options, name, value)?;
+        rest = next;
+    }
+    Ok((PathBuf::from(path), options))
+}
+
+fn make_tag_rules(conf: &RunConf) -> Result<ammo::TagRules, error::ProcError> {
+    let mut rules = ammo::TagRules {
+        strip_vowels: conf.strip_vowels,
+        max_tags: conf.max_tags,
+        ..Default::default()
+    };
+    if let Some(ref path) = conf.tag_dict {
+        rules.aliases = read_rules_file(path, ammo::TagRules::parse_aliases)?;
+    }
+    Ok(rules)
+}
+
+/// Reads file parsed line by line, such as tag dictionary or filters file
+fn read_rules_file<T, F: Fn(&str) -> Result<T, error::LineError>>(path: &Path, parse: F) -> Result<T, error::ProcError> {
+    let mut text = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| with_path(path, e))?;
+    parse(&text).map_err(|e| e.in_file(&path.display().to_string()))
+}
+
+/// Adds path to the message of error
+fn with_path(path: &Path, err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+}
+
+/// Makes error of input which can't be read, malformed data is told apart from IO failures
+fn input_error(name: &str, line: Option<u64>, err: io::Error) -> error::ProcError {
+    if err.kind() == io::ErrorKind::InvalidData {
+        error::ProcError::InputParse { path: name.to_string(), line: line, message: err.to_string() }
+    } else {
+        error::ProcError::Io(io::Error::new(err.kind(), format!("{}: {}", name, err)))
     }
 }
 
 fn make_writer(conf: &RunConf) -> Result<Box<AmmoProcessor>, error::ProcError> {
     use std::ffi::OsStr;
+    let tags = make_tag_rules(conf)?;
     let mut writers: Vec<Box<ammo_proc::AmmoProcessor>> = Vec::new();
     if conf.out_files.len() <= 0 {
-        writers.push(Box::new(ammo_proc::WriteAmmo::to_stdout()?));
+        let writer = ammo_proc::WriteAmmo::to_stdout()?;
+        writers.push(Box::new(writer.with_format(conf.out_format).with_template(conf.template.clone()).with_tags(tags)));
     } else {
         for path in &conf.out_files {
             let extension = path.extension().unwrap_or(OsStr::new(""));
             let writer = if extension == "gz" {
-                Box::new(ammo_proc::WriteAmmo::to_gzip(path)?)
+                ammo_proc::WriteAmmo::to_gzip(path, conf.gzip_level)?
             } else {
-                Box::new(ammo_proc::WriteAmmo::to_file(path)?)
+                ammo_proc::WriteAmmo::to_file(path)?
             };
-            writers.push(writer);
+            writers.push(Box::new(writer.with_format(conf.out_format).with_template(conf.template.clone()).with_tags(tags.clone())));
         }
     }
-    Ok(Box::new(ammo_proc::RoundRobin::new(writers)))
+    match conf.shard_key {
+        Some(ref key) => Ok(Box::new(ammo_proc::HashSharding::new(key.clone(), writers))),
+        None => Ok(Box::new(ammo_proc::RoundRobin::new(writers))),
+    }
 }
 
 struct FilteringReader {
-    check: Box<Fn(&[u8]) -> bool>,
+    filter: filter::Filter,
+    parser: LineParser,
     source: Box<ReadByLine>,
 }
 
 impl ReadByLine for FilteringReader {
     fn process_lines(&mut self, feed_to: &mut FnMut(&[u8])) -> io::Result<()> {
-        let closure = &self.check;
+        let filter = &self.filter;
+        let parser = &self.parser;
         let mut process_line = |line: &[u8]| {
-            if (*closure)(line) {
+            if filter.check(line, parser) {
                 feed_to(line);
             }
         };
@@ -200,73 +817,410 @@ impl ReadByLine for FilteringReader {
     }
 }
 
-
-fn make_reader(conf: &RunConf) -> Result<Box<ReadByLine>, std::io::Error> {
-    let check_fn = |line: &[u8]| -> bool {
-        twoway::find_bytes(line, b"rep-outgoing=1") == None &&
-            twoway::find_bytes(line, b"subrequest=1") == None
-    };
-    let source: Box<ReadByLine> = if conf.in_files.len() <= 0 {
-        Box::new(logut::read::FromStdin)
+fn make_filter(conf: &RunConf) -> Result<filter::Filter, error::ProcError> {
+    let mut result = if conf.no_default_filters {
+        filter::Filter::new()
     } else {
-        let mut readers: Vec<Box<ReadByLine>> = Vec::new();
-        for source in &conf.in_files {
-            let reader: Box<ReadByLine> = match source {
-                &LinesSource::FileName(ref path) => {
-                    if !path.is_file() {
-                        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Path {:?} not exists or it is not a file", path)));
-                    }
-                    Box::new(read::FileLinesReader{filename: path.clone()})
-                },
-                &LinesSource::Fabric(ref fabric) => { (*fabric)() }
-            };
-            readers.push(reader);
-        }
-        Box::new(logut::read::Chained{sources: readers})
+        filter::Filter::with_defaults()
     };
+    for path in &conf.filter_files {
+        for (action, rule) in read_rules_file(path, filter::parse_filter_file)? {
+            result.add(action, rule);
+        }
+    }
+    for &(action, ref rule) in &conf.filters {
+        result.add(action, rule.clone());
+    }
+    Ok(result)
+}
+
+/// Reader of input along with the way its content is turned into bullets
+enum InputReader {
+    /// Filtered log lines and parser of their format
+    Log(Box<ReadByLine>, LineParser),
+    Phantom(ammo::PhantomReader<Box<io::BufRead>>, filter::Filter),
+}
+
+impl InputReader {
+    fn new(options: &InputOptions, filter: &filter::Filter, source: Box<ReadByLine>) -> InputReader {
+        let reader = FilteringReader{filter: filter.clone(), parser: options.parser.clone(), source: source};
+        InputReader::Log(Box::new(reader), options.parser.clone())
+    }
+
+    fn phantom<R: io::BufRead + 'static>(filter: &filter::Filter, raw: R) -> io::Result<InputReader> {
+        Ok(InputReader::Phantom(ammo::PhantomReader::new(read::decompressed(raw)?), filter.clone()))
+    }
+}
+
+fn make_inputs(conf: &RunConf) -> Result<Vec<(String, InputReader)>, error::ProcError> {
+    open_inputs(&conf.in_files, &conf.input_options, &make_filter(conf)?)
+}
 
-    Ok(Box::new(FilteringReader{check: Box::new(check_fn), source: source}))
+/// Opens inputs, stdin if there are none. Readers come with names used in error messages.
+fn open_inputs(in_files: &[Input], stdin_options: &InputOptions, filter: &filter::Filter) -> Result<Vec<(String, InputReader)>, error::ProcError> {
+    if in_files.is_empty() {
+        let reader = if stdin_options.phantom {
+            InputReader::phantom(filter, io::BufReader::new(io::stdin())).map_err(|e| input_error("stdin", None, e))?
+        } else {
+            InputReader::new(stdin_options, filter, Box::new(logut::read::FromStdin))
+        };
+        return Ok(vec![("stdin".to_string(), reader)]);
+    }
+    let mut inputs = Vec::new();
+    for input in in_files {
+        if let LinesSource::FileName(ref path) = input.source {
+            if !path.is_file() {
+                return Err(error::ProcError::Io(io::Error::new(io::ErrorKind::NotFound, format!("Path {:?} not exists or it is not a file", path))));
+            }
+        }
+        let reader = match input.source {
+            LinesSource::FileName(ref path) if input.options.phantom => {
+                File::open(path).and_then(|f| InputReader::phantom(filter, io::BufReader::new(f)))
+                    .map_err(|e| input_error(&input_name(input), None, e))?
+            },
+            LinesSource::FileName(ref path) => InputReader::new(&input.options, filter, Box::new(read::FileLinesReader{filename: path.clone()})),
+            LinesSource::Fabric(_) if input.options.phantom => {
+                return Err(error::ProcError::Config("phantom ammo is read from files and stdin only".to_string()));
+            },
+            LinesSource::Fabric(ref fabric) => InputReader::new(&input.options, filter, (*fabric)()),
+        };
+        inputs.push((input_name(input), reader));
+    }
+    Ok(inputs)
 }
 
-fn get_lines_count(conf: &RunConf) -> std::io::Result<usize> {
+/// Name of input in messages
+fn input_name(input: &Input) -> String {
+    match input.source {
+        LinesSource::FileName(ref path) => path.display().to_string(),
+        LinesSource::Fabric(_) => "input".to_string(),
+    }
+}
+
+/// Counts lines and bullets which pass the filter, malformed lines are not counted
+/// as they make no bullets
+fn get_lines_count(conf: &RunConf) -> Result<usize, error::ProcError> {
     let mut count: usize = 0;
-    make_reader(conf)?.process_lines(&mut |_| count += 1)?;
+    for (name, input) in make_inputs(conf)? {
+        match input {
+            InputReader::Log(mut reader, parser) => reader.process_lines(&mut |line| if parser.parse(line).is_ok() {
+                count += 1;
+            }).map_err(|e| input_error(&name, None, e))?,
+            InputReader::Phantom(mut reader, filter) => {
+                while let Some((request, bullet)) = reader.read_bullet().map_err(|e| input_error(&name, Some(reader.line()), e))? {
+                    if filter.check_bullet(&request, &bullet.get_data()) {
+                        count += 1;
+                    }
+                }
+            },
+        }
+    }
     Ok(count)
 }
 
-fn make_processor(conf: &RunConf, writer: Box<AmmoProcessor>) -> io::Result<Box<AmmoProcessor>> {
+fn make_processor(conf: &RunConf, writer: Box<AmmoProcessor>) -> Result<Box<AmmoProcessor>, error::ProcError> {
+    // repeats are a part of --count, sampler selects the rest
+    let repeat_plan = match (conf.repeat_ratio, conf.target_set_size) {
+        (Some(ratio), Some(size)) if conf.algo != Algo::DoNotRandomize => {
+            let repeats = ((size as f64 * ratio).round() as usize).min(size.saturating_sub(1));
+            Some((size - repeats, repeats))
+        },
+        _ => None,
+    };
+    let writer: Box<AmmoProcessor> = match conf.repeat_ratio {
+        Some(ratio) => {
+            // sampler is seeded with the same seed, mixer should not make the same choices
+            let rng = ammo_proc::make_rng(conf.seed.map(|s| s.wrapping_add(1)));
+            let recency = conf.repeat_recency.unwrap_or(ammo_proc::Recency::Uniform);
+            let mixer = ammo_proc::RepeatMixer::new(ratio, conf.repeat_window, recency, rng, writer);
+            match repeat_plan {
+                Some((sampled, repeats)) => Box::new(mixer.with_plan(sampled, repeats)),
+                None => Box::new(mixer),
+            }
+        },
+        None => writer,
+    };
+    let count = || repeat_plan.map(|(sampled, _)| sampled).or(conf.target_set_size).ok_or_else(|| error::ProcError::Config("mixing method needs --count".to_string()));
+    if conf.algo != Algo::Sources && conf.in_files.iter().any(|input| input.options.quota.is_some()) {
+        return Err(error::ProcError::Config("weight and quota of input are used by '--method sources' only".to_string()));
+    }
+    let has_phantom_input = if conf.in_files.is_empty() {
+        conf.input_options.phantom
+    } else {
+        conf.in_files.iter().any(|input| input.options.phantom)
+    };
+    if conf.weight_field.is_some() && has_phantom_input {
+        return Err(error::ProcError::Config("weights are read from log lines, phantom input can't be weighted".to_string()));
+    }
     let processor = match conf.algo {
+        Algo::StreamSampling => Box::new(ammo_proc::StreamSampling::new(count()?, ammo_proc::make_rng(conf.seed), writer)?),
         Algo::MethodS => {
+            if conf.in_files.len() <= 0 {
+                return Err(error::ProcError::Config("twopass method needs input files: stdin can't be read twice".to_string()));
+            }
+            if conf.dedup.is_some() {
+                // lines are counted before dedup, sample would come out smaller than --count
+                return Err(error::ProcError::Config("twopass method can't be used with --dedup".to_string()));
+            }
             let lines_count = get_lines_count(conf)?;
-            ammo_proc::MethodS::new(lines_count, conf.target_set_size.unwrap(), writer)
+            ammo_proc::MethodS::new(lines_count, count()?, ammo_proc::make_rng(conf.seed), writer)?
+        },
+        Algo::ReserviorSampling => Box::new(ammo_proc::ReserviorSampling::new(count()?, ammo_proc::make_rng(conf.seed), writer)),
+        Algo::Stratified => {
+            let quotas = match conf.strata_quota_file {
+                Some(ref path) => ammo_proc::Quotas::Explicit(read_rules_file(path, ammo_proc::parse_quotas)?),
+                None if conf.strata_uniform => ammo_proc::Quotas::Uniform,
+                None => ammo_proc::Quotas::Proportional,
+            };
+            Box::new(ammo_proc::StratifiedSampling::new(count()?, conf.strata_key.clone(), quotas, ammo_proc::make_rng(conf.seed), writer))
+        },
+        Algo::Weighted => {
+            if conf.weight_field.is_none() {
+                return Err(error::ProcError::Config("weighted method needs --weight-key or --weight-column".to_string()));
+            }
+            Box::new(ammo_proc::WeightedSampling::new(count()?, ammo_proc::make_rng(conf.seed), writer))
+        },
+        Algo::Bootstrap => Box::new(ammo_proc::BootstrapSampling::new(count()?, conf.max_repeats, ammo_proc::make_rng(conf.seed), writer)),
+        Algo::Sources => {
+            let quotas = if conf.in_files.is_empty() {
+                vec![("stdin".to_string(), conf.input_options.quota.unwrap_or_default())]
+            } else {
+                conf.in_files.iter().map(|input| (input_name(input), input.options.quota.unwrap_or_default())).collect()
+            };
+            Box::new(ammo_proc::SourceSampling::new(count()?, quotas, ammo_proc::make_rng(conf.seed), writer)?)
         },
-        Algo::ReserviorSampling => Box::new(ammo_proc::ReserviorSampling::new(conf.target_set_size.unwrap(), writer)),
         Algo::DoNotRandomize => writer,
     };
-    Ok(processor)
+    let processor: Box<AmmoProcessor> = match conf.dedup {
+        Some(ref mode) => {
+            let seen = match *mode {
+                DedupMode::Exact => dedup::SeenSet::Exact(std::collections::HashSet::new()),
+                DedupMode::Bloom { capacity, error_rate } => dedup::SeenSet::Bloom(dedup::BloomFilter::with_capacity(capacity, error_rate)),
+            };
+            Box::new(dedup::Dedup::new(seen, conf.volatile_params.clone(), processor))
+        },
+        None => processor,
+    };
+    if conf.rewrite_files.is_empty() && conf.rewrite_rules.is_empty() {
+        return Ok(processor);
+    }
+    let mut rules = Vec::new();
+    for path in &conf.rewrite_files {
+        rules.extend(read_rules_file(path, rewrite::parse_rewrite_file)?);
+    }
+    rules.extend(conf.rewrite_rules.iter().cloned());
+    Ok(Box::new(rewrite::Rewrite::new(rules, processor)))
+}
+
+/// Returns weight of log line, missing or malformed weight counts as 1
+fn get_line_weight(line: &[u8], field: &WeightField) -> f64 {
+    let value = match *field {
+        WeightField::TskvKey(ref key) => get_tskv_value(line, key),
+        WeightField::Column(index) => get_tsv_column(line, index),
+    };
+    value.and_then(|v| std::str::from_utf8(v).ok())
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        .unwrap_or(1.0)
+}
+
+/// Appends query written apart from url to url, `buf` keeps the result
+fn join_query<'a>(mut rec: LogRecord<'a>, buf: &'a mut Vec<u8>) -> LogRecord<'a> {
+    if !rec.query.is_empty() {
+        *buf = [rec.url, b"?", rec.query].concat();
+        rec.url = buf;
+    }
+    rec
 }
 
-fn make_log_line_process_func<'a>(ammo_processor: &'a mut Box<AmmoProcessor>) -> Box<FnMut(&[u8]) + 'a> {
+/// Makes function which passes log lines to processor. The first error of processor
+/// is kept in `failure`, the following lines are skipped.
+fn make_log_line_process_func<'a>(conf: &'a RunConf, parser: &'a LineParser, ammo_processor: &'a mut Box<AmmoProcessor>, malformed: &'a mut usize, failure: &'a mut Option<error::ProcError>) -> Box<FnMut(&[u8]) + 'a> {
     let process_log_line = move |line_from_log: &[u8]| {
-        let rec = parse_log_line(line_from_log);
-        let bullet_data = ammo::make_bullet_data_from_log_record(rec);
-        ammo_processor.process(&bullet_data).unwrap();
+        if failure.is_some() {
+            return;
+        }
+        let rec = match parser.parse(line_from_log) {
+            Ok(rec) => rec,
+            Err(_) => {
+                *malformed += 1;
+                return;
+            },
+        };
+        let mut url_with_query = Vec::new();
+        let rec = join_query(rec, &mut url_with_query);
+        let mut bullet_data = ammo::make_bullet_data_from_log_record(&rec);
+        if let Some(ref field) = conf.weight_field {
+            bullet_data.weight = get_line_weight(line_from_log, field);
+        }
+        if let Err(err) = ammo_processor.process(&bullet_data) {
+            *failure = Some(err);
+        }
     };
     Box::new(process_log_line)
 }
 
-fn main() {
-    let conf = get_conf_from_cli(None);
-    let writer = make_writer(&conf).unwrap();
-    let mut mixer = make_processor(&conf, writer).unwrap();
+/// Validates ammo files, prints problems and returns exit code
+fn run_validate(files: &[PathBuf], format: Option<ammo::OutputFormat>, max_problems: usize) -> i32 {
+    let mut sources: Vec<(String, io::Result<Box<io::BufRead>>)> = files.iter()
+        .map(|path| (path.display().to_string(), File::open(path).map(|f| Box::new(io::BufReader::new(f)) as Box<io::BufRead>)))
+        .collect();
+    if files.is_empty() {
+        sources.push(("stdin".to_string(), Ok(Box::new(io::BufReader::new(io::stdin())))));
+    }
+    let mut exit_code = 0;
+    for (name, source) in sources {
+        let result = source.and_then(read::decompressed).and_then(|mut reader| {
+            let format = match format {
+                Some(format) => format,
+                None => validate::detect_format(reader.fill_buf()?),
+            };
+            let mut problems = 0;
+            let mut report = |problem: validate::Problem| {
+                if problems < max_problems {
+                    println!("{}: offset {}: {}", name, problem.offset, problem.message);
+                }
+                problems += 1;
+            };
+            let bullets = match format {
+                ammo::OutputFormat::Uri => validate::validate_uri(reader, &mut report)?,
+                _ => validate::validate_phantom(reader, &mut report)?,
+            };
+            Ok((bullets, problems))
+        });
+        match result {
+            Ok((bullets, problems)) => {
+                println!("{}: {} bullets, {} problems", name, bullets, problems);
+                if problems > 0 {
+                    exit_code = exit_code.max(1);
+                }
+            },
+            Err(e) => {
+                eprintln!("{}: can't read: {}", name, e);
+                exit_code = error::ProcError::Io(e).exit_code();
+            },
+        }
+    }
+    exit_code
+}
 
-    {
-        let mut reader = make_reader(&conf).unwrap();
-        let mut f = make_log_line_process_func(&mut mixer);
-        reader.process_lines(&mut *f).unwrap();
+/// Collects statistics of inputs, filtered lines are counted by the rule which dropped them
+fn collect_stats(in_files: &[Input], stdin_options: &InputOptions, filter: &filter::Filter) -> Result<stats::Stats, error::ProcError> {
+    let mut stats = stats::Stats::default();
+    for (name, input) in open_inputs(in_files, stdin_options, &filter::Filter::new())? {
+        let add = |stats: &mut stats::Stats, line: &[u8], bullet: &ammo::BulletData| {
+            match filter.rejecting_rule(line, bullet) {
+                Some((action, rule)) => stats.add_filtered(format!("{} {}", action, rule)),
+                None => stats.add(bullet),
+            }
+        };
+        match input {
+            InputReader::Log(mut reader, parser) => reader.process_lines(&mut |line| {
+                match parser.parse(line) {
+                    Ok(rec) => {
+                        let mut url_with_query = Vec::new();
+                        let rec = join_query(rec, &mut url_with_query);
+                        add(&mut stats, line, &ammo::make_bullet_data_from_log_record(&rec));
+                    },
+                    Err(_) => stats.add_malformed(),
+                }
+            }).map_err(|e| input_error(&name, None, e))?,
+            InputReader::Phantom(mut reader, _) => {
+                while let Some((request, bullet)) = reader.read_bullet().map_err(|e| input_error(&name, Some(reader.line()), e))? {
+                    add(&mut stats, &request, &bullet.get_data());
+                }
+            },
+        }
     }
+    Ok(stats)
+}
 
-    mixer.finish().unwrap();
+fn run_stats(conf: &RunConf, top: usize, json: bool, compare: &[String]) -> Result<(), error::ProcError> {
+    let filter = make_filter(conf)?;
+    let source = collect_stats(&conf.in_files, &conf.input_options, &filter)?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if compare.is_empty() {
+        if json {
+            source.write_json(top, &mut out)?;
+            writeln!(out)?;
+        } else {
+            source.write_text(top, &mut out)?;
+        }
+        return Ok(());
+    }
+    let base = InputOptions { phantom: true, ..Default::default() };
+    let mut ammo_files = Vec::new();
+    for spec in compare {
+        let (path, options) = parse_input_spec(spec, &base).map_err(error::ProcError::Config)?;
+        ammo_files.push(Input { source: LinesSource::FileName(path), options: options });
+    }
+    let ammo = collect_stats(&ammo_files, &base, &filter)?;
+    if json {
+        out.write_all(b"{\"source\":")?;
+        source.write_json(top, &mut out)?;
+        out.write_all(b",\"ammo\":")?;
+        ammo.write_json(top, &mut out)?;
+        writeln!(out, "}}")?;
+    } else {
+        stats::write_comparison(&source, &ammo, top, &mut out)?;
+    }
+    Ok(())
+}
+
+/// Reads inputs and writes ammo
+fn generate(conf: &RunConf) -> Result<(), error::ProcError> {
+    let writer = make_writer(conf)?;
+    let mut mixer = make_processor(conf, writer)?;
+    process_inputs(conf, &mut mixer)
+}
+
+/// Passes bullets of all inputs to processor and finishes it
+fn process_inputs(conf: &RunConf, mixer: &mut Box<AmmoProcessor>) -> Result<(), error::ProcError> {
+    let mut malformed = 0;
+    for (index, (name, input)) in make_inputs(conf)?.into_iter().enumerate() {
+        mixer.begin_source(index);
+        match input {
+            InputReader::Log(mut reader, parser) => {
+                let mut failure = None;
+                reader.process_lines(&mut *make_log_line_process_func(conf, &parser, mixer, &mut malformed, &mut failure))
+                    .map_err(|e| input_error(&name, None, e))?;
+                if let Some(err) = failure {
+                    return Err(err);
+                }
+            },
+            InputReader::Phantom(mut reader, filter) => {
+                while let Some((request, bullet)) = reader.read_bullet().map_err(|e| input_error(&name, Some(reader.line()), e))? {
+                    let bullet = bullet.get_data();
+                    if filter.check_bullet(&request, &bullet) {
+                        mixer.process(&bullet)?;
+                    }
+                }
+            },
+        }
+    }
+    if malformed > 0 {
+        eprintln!("Skipped {} malformed lines", malformed);
+    }
+
+    mixer.finish()?;
+    let mut report = Vec::new();
+    mixer.report(&mut report);
+    for line in report {
+        eprintln!("{}", line);
+    }
+    Ok(())
+}
+
+fn main() {
+    let result = get_conf_from_cli(None).and_then(|conf| match conf.command {
+        Command::Validate { ref files, format, max_problems } => std::process::exit(run_validate(files, format, max_problems)),
+        Command::Stats { top, json, ref compare } => run_stats(&conf, top, json, compare),
+        Command::Generate => generate(&conf),
+    });
+    if let Err(err) = result {
+        eprintln!("gen_ammo: {}", err);
+        std::process::exit(err.exit_code());
+    }
 }
 
 #[cfg(test)]
@@ -278,7 +1232,7 @@ mod tests {
 
     #[test]
     fn no_args() {
-        let conf = super::get_conf_from_cli(Some(vec![]));
+        let conf = super::get_conf_from_cli(Some(vec![])).unwrap();
         assert!(conf.algo == Algo::DoNotRandomize);
         assert!(conf.target_set_size.is_none());
         assert!(conf.in_files.len() == 0);
@@ -287,7 +1241,7 @@ mod tests {
 
     #[test]
     fn many_in_files() {
-        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--in", "file1.txt", "file2.txt", "file3.txt"]));
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--in", "file1.txt", "file2.txt", "file3.txt"])).unwrap();
         assert!(conf.algo == Algo::DoNotRandomize);
         assert!(conf.target_set_size.is_none());
         assert!(conf.in_files.len() == 3);
@@ -296,7 +1250,7 @@ mod tests {
 
     #[test]
     fn many_in_files2() {
-        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--in", "file1.txt", "--in", "file2.txt", "file3.txt"]));
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--in", "file1.txt", "--in", "file2.txt", "file3.txt"])).unwrap();
         assert!(conf.algo == Algo::DoNotRandomize);
         assert!(conf.target_set_size.is_none());
         assert!(conf.in_files.len() == 3);
@@ -305,7 +1259,7 @@ mod tests {
 
     #[test]
     fn many_out_files() {
-        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--out", "file1.gz", "file2.gz", "file3.gz"]));
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--out", "file1.gz", "file2.gz", "file3.gz"])).unwrap();
         assert!(conf.algo == Algo::DoNotRandomize);
         assert!(conf.target_set_size.is_none());
         assert!(conf.in_files.len() == 0);
@@ -314,7 +1268,7 @@ mod tests {
 
     #[test]
     fn gen_files_with_prefix_gzip() {
-        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--ammo-prefix", "file", "--nfiles", "3", "--gzip"]));
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--ammo-prefix", "file", "--nfiles", "3", "--gzip"])).unwrap();
         assert!(conf.algo == Algo::DoNotRandomize);
         assert!(conf.target_set_size.is_none());
         assert!(conf.in_files.len() == 0);
@@ -326,7 +1280,7 @@ mod tests {
 
     #[test]
     fn gen_files_with_prefix_no_gzip() {
-        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--ammo-prefix", "file", "--nfiles", "3"]));
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--ammo-prefix", "file", "--nfiles", "3"])).unwrap();
         assert_eq!(conf.out_files[0].to_str(), Some("file-00.txt"));
         assert_eq!(conf.out_files[1].to_str(), Some("file-01.txt"));
         assert_eq!(conf.out_files[2].to_str(), Some("file-02.txt"));
@@ -334,26 +1288,387 @@ mod tests {
 
     #[test]
     fn in_mem_algo_conf() {
-        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "inmem", "--count", "1000", "--in", "file1.txt", "--ammo-prefix", "file", "--nfiles", "3"]));
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "inmem", "--count", "1000", "--in", "file1.txt", "--ammo-prefix", "file", "--nfiles", "3"])).unwrap();
         assert!(conf.algo == Algo::ReserviorSampling);
         assert_eq!(conf.target_set_size.unwrap(), 3000);
     }
 
     #[test]
     fn stream_algo_conf() {
-        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "stream", "--count", "1000", "--in", "file1.txt", "--ammo-prefix", "file", "--nfiles", "3"]));
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "stream", "--count", "1000", "--in", "file1.txt", "--ammo-prefix", "file", "--nfiles", "3"])).unwrap();
+        assert!(conf.algo == Algo::StreamSampling);
+        assert_eq!(conf.target_set_size.unwrap(), 3000);
+    }
+
+    #[test]
+    fn twopass_algo_conf() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "twopass", "--count", "1000", "--in", "file1.txt", "--ammo-prefix", "file", "--nfiles", "3"])).unwrap();
         assert!(conf.algo == Algo::MethodS);
         assert_eq!(conf.target_set_size.unwrap(), 3000);
     }
 
+    #[test]
+    fn twopass_denies_stdin() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "twopass", "--count", "1"])).unwrap();
+        let writer = super::make_writer(&conf).unwrap();
+        assert!(super::make_processor(&conf, writer).is_err());
+    }
+
+    #[test]
+    fn twopass_denies_dedup() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "twopass", "--count", "1", "--in", "file1.txt", "--dedup", "exact"])).unwrap();
+        let writer = super::make_writer(&conf).unwrap();
+        assert_eq!(super::make_processor(&conf, writer).err().unwrap().exit_code(), 78);
+    }
+
+    #[test]
+    fn stream_allows_stdin() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "stream", "--count", "2", "--seed", "1"])).unwrap();
+        let writer = super::make_writer(&conf).unwrap();
+        assert!(super::make_processor(&conf, writer).is_ok());
+    }
+
+    #[test]
+    fn seed() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "inmem", "--count", "10", "--seed", "12345"])).unwrap();
+        assert_eq!(conf.seed, Some(12345));
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "inmem", "--count", "10"])).unwrap();
+        assert_eq!(conf.seed, None);
+    }
+
+    #[test]
+    fn out_format() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo"])).unwrap();
+        assert_eq!(conf.out_format, ammo::OutputFormat::Phantom);
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--format", "uri"])).unwrap();
+        assert_eq!(conf.out_format, ammo::OutputFormat::Uri);
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--format", "json"])).unwrap();
+        assert_eq!(conf.out_format, ammo::OutputFormat::Json);
+    }
+
+    #[test]
+    fn request_template() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo"])).unwrap();
+        assert_eq!(conf.template.http_version, ammo::HttpVersion::Http10);
+        assert_eq!(conf.template.host, ammo::HostHeader::Original);
+        assert!(!conf.template.keep_alive);
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--http-version", "1.1", "--host-header", "example.com", "--keep-alive"])).unwrap();
+        assert_eq!(conf.template.http_version, ammo::HttpVersion::Http11);
+        assert_eq!(conf.template.host, ammo::HostHeader::Override(b"example.com".to_vec()));
+        assert!(conf.template.keep_alive);
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--no-host-header"])).unwrap();
+        assert_eq!(conf.template.host, ammo::HostHeader::Omit);
+        assert_eq!(super::get_conf_from_cli(Some(vec!["gen_ammo", "--no-host-header", "--http-version", "1.1"])).err().unwrap().exit_code(), 78);
+    }
+
+    #[test]
+    fn tag_rules() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--strip-vowels", "--max-tags", "2"])).unwrap();
+        let rules = super::make_tag_rules(&conf).unwrap();
+        assert!(rules.strip_vowels);
+        assert_eq!(rules.max_tags, Some(2));
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--tag-dict", "/nonexistent/tags.txt"])).unwrap();
+        assert!(super::make_tag_rules(&conf).is_err());
+    }
+
+    #[test]
+    fn stratified_algo_conf() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "stratified", "--count", "10"])).unwrap();
+        assert!(conf.algo == Algo::Stratified);
+        assert_eq!(conf.strata_key, ammo_proc::StratumKey::Place);
+        assert!(!conf.strata_uniform);
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "stratified", "--count", "10", "--strata-by", "cgi:text", "--strata-quota", "uniform"])).unwrap();
+        assert_eq!(conf.strata_key, ammo_proc::StratumKey::CgiParam(b"text".to_vec()));
+        assert!(conf.strata_uniform);
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "stratified", "--count", "10", "--strata-by", "wizard", "--strata-quota-file", "/nonexistent/quotas.txt"])).unwrap();
+        assert_eq!(conf.strata_key, ammo_proc::StratumKey::Wizard);
+        let writer = super::make_writer(&conf).unwrap();
+        assert!(super::make_processor(&conf, writer).is_err());
+    }
+
+    #[test]
+    fn shard_conf() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--out", "a.txt", "b.txt"])).unwrap();
+        assert_eq!(conf.shard_key, None);
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--out", "a.txt", "b.txt", "--shard-by", "cgi:yandexuid"])).unwrap();
+        assert_eq!(conf.shard_key, Some(ammo_proc::StratumKey::CgiParam(b"yandexuid".to_vec())));
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--shard-by", "resource"])).unwrap();
+        assert_eq!(conf.shard_key, Some(ammo_proc::StratumKey::Resource));
+    }
+
+    #[test]
+    fn weighted_algo_conf() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "weighted", "--count", "10", "--weight-key", "count"])).unwrap();
+        assert!(conf.algo == Algo::Weighted);
+        assert_eq!(conf.weight_field, Some(WeightField::TskvKey(b"count".to_vec())));
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "weighted", "--count", "10", "--weight-column", "3"])).unwrap();
+        assert_eq!(conf.weight_field, Some(WeightField::Column(3)));
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "weighted", "--count", "10"])).unwrap();
+        let writer = super::make_writer(&conf).unwrap();
+        assert!(super::make_processor(&conf, writer).is_err());
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "weighted", "--count", "10", "--weight-key", "count", "--input-format", "phantom"])).unwrap();
+        let writer = super::make_writer(&conf).unwrap();
+        assert_eq!(super::make_processor(&conf, writer).err().unwrap().exit_code(), 78);
+    }
+
+    #[test]
+    fn line_weight() {
+        let key = WeightField::TskvKey(b"count".to_vec());
+        assert_eq!(super::get_line_weight(b"tskv\turl=/search\tcount=12", &key), 12.0);
+        assert_eq!(super::get_line_weight(b"tskv\turl=/search\tcount=0.5", &key), 0.5);
+        assert_eq!(super::get_line_weight(b"tskv\turl=/search\tcount=many", &key), 1.0);
+        assert_eq!(super::get_line_weight(b"tskv\turl=/search", &key), 1.0);
+        let column = WeightField::Column(2);
+        assert_eq!(super::get_line_weight(b"[date]\thttp://example.com/search\t7", &column), 7.0);
+        assert_eq!(super::get_line_weight(b"[date]\thttp://example.com/search", &column), 1.0);
+    }
+
+    #[test]
+    fn bootstrap_algo_conf() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "bootstrap", "--count", "25000000", "--out", "a.txt", "b.txt"])).unwrap();
+        assert!(conf.algo == Algo::Bootstrap);
+        assert_eq!(conf.target_set_size, Some(50000000));
+        assert_eq!(conf.max_repeats, None);
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "bootstrap", "--count", "100", "--max-repeats", "30"])).unwrap();
+        assert_eq!(conf.max_repeats, Some(30));
+    }
+
+    #[test]
+    fn dedup_conf() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo"])).unwrap();
+        assert_eq!(conf.dedup, None);
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--dedup", "exact", "--volatile-params", "reqid,ts", "--volatile-params", "uid"])).unwrap();
+        assert_eq!(conf.dedup, Some(DedupMode::Exact));
+        assert_eq!(conf.volatile_params, vec![b"reqid".to_vec(), b"ts".to_vec(), b"uid".to_vec()]);
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--dedup", "bloom", "--dedup-capacity", "1000"])).unwrap();
+        assert_eq!(conf.dedup, Some(DedupMode::Bloom { capacity: 1000, error_rate: 0.001 }));
+    }
+
+    #[test]
+    fn repeat_conf() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--repeat-ratio", "0.3"])).unwrap();
+        assert_eq!(conf.repeat_ratio, Some(0.3));
+        assert_eq!(conf.repeat_window, 1000);
+        assert_eq!(conf.repeat_recency, None);
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--repeat-ratio", "0.5", "--repeat-window", "50", "--repeat-recency", "exp:10"])).unwrap();
+        assert_eq!(conf.repeat_window, 50);
+        assert_eq!(conf.repeat_recency, Some(ammo_proc::Recency::Exponential(10.0)));
+
+        // repeats are a part of --count
+        let mut conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "inmem", "--count", "10", "--repeat-ratio", "0.3", "--seed", "1"])).unwrap();
+        let content: String = (0..100).map(|i| format!("http://you.ru/search?text={}\n", i)).collect();
+        conf.in_files = vec![make_fabric(&content)];
+        let collect = ammo_proc::testing::Collect::default();
+        let mut processor = super::make_processor(&conf, collect.boxed()).unwrap();
+        super::process_inputs(&conf, &mut processor).unwrap();
+        let mut resources = collect.resources();
+        assert_eq!(resources.len(), 10);
+        resources.sort();
+        resources.dedup();
+        assert_eq!(resources.len(), 7);
+    }
+
+    #[test]
+    fn rewrite_conf() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--rewrite-rule", "set base test", "--rewrite-rule", "remove reqid", "--rewrite", "rules.txt"])).unwrap();
+        assert_eq!(conf.rewrite_rules.len(), 2);
+        assert_eq!(conf.rewrite_files, vec![Path::new("rules.txt").to_path_buf()]);
+        let writer = super::make_writer(&conf).unwrap();
+        assert!(super::make_processor(&conf, writer).is_err());
+    }
+
+    #[test]
+    fn input_format_conf() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--input-format", "tsv", "--url-column", "0", "--in", "a.tsv", "b@x.log@format=tskv,url-field=uri", "c.log@format=log_format:$host, \"$request\""])).unwrap();
+        assert_eq!(conf.in_files.len(), 3);
+        let parse = |i: usize, line: &'static [u8]| conf.in_files[i].options.parser.parse(line).map(|rec| rec.url.to_vec());
+        match conf.in_files[1].source {
+            LinesSource::FileName(ref path) => assert_eq!(path, &PathBuf::from("b@x.log")),
+            _ => panic!(),
+        }
+        assert_eq!(parse(0, b"/search\t200").unwrap(), b"/search".to_vec());
+        assert_eq!(parse(1, b"tskv\turl=/other\turi=/search").unwrap(), b"/search".to_vec());
+        assert_eq!(parse(2, b"example.com, \"GET /search HTTP/1.1\"").unwrap(), b"/search".to_vec());
+        assert!(parse(2, b"/search").is_err());
+        assert_eq!(conf.input_options.parser.parse(b"/search\t200").unwrap().url, b"/search");
+
+        let (_, options) = super::parse_input_spec("a.log@wizards-column=2", &Default::default()).unwrap();
+        assert_eq!(options.parser.parse(b"[date]\t/search\twiz1").unwrap().wizards, b"wiz1");
+        assert!(super::parse_input_spec("a.log@format=csv", &Default::default()).is_err());
+        assert!(super::parse_input_spec("a.log@url-column=x", &Default::default()).is_err());
+        assert!(super::parse_input_spec("a.log@status-field=code", &Default::default()).is_err());
+    }
+
+    #[test]
+    fn phantom_input() {
+        let requests = ["GET /search?text=a HTTP/1.1\r\nHost: a.ru\r\n\r\n", "GET /search?text=a&subrequest=1 HTTP/1.1\r\nHost: a.ru\r\n\r\n"];
+        let ammo = format!("{} prime\r\n{}\r\n{}\r\n{}\r\n", requests[0].len(), requests[0], requests[1].len(), requests[1]);
+        let path = std::env::temp_dir().join(format!("gen_ammo_phantom_input_{}.txt", std::process::id()));
+        std::fs::write(&path, &ammo).unwrap();
+        let spec = format!("{}@format=phantom", path.to_str().unwrap());
+        let mut conf = super::get_conf_from_cli(Some(vec!["gen_ammo"])).unwrap();
+        let (path, options) = super::parse_input_spec(&spec, &conf.input_options).unwrap();
+        conf.in_files = vec![super::Input { source: LinesSource::FileName(path.clone()), options: options }];
+
+        assert_eq!(super::get_lines_count(&conf).unwrap(), 1);
+        let mut bullets = Vec::new();
+        for (_, input) in super::make_inputs(&conf).unwrap() {
+            match input {
+                super::InputReader::Phantom(mut reader, _) => while let Some((_, b)) = reader.read_bullet().unwrap() {
+                    bullets.push((b.host.clone(), b.resource.clone(), b.place.clone()));
+                },
+                _ => panic!("expected phantom reader"),
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bullets[0], (b"a.ru".to_vec(), b"search?text=a".to_vec(), b"prime".to_vec()));
+        assert_eq!(bullets.len(), 2);
+
+        conf.in_files = vec![make_fabric(&ammo)];
+        conf.in_files[0].options.phantom = true;
+        assert!(super::make_inputs(&conf).is_err());
+    }
+
+    #[test]
+    fn validate_conf() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "validate", "a.txt", "b.txt", "--format", "uri"])).unwrap();
+        assert_eq!(conf.command, super::Command::Validate {
+            files: vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")],
+            format: Some(ammo::OutputFormat::Uri),
+            max_problems: 100,
+        });
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--in", "a.log"])).unwrap();
+        assert_eq!(conf.command, super::Command::Generate);
+    }
+
+    #[test]
+    fn stats_conf() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "stats", "a.log", "b.log@format=tsv", "--top", "5", "--compare", "ammo.txt"])).unwrap();
+        assert_eq!(conf.command, super::Command::Stats { top: 5, json: false, compare: vec!["ammo.txt".to_string()] });
+        assert_eq!(conf.in_files.len(), 2);
+        match conf.in_files[1].source {
+            LinesSource::FileName(ref path) => assert_eq!(path, &PathBuf::from("b.log")),
+            _ => panic!("expected file"),
+        }
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--in", "a.log", "--no-default-filters", "stats"])).unwrap();
+        assert_eq!(conf.in_files.len(), 1);
+        assert_eq!(super::get_conf_from_cli(Some(vec!["gen_ammo", "--in", "a.log", "--no-default-filters", "stats", "b.log"])).err().unwrap().exit_code(), 78);
+
+        let content = "http://you.ru/search?text=a\nhttp://you.ru/search?text=b&subrequest=1\nhttp://me.ru/api?x=1\nhttp://you.ru/search?text=c";
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo"])).unwrap();
+        let filter = super::make_filter(&conf).unwrap();
+        let stats = super::collect_stats(&[make_fabric(content)], &conf.input_options, &filter).unwrap();
+        assert_eq!((stats.lines, stats.bullets), (4, 3));
+        assert_eq!(stats.filtered.keys().collect::<Vec<_>>(), vec!["exclude substr:subrequest=1"]);
+        assert_eq!(stats.top(stats::Category::Hosts, 1), vec![(b"you.ru".as_ref(), 2)]);
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo"])).unwrap();
+        let collect = ammo_proc::testing::Collect::default();
+        let mut processor = collect.boxed();
+        let mut malformed = 0;
+        let mut failure = None;
+        {
+            let parser = LineParser::default();
+            let mut f = super::make_log_line_process_func(&conf, &parser, &mut processor, &mut malformed, &mut failure);
+            f(br#"{"url": "http://example.com/search?text=a"}"#);
+            f(br#"{"url": "http://example.com/sea"#);
+            f(br#"{"path": "/search", "query": "text=b"}"#);
+            f(b"http://example.com/search?text=c");
+        }
+        assert_eq!(malformed, 1);
+        assert!(failure.is_none());
+        assert_eq!(collect.resources(), vec![b"search?text=a".to_vec(), b"search?text=b".to_vec(), b"search?text=c".to_vec()]);
+    }
+
+    #[test]
+    fn twopass_skips_malformed_lines() {
+        let content = "{\"url\": \"/a\"}\n{\"url\": \"/b\n{\"url\": \"/c\"}\n{\"url\n{\"url\": \"/d\"}\n{\"url\": \"/e\"}\n{";
+        let mut conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "twopass", "--count", "4", "--seed", "1"])).unwrap();
+        conf.in_files = vec![make_fabric(content)];
+        let collect = ammo_proc::testing::Collect::default();
+        let mut processor = super::make_processor(&conf, collect.boxed()).unwrap();
+        super::process_inputs(&conf, &mut processor).unwrap();
+        assert_eq!(collect.resources().len(), 4);
+    }
+
+    #[test]
+    fn source_quotas() {
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "sources", "--count", "10", "--in", "a.log@weight=40", "b.log@format=tsv,quota=3", "c.log"])).unwrap();
+        let quotas: Vec<_> = conf.in_files.iter().map(|input| input.options.quota).collect();
+        assert_eq!(quotas, vec![Some(ammo_proc::SourceQuota::Weight(40.0)), Some(ammo_proc::SourceQuota::Count(3)), None]);
+        assert!(super::parse_input_spec("a.log@weight=-1", &Default::default()).is_err());
+
+        let mut conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "sources", "--count", "10", "--seed", "1"])).unwrap();
+        let big: String = (0..100).map(|i| format!("http://big.ru/search?text={}\n", i)).collect();
+        let small: String = (0..5).map(|i| format!("http://small.ru/search?text={}\n", i)).collect();
+        conf.in_files = vec![make_fabric(&big), make_fabric(&small)];
+        conf.in_files[1].options.quota = Some(ammo_proc::SourceQuota::Count(5));
+        let collect = ammo_proc::testing::Collect::default();
+        let mut processor = super::make_processor(&conf, collect.boxed()).unwrap();
+        super::process_inputs(&conf, &mut processor).unwrap();
+        let hosts = collect.map(|bullet| bullet.host.to_vec());
+        assert_eq!(hosts.iter().filter(|h| h.as_slice() == b"small.ru").count(), 5);
+        assert_eq!(hosts.len(), 10);
+
+        conf.algo = Algo::Bootstrap;
+        assert_eq!(super::make_processor(&conf, collect.boxed()).err().unwrap().exit_code(), 78);
+    }
+
+    #[test]
+    fn validate_exit_code() {
+        let path = std::env::temp_dir().join(format!("gen_ammo_validate_{}.txt", std::process::id()));
+        std::fs::write(&path, "/a\n").unwrap();
+        // missing Host in the second file doesn't hide the unreadable first one
+        let files = vec![PathBuf::from("/nonexistent/ammo.txt"), path.clone()];
+        assert_eq!(super::run_validate(&files, None, 10), 74);
+        assert_eq!(super::run_validate(&files[1..], None, 10), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        struct Fail;
+        impl AmmoProcessor for Fail {
+            fn process(&mut self, _: &ammo::BulletData) -> Result<(), error::ProcError> {
+                Err(error::ProcError::NotEnoughInput("no".to_string()))
+            }
+        }
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo"])).unwrap();
+        let mut processor: Box<AmmoProcessor> = Box::new(Fail);
+        let (mut malformed, mut failure) = (0, None);
+        {
+            let parser = LineParser::default();
+            let mut f = super::make_log_line_process_func(&conf, &parser, &mut processor, &mut malformed, &mut failure);
+            f(b"http://example.com/a");
+            f(b"http://example.com/b");
+        }
+        assert_eq!(failure.unwrap().exit_code(), 66);
+
+        let path = std::env::temp_dir().join(format!("gen_ammo_errors_{}.txt", std::process::id()));
+        std::fs::write(&path, "include place:prime\nskip place:dubai\n").unwrap();
+        let mut conf = super::get_conf_from_cli(Some(vec!["gen_ammo"])).unwrap();
+        conf.filter_files = vec![path.clone()];
+        let err = super::make_filter(&conf).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.to_string(), format!("{}:2: expected 'include' or 'exclude' but got 'skip'", path.display()));
+        assert_eq!(err.exit_code(), 65);
+        assert_eq!(super::make_filter(&conf).err().unwrap().exit_code(), 74);
+
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "twopass", "--count", "1"])).unwrap();
+        assert_eq!(super::make_processor(&conf, Box::new(Fail)).err().unwrap().exit_code(), 78);
+    }
+
     #[test]
     fn count_1() {
-        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "stream", "--count", "1000", "--in", "file1.txt", "--out", "file1", "file2", "file3"]));
-        assert!(conf.algo == Algo::MethodS);
+        let conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--method", "stream", "--count", "1000", "--in", "file1.txt", "--out", "file1", "file2", "file3"])).unwrap();
+        assert!(conf.algo == Algo::StreamSampling);
         assert_eq!(conf.target_set_size.unwrap(), 3000);
     }
 
-    fn make_fabric(content: &str) -> LinesSource {
+    fn make_fabric(content: &str) -> super::Input {
         let content = content.to_string();
         let closure = move || -> Box<ReadByLine> {
             let reader = GenericReader{
@@ -361,7 +1676,17 @@ mod tests {
             };
             Box::new(reader)
         };
-        LinesSource::Fabric(Box::new(closure))
+        super::Input { source: LinesSource::Fabric(Box::new(closure)), options: Default::default() }
+    }
+
+    fn read_lines(conf: &super::RunConf) -> Vec<Vec<u8>> {
+        let mut lines: Vec<Vec<u8>> = Vec::new();
+        for (_, input) in super::make_inputs(conf).unwrap() {
+            if let super::InputReader::Log(mut reader, _) = input {
+                reader.process_lines(&mut |line: &[u8]| lines.push(line.to_vec())).unwrap();
+            }
+        }
+        lines
     }
 
     #[test]
@@ -373,13 +1698,39 @@ mod tests {
             ..Default::default()
         };
 
-        let mut lines: Vec<Vec<u8>> = Vec::new();
-        let mut reader = super::make_reader(&conf).unwrap();
-        reader.process_lines(&mut |line: &[u8]| lines.push(line.to_vec())).unwrap();
+        let lines = read_lines(&conf);
 
         assert_eq!(lines.len(), 4);
     }
 
+    #[test]
+    fn no_default_filters() {
+        let content = "line one\nhttp://you.ru?subrequest=1\nrep-outgoing=1";
+
+        let conf = super::RunConf {
+            in_files: vec![make_fabric(content)],
+            no_default_filters: true,
+            ..Default::default()
+        };
+
+        let lines = read_lines(&conf);
+
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn filter_rules_from_cli() {
+        let content = "http://example.com/search?place=prime\nhttp://example.com/search?place=dubai\nhttp://localhost/search?place=prime\nhttp://example.com/search?place=prime&subrequest=1";
+
+        let mut conf = super::get_conf_from_cli(Some(vec!["gen_ammo", "--include", "place:prime", "--exclude", "host:localhost"])).unwrap();
+        assert_eq!(conf.filters.len(), 2);
+        conf.in_files = vec![make_fabric(content)];
+
+        let lines = read_lines(&conf);
+
+        assert_eq!(lines, vec![b"http://example.com/search?place=prime".to_vec()]);
+    }
+
     #[test]
     fn simple_run() {
         let content = "one\ntwo\nthree";
@@ -387,12 +1738,10 @@ mod tests {
             in_files: vec![make_fabric(content)],
             ..Default::default()
         };
-        super::make_reader(&conf).unwrap();
+        super::make_inputs(&conf).unwrap();
     }
 
-    // TODO: deny combination of stdin and --method=stream
     // TODO: check that fails without --count
     // TODO: not in countd
-    // TODO: zero count
     // TODO: nfiles excludes --out and vs
 }
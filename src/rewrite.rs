@@ -0,0 +1,232 @@
+//! Rewriting of bullets, e.g. to point ammo made from production logs to test environment
+//!
+//! Rules file contains one rule per line, rules are applied in order:
+//!
+//! ```text
+//! set NAME VALUE                  set CGI param (appended if absent)
+//! remove NAME                     remove CGI param
+//! rename FROM TO                  rename CGI param
+//! replace NAME REGEX REPLACEMENT  replace REGEX matches in CGI param value, $1 etc. refer to groups
+//! host HOST                       override request host
+//! ```
+//!
+//! Arguments containing spaces are put in double quotes, e.g. `set text "a b"`; inside
+//! quotes `\"` and `\\` stand for quote and backslash, other backslashes are kept as is.
+//! Set values and replacement results are percent-encoded where they can't go to URL as is,
+//! `%XX` sequences are kept.
+//! Empty lines and lines starting with `#` are ignored. Place is taken from rewritten request
+//! if a rule changes `place` param.
+
+use regex::bytes::Regex;
+use logut;
+use error::{ProcError, LineError};
+use ammo::BulletData;
+use ammo_proc::AmmoProcessor;
+
+#[derive(Clone, Debug)]
+pub enum RewriteRule {
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    Rename(Vec<u8>, Vec<u8>),
+    Replace(Vec<u8>, Regex, Vec<u8>),
+    Host(Vec<u8>),
+}
+
+impl RewriteRule {
+    /// Whether the rule may change given CGI param
+    fn touches(&self, param: &[u8]) -> bool {
+        match *self {
+            RewriteRule::Set(ref name, _) | RewriteRule::Remove(ref name) | RewriteRule::Replace(ref name, _, _) => name.as_slice() == param,
+            RewriteRule::Rename(ref from, ref to) => from.as_slice() == param || to.as_slice() == param,
+            RewriteRule::Host(_) => false,
+        }
+    }
+
+    fn apply(&self, resource: &mut Vec<u8>, host: &mut Vec<u8>) {
+        let rewritten = match *self {
+            RewriteRule::Set(ref name, ref value) => logut::set_cgi_param(resource, name, value),
+            RewriteRule::Remove(ref name) => logut::remove_cgi_param(resource, name),
+            RewriteRule::Rename(ref from, ref to) => logut::rename_cgi_param(resource, from, to),
+            RewriteRule::Replace(ref name, ref re, ref replacement) => {
+                logut::map_cgi_param_values(resource, name, |value| {
+                    logut::url::percent_encode(&re.replace_all(value, replacement.as_slice())).into_owned()
+                })
+            },
+            RewriteRule::Host(ref new_host) => {
+                *host = new_host.clone();
+                return;
+            },
+        };
+        *resource = rewritten;
+    }
+}
+
+/// Splits rule into whitespace separated words, double-quoted words may contain spaces
+fn split_words(rule: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = rule.chars().peekable();
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        let mut word = String::new();
+        match chars.peek() {
+            None => return Ok(words),
+            Some(&'"') => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        None => return Err(format!("unterminated quote in rewrite rule '{}'", rule)),
+                        Some('"') => break,
+                        Some('\\') if chars.peek() == Some(&'"') || chars.peek() == Some(&'\\') => word.push(chars.next().unwrap()),
+                        Some(c) => word.push(c),
+                    }
+                }
+            },
+            Some(_) => {
+                while let Some(c) = chars.peek().cloned() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+            },
+        }
+        words.push(word);
+    }
+}
+
+/// Parses single rule, e.g. `set base test.example.com`
+pub fn parse_rewrite_rule(rule: &str) -> Result<RewriteRule, String> {
+    let words = split_words(rule)?;
+    let bytes = |i: usize| words[i].as_bytes().to_vec();
+    match (words.first().map(|w| w.as_str()), words.len()) {
+        (Some("set"), 3) => Ok(RewriteRule::Set(bytes(1), logut::url::percent_encode(words[2].as_bytes()).into_owned())),
+        (Some("remove"), 2) => Ok(RewriteRule::Remove(bytes(1))),
+        (Some("rename"), 3) => Ok(RewriteRule::Rename(bytes(1), bytes(2))),
+        (Some("replace"), 3) | (Some("replace"), 4) => {
+            let re = Regex::new(&words[2]).map_err(|e| format!("bad regex '{}': {}", words[2], e))?;
+            let replacement = if words.len() == 4 { bytes(3) } else { Vec::new() };
+            Ok(RewriteRule::Replace(bytes(1), re, replacement))
+        },
+        (Some("host"), 2) => Ok(RewriteRule::Host(bytes(1))),
+        _ => Err(format!("can't parse rewrite rule '{}' (quote arguments containing spaces)", rule)),
+    }
+}
+
+/// Parses rules file
+pub fn parse_rewrite_file(text: &str) -> Result<Vec<RewriteRule>, LineError> {
+    let mut rules = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        rules.push(parse_rewrite_rule(line).map_err(|e| LineError::new(n, e))?);
+    }
+    Ok(rules)
+}
+
+/// Applies rewrite rules to bullets before passing them to subprocessor
+pub struct Rewrite {
+    rules: Vec<RewriteRule>,
+    subprocessor: Box<AmmoProcessor>,
+}
+
+impl Rewrite {
+    pub fn new(rules: Vec<RewriteRule>, subprocessor: Box<AmmoProcessor>) -> Rewrite {
+        Rewrite {
+            rules: rules,
+            subprocessor: subprocessor,
+        }
+    }
+}
+
+impl AmmoProcessor for Rewrite {
+    fn process(&mut self, bullet: &BulletData) -> Result<(), ProcError> {
+        let mut resource = bullet.resource.to_vec();
+        let mut host = bullet.host.to_vec();
+        let mut place_changed = false;
+        for rule in &self.rules {
+            rule.apply(&mut resource, &mut host);
+            place_changed |= rule.touches(b"place");
+        }
+        // place may also come from tag of phantom input, it is kept unless a rule changes it
+        let place = if place_changed {
+            logut::get_cgi_param_value_naive(&resource, b"place").unwrap_or(b"")
+        } else {
+            bullet.place
+        };
+        let rewritten = BulletData {
+            resource: &resource,
+            host: &host,
+            place: place,
+            ..*bullet
+        };
+        self.subprocessor.process(&rewritten)
+    }
+    fn begin_source(&mut self, index: usize) {
+        self.subprocessor.begin_source(index)
+    }
+    fn report(&self, lines: &mut Vec<String>) {
+        self.subprocessor.report(lines)
+    }
+    fn finish(&mut self) -> Result<(), ProcError> {
+        self.subprocessor.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ammo_proc::testing::Collect;
+
+    fn rewrite(rules: &str, host: &[u8], resource: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let place = logut::get_cgi_param_value_naive(resource, b"place").unwrap_or(b"");
+        rewrite_bullet(rules, &BulletData { host: host, resource: resource, place: place, ..Default::default() })
+    }
+
+    /// Host, resource and place of rewritten bullet
+    fn rewrite_bullet(rules: &str, bullet: &BulletData) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let collect = Collect::default();
+        let mut rewrite = Rewrite::new(parse_rewrite_file(rules).unwrap(), collect.boxed());
+        rewrite.process(bullet).unwrap();
+        collect.map(|b| (b.host.to_vec(), b.resource.to_vec(), b.place.to_vec())).remove(0)
+    }
+
+    #[test]
+    fn rewrite_rules() {
+        let rules = "# point to test\nset base test.example.com\nremove reqid\n\nset nocache 1\nhost test.example.com\n";
+        let (host, resource, _) = rewrite(rules, b"prod.example.com", b"search?base=prod&reqid=12&text=a");
+        assert_eq!(host, b"test.example.com".to_vec());
+        assert_eq!(resource, b"search?base=test.example.com&text=a&nocache=1".to_vec());
+
+        let (_, resource, place) = rewrite("rename where place\nreplace place ^(\\w+)-old$ $1", b"", b"search?where=prime-old");
+        assert_eq!(resource, b"search?place=prime".to_vec());
+        assert_eq!(place, b"prime".to_vec());
+
+        let (_, resource, place) = rewrite("replace text \\s+", b"", b"search?text=a b&place=dubai");
+        assert_eq!(resource, b"search?text=ab&place=dubai".to_vec());
+        assert_eq!(place, b"dubai".to_vec());
+
+        let (_, resource, _) = rewrite("set text \"a b\"\nreplace base \"^prod (\\w+)$\" \"test \\\"$1\\\"\"", b"", b"search?text=c&base=prod x");
+        assert_eq!(resource, b"search?text=a%20b&base=test%20%22x%22".to_vec());
+
+        // place from phantom tag is kept unless a rule changes place param
+        let tagged = BulletData { resource: b"search?text=a", place: b"prime", ..Default::default() };
+        assert_eq!(rewrite_bullet("set text b", &tagged).2, b"prime".to_vec());
+        assert_eq!(rewrite_bullet("set place dubai", &tagged).2, b"dubai".to_vec());
+    }
+
+    #[test]
+    fn bad_rules() {
+        assert!(parse_rewrite_rule("set base").is_err());
+        assert!(parse_rewrite_rule("remove").is_err());
+        assert!(parse_rewrite_rule("replace text (").is_err());
+        assert!(parse_rewrite_rule("drop text").is_err());
+        assert!(parse_rewrite_rule("set text a b").is_err());
+        assert!(parse_rewrite_rule("set text \"a b").is_err());
+        assert_eq!(parse_rewrite_file("set a 1\nfoo").unwrap_err().to_string(), "line 2: can't parse rewrite rule 'foo' (quote arguments containing spaces)");
+    }
+}
@@ -0,0 +1,303 @@
+//! Statistics of input logs and ammo
+//!
+//! Shows what a sample is made of before it is shot: which places, wizards, hosts
+//! and CGI params dominate, how long urls are and how many of them are distinct.
+
+use std::collections::{HashMap, BTreeMap};
+use std::io::{self, Write};
+use ammo::{self, BulletData};
+use ammo_proc;
+
+const HLL_BITS: u32 = 14;
+
+/// Finalizer of MurmurHash3. High bits of FNV-1a differ little for similar strings
+/// like urls, but HyperLogLog picks register by them.
+fn mix_bits(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/// HyperLogLog counter of distinct items, error is about 1%
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog { registers: vec![0; 1 << HLL_BITS] }
+    }
+}
+
+impl HyperLogLog {
+    pub fn insert(&mut self, item: &[u8]) {
+        let hash = mix_bits(ammo_proc::stable_hash(item));
+        let index = (hash >> (64 - HLL_BITS)) as usize;
+        let rank = ((hash << HLL_BITS) | (1 << (HLL_BITS - 1))).leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            // linear counting is better for small sets
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+/// Kinds of values which have top lists
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Category {
+    Places,
+    Wizards,
+    Hosts,
+    CgiParams,
+}
+
+impl Category {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Category::Places => "places",
+            Category::Wizards => "wizards",
+            Category::Hosts => "hosts",
+            Category::CgiParams => "cgi_params",
+        }
+    }
+}
+
+/// Categories in the order of reports
+pub const CATEGORIES: [Category; 4] = [Category::Places, Category::Wizards, Category::Hosts, Category::CgiParams];
+
+const PERCENTILES: [u32; 3] = [50, 90, 99];
+
+/// Statistics of lines read from logs or bullets read from ammo
+#[derive(Default)]
+pub struct Stats {
+    /// Lines or bullets read
+    pub lines: u64,
+    /// Lines which can't be parsed
+    pub malformed: u64,
+    /// Count of filtered lines by the rule which dropped them
+    pub filtered: BTreeMap<String, u64>,
+    /// Lines which passed the filter
+    pub bullets: u64,
+    counters: [HashMap<Vec<u8>, u64>; 4],
+    url_lengths: BTreeMap<usize, u64>,
+    distinct_urls: HyperLogLog,
+}
+
+impl Stats {
+    pub fn add_malformed(&mut self) {
+        self.lines += 1;
+        self.malformed += 1;
+    }
+
+    pub fn add_filtered(&mut self, reason: String) {
+        self.lines += 1;
+        *self.filtered.entry(reason).or_insert(0) += 1;
+    }
+
+    pub fn add(&mut self, bullet: &BulletData) {
+        self.lines += 1;
+        self.bullets += 1;
+        if !bullet.place.is_empty() {
+            self.count(Category::Places, bullet.place);
+        }
+        for wizard in ammo::wizard_names(bullet.wizards) {
+            self.count(Category::Wizards, wizard);
+        }
+        self.count(Category::Hosts, bullet.host);
+        let mut params: Vec<&[u8]> = match bullet.resource.iter().position(|b| *b == b'?') {
+            Some(pos) => bullet.resource[pos + 1..].split(|b| *b == b'&')
+                .filter(|param| !param.is_empty())
+                .map(|param| param.split(|b| *b == b'=').next().unwrap_or(b""))
+                .collect(),
+            None => Vec::new(),
+        };
+        params.sort();
+        params.dedup();
+        for param in params {
+            self.count(Category::CgiParams, param);
+        }
+        // with leading slash, as in request line
+        *self.url_lengths.entry(bullet.resource.len() + 1).or_insert(0) += 1;
+        self.distinct_urls.insert(&[bullet.host, b"/", bullet.resource].concat());
+    }
+
+    fn count(&mut self, category: Category, value: &[u8]) {
+        *self.counters[category as usize].entry(value.to_vec()).or_insert(0) += 1;
+    }
+
+    /// Returns `n` most frequent values of category with their counts
+    pub fn top(&self, category: Category, n: usize) -> Vec<(&[u8], u64)> {
+        let mut values: Vec<(&[u8], u64)> = self.counters[category as usize].iter().map(|(k, v)| (&k[..], *v)).collect();
+        values.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        values.truncate(n);
+        values
+    }
+
+    /// Share of bullets having given value of category
+    pub fn share(&self, category: Category, value: &[u8]) -> f64 {
+        let count = self.counters[category as usize].get(value).cloned().unwrap_or(0);
+        if self.bullets == 0 { 0.0 } else { count as f64 / self.bullets as f64 }
+    }
+
+    /// Smallest url length which isn't exceeded by `percent` of urls, 0 if there are none
+    pub fn url_length_percentile(&self, percent: u32) -> usize {
+        // at least one url has to be seen
+        let threshold = (self.bullets * percent as u64).saturating_sub(1) / 100 + 1;
+        let mut seen = 0;
+        for (length, count) in &self.url_lengths {
+            seen += count;
+            if seen >= threshold {
+                return *length;
+            }
+        }
+        0
+    }
+
+    pub fn max_url_length(&self) -> usize {
+        self.url_lengths.keys().next_back().cloned().unwrap_or(0)
+    }
+
+    pub fn distinct_urls(&self) -> u64 {
+        self.distinct_urls.estimate()
+    }
+
+    pub fn write_text<W: Write>(&self, top: usize, to: &mut W) -> io::Result<()> {
+        writeln!(to, "lines          {}", self.lines)?;
+        writeln!(to, "malformed      {}", self.malformed)?;
+        writeln!(to, "filtered       {}", self.filtered.values().sum::<u64>())?;
+        for (reason, count) in &self.filtered {
+            writeln!(to, "  {}  {}", reason, count)?;
+        }
+        writeln!(to, "bullets        {}", self.bullets)?;
+        writeln!(to, "distinct urls  ~{}", self.distinct_urls())?;
+        write!(to, "url length    ")?;
+        for percent in &PERCENTILES {
+            write!(to, " p{} {},", percent, self.url_length_percentile(*percent))?;
+        }
+        writeln!(to, " max {}", self.max_url_length())?;
+        for &category in &CATEGORIES {
+            writeln!(to, "\ntop {}", category.name().replace('_', " "))?;
+            for (value, count) in self.top(category, top) {
+                writeln!(to, "  {}  {}  {:.1}%", String::from_utf8_lossy(value), count, 100.0 * self.share(category, value))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, top: usize, to: &mut W) -> io::Result<()> {
+        write!(to, "{{\"lines\":{},\"malformed\":{},\"filtered\":{{", self.lines, self.malformed)?;
+        for (i, (reason, count)) in self.filtered.iter().enumerate() {
+            if i > 0 {
+                to.write_all(b",")?;
+            }
+            ammo::write_json_string(reason.as_bytes(), to)?;
+            write!(to, ":{}", count)?;
+        }
+        write!(to, "}},\"bullets\":{},\"distinct_urls\":{},\"url_length\":{{", self.bullets, self.distinct_urls())?;
+        for percent in &PERCENTILES {
+            write!(to, "\"p{}\":{},", percent, self.url_length_percentile(*percent))?;
+        }
+        write!(to, "\"max\":{}}}", self.max_url_length())?;
+        for &category in &CATEGORIES {
+            write!(to, ",\"{}\":[", category.name())?;
+            for (i, (value, count)) in self.top(category, top).into_iter().enumerate() {
+                if i > 0 {
+                    to.write_all(b",")?;
+                }
+                to.write_all(b"{\"value\":")?;
+                ammo::write_json_string(value, to)?;
+                write!(to, ",\"count\":{}}}", count)?;
+            }
+            to.write_all(b"]")?;
+        }
+        to.write_all(b"}")
+    }
+}
+
+/// Writes source and ammo statistics side by side, top values are the ones of source
+pub fn write_comparison<W: Write>(source: &Stats, ammo: &Stats, top: usize, to: &mut W) -> io::Result<()> {
+    writeln!(to, "{:<24}{:>12}{:>12}", "", "source", "ammo")?;
+    writeln!(to, "{:<24}{:>12}{:>12}", "bullets", source.bullets, ammo.bullets)?;
+    writeln!(to, "{:<24}{:>12}{:>12}", "distinct urls", source.distinct_urls(), ammo.distinct_urls())?;
+    for percent in &PERCENTILES {
+        writeln!(to, "{:<24}{:>12}{:>12}", format!("url length p{}", percent),
+            source.url_length_percentile(*percent), ammo.url_length_percentile(*percent))?;
+    }
+    for &category in &CATEGORIES {
+        writeln!(to, "\ntop {}", category.name().replace('_', " "))?;
+        for (value, _) in source.top(category, top) {
+            writeln!(to, "  {:<22}{:>11.1}%{:>11.1}%", String::from_utf8_lossy(value),
+                100.0 * source.share(category, value), 100.0 * ammo.share(category, value))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_stats() -> Stats {
+        let mut stats = Stats::default();
+        for i in 0..100 {
+            let resource = format!("search?place={}&text={}&x=1&x=2", if i < 70 { "prime" } else { "dubai" }, "a".repeat(i));
+            let bullet = BulletData { host: b"a.ru", resource: resource.as_bytes(), place: &resource.as_bytes()[13..18], wizards: b"w1,w2", ..Default::default() };
+            stats.add(&bullet);
+        }
+        stats.add_malformed();
+        stats.add_filtered("exclude substr:subrequest=1".to_string());
+        stats
+    }
+
+    #[test]
+    fn counts() {
+        let stats = make_stats();
+        assert_eq!((stats.lines, stats.bullets, stats.malformed), (102, 100, 1));
+        assert_eq!(stats.top(Category::Places, 5), vec![(b"prime".as_ref(), 70), (b"dubai".as_ref(), 30)]);
+        assert_eq!(stats.top(Category::Wizards, 1), vec![(b"w1".as_ref(), 100)]);
+        assert_eq!(stats.top(Category::CgiParams, 5), vec![(b"place".as_ref(), 100), (b"text".as_ref(), 100), (b"x".as_ref(), 100)]);
+        assert_eq!(stats.share(Category::Places, b"dubai"), 0.3);
+        // url lengths are 33 + i with leading slash
+        assert_eq!(stats.url_length_percentile(50), 82);
+        assert_eq!(stats.url_length_percentile(99), 131);
+        assert_eq!(stats.max_url_length(), 132);
+        // estimate, two urls may fall into the same register
+        let distinct = stats.distinct_urls();
+        assert!(distinct == 99 || distinct == 100, "{}", distinct);
+        assert_eq!(Stats::default().url_length_percentile(50), 0);
+
+        let mut json = Vec::new();
+        stats.write_json(1, &mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with(&format!("{{\"lines\":102,\"malformed\":1,\"filtered\":{{\"exclude substr:subrequest=1\":1}},\"bullets\":100,\"distinct_urls\":{},", distinct)), "{}", json);
+        assert!(json.ends_with(",\"cgi_params\":[{\"value\":\"place\",\"count\":100}]}"), "{}", json);
+
+        let mut text = Vec::new();
+        write_comparison(&stats, &Stats::default(), 2, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("\n  prime                        70.0%        0.0%\n"), "{}", text);
+    }
+
+    #[test]
+    fn hyper_log_log() {
+        let mut hll = HyperLogLog::default();
+        for i in 0..200000 {
+            hll.insert(format!("url{}", i % 100000).as_bytes());
+        }
+        let estimate = hll.estimate() as f64;
+        assert!((estimate - 100000.0).abs() < 3000.0, "{}", estimate);
+    }
+}
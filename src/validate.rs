@@ -0,0 +1,310 @@
+//! Validation of ammo files
+//!
+//! Catches problems which make Tank fail late or shoot wrong requests: size lines
+//! which don't match requests, malformed request lines and headers, missing Host
+//! header, forbidden characters in tags and line ends other than CRLF.
+
+use std::io::{self, BufRead, Read};
+use twoway;
+use ammo::{self, OutputFormat};
+
+/// Problem of bullet starting at `offset`
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    pub offset: u64,
+    pub message: String,
+}
+
+/// Guesses ammo format by the start of file: uri ammo starts with header or uri
+pub fn detect_format(start: &[u8]) -> OutputFormat {
+    match start.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(&b'[') | Some(&b'/') => OutputFormat::Uri,
+        _ => OutputFormat::Phantom,
+    }
+}
+
+fn lossy(s: &[u8]) -> String {
+    String::from_utf8_lossy(s).into_owned()
+}
+
+/// Tags may contain printable ASCII characters except space
+fn check_tag(tag: &[u8]) -> Option<String> {
+    tag.iter().find(|b| !b.is_ascii_graphic())
+        .map(|b| format!("tag '{}' contains forbidden character 0x{:02x}", lossy(tag), b))
+}
+
+/// Checks `METHOD TARGET HTTP/x.y`, returns target and version if the line is fine
+fn check_request_line(line: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    let parts: Vec<&[u8]> = line.split(|b| *b == b' ').collect();
+    let bad = || format!("bad request line '{}'", lossy(line));
+    if parts.len() != 3 {
+        return Err(bad());
+    }
+    let (method, target, version) = (parts[0], parts[1], parts[2]);
+    let version_ok = version.len() == 8 && version.starts_with(b"HTTP/") && version[5].is_ascii_digit() &&
+        version[6] == b'.' && version[7].is_ascii_digit();
+    let target_ok = target.starts_with(b"/") || target.starts_with(b"http://") || target.starts_with(b"https://") || target == b"*";
+    if method.is_empty() || !method.iter().all(|b| b.is_ascii_uppercase()) || !target_ok || !version_ok {
+        return Err(bad());
+    }
+    Ok((target, version))
+}
+
+/// Checks raw HTTP request, returns descriptions of its problems
+pub fn check_request(request: &[u8]) -> Vec<String> {
+    let mut problems = Vec::new();
+    let (head, body) = match twoway::find_bytes(request, b"\r\n\r\n") {
+        Some(pos) => (&request[..pos], &request[pos + 4..]),
+        None => {
+            problems.push("headers are not terminated with CRLF CRLF".to_string());
+            (ammo::trim_crlf(request), b"".as_ref())
+        },
+    };
+    let lines: Vec<&[u8]> = head.split(|b| *b == b'\n').collect();
+    let last = lines.len() - 1;
+    let mut has_host = false;
+    let mut content_length = None;
+    let mut is_absolute = false;
+    // HTTP/1.0 doesn't require Host
+    let mut needs_host = true;
+    for (i, line) in lines.iter().enumerate() {
+        let line = if i < last && line.ends_with(b"\r") {
+            &line[..line.len() - 1]
+        } else {
+            if i < last {
+                problems.push(format!("line {} of request ends with LF instead of CRLF", i + 1));
+            }
+            line
+        };
+        if i == 0 {
+            match check_request_line(line) {
+                Ok((target, version)) => {
+                    is_absolute = !target.starts_with(b"/") && target != b"*";
+                    needs_host = version != b"HTTP/1.0";
+                },
+                Err(e) => problems.push(e),
+            }
+            continue;
+        }
+        let colon = line.iter().position(|b| *b == b':');
+        let name = &line[..colon.unwrap_or(0)];
+        if colon.is_none() || name.is_empty() || !name.iter().all(|b| b.is_ascii_graphic()) {
+            problems.push(format!("bad header line '{}'", lossy(line)));
+            continue;
+        }
+        let value = lossy(&line[name.len() + 1..]);
+        if name.eq_ignore_ascii_case(b"host") {
+            has_host = !value.trim().is_empty();
+        } else if name.eq_ignore_ascii_case(b"content-length") {
+            content_length = Some(value.trim().to_string());
+        }
+    }
+    if !has_host && !is_absolute && needs_host {
+        problems.push("no Host header".to_string());
+    }
+    match content_length {
+        Some(ref value) if value.parse::<usize>().ok() != Some(body.len()) => {
+            problems.push(format!("Content-Length is {} but body has {} bytes", value, body.len()));
+        },
+        None if !body.is_empty() => {
+            problems.push(format!("{} bytes after headers without Content-Length, size may be too large", body.len()));
+        },
+        _ => {},
+    }
+    problems
+}
+
+/// Checks phantom ammo, calls `report` for each problem and returns the number of bullets
+pub fn validate_phantom<R: BufRead>(mut reader: R, report: &mut FnMut(Problem)) -> io::Result<usize> {
+    let mut offset = 0u64;
+    let mut bullets = 0;
+    let mut line = Vec::new();
+    // after broken bullet lines are skipped up to the next size line
+    let mut lost = false;
+    loop {
+        let start = offset;
+        line.clear();
+        let count = reader.read_until(b'\n', &mut line)?;
+        if count == 0 {
+            break;
+        }
+        offset += count as u64;
+        let size_line = ammo::trim_crlf(&line).to_vec();
+        if size_line.is_empty() {
+            continue;
+        }
+        let (size, tag) = match ammo::parse_size_line(&size_line) {
+            Some(parsed) => parsed,
+            None => {
+                if !lost {
+                    report(Problem { offset: start, message: format!("bad size line '{}'", lossy(&size_line)) });
+                    lost = true;
+                }
+                continue;
+            },
+        };
+        lost = false;
+        bullets += 1;
+
+        let mut problems: Vec<String> = check_tag(tag).into_iter().collect();
+        let mut request = Vec::new();
+        reader.by_ref().take(size as u64).read_to_end(&mut request)?;
+        offset += request.len() as u64;
+        if request.len() < size {
+            problems.push(format!("size is {} but only {} bytes left", size, request.len()));
+        } else {
+            line.clear();
+            offset += reader.read_until(b'\n', &mut line)? as u64;
+            if !ammo::trim_crlf(&line).is_empty() {
+                problems.push(format!("size is {} but request continues after it", size));
+                lost = true;
+            }
+        }
+        problems.extend(check_request(&request));
+        for message in problems {
+            report(Problem { offset: start, message: message });
+        }
+    }
+    Ok(bullets)
+}
+
+/// Checks uri ammo, calls `report` for each problem and returns the number of bullets
+pub fn validate_uri<R: BufRead>(mut reader: R, report: &mut FnMut(Problem)) -> io::Result<usize> {
+    let mut offset = 0u64;
+    let mut bullets = 0;
+    let mut has_host = false;
+    let mut line = Vec::new();
+    loop {
+        let start = offset;
+        line.clear();
+        let count = reader.read_until(b'\n', &mut line)?;
+        if count == 0 {
+            break;
+        }
+        offset += count as u64;
+        let mut problem = |message: String| report(Problem { offset: start, message: message });
+        let content = if line.ends_with(b"\n") { &line[..line.len() - 1] } else { &line[..] };
+        let content = if content.ends_with(b"\r") {
+            problem("line ends with CRLF, Tank takes CR for part of the line".to_string());
+            &content[..content.len() - 1]
+        } else {
+            content
+        };
+        if content.is_empty() {
+            continue;
+        }
+        if content.starts_with(b"[") {
+            let header = &content[1..content.len() - content.ends_with(b"]") as usize];
+            match header.iter().position(|b| *b == b':') {
+                Some(pos) if pos > 0 && content.ends_with(b"]") => {
+                    if header[..pos].eq_ignore_ascii_case(b"host") {
+                        has_host = !lossy(&header[pos + 1..]).trim().is_empty();
+                    }
+                },
+                _ => problem(format!("bad header line '{}'", lossy(content))),
+            }
+            continue;
+        }
+        bullets += 1;
+        let (uri, tag) = match content.iter().position(|b| *b == b' ') {
+            Some(pos) => (&content[..pos], &content[pos + 1..]),
+            None => (content, b"".as_ref()),
+        };
+        if !uri.starts_with(b"/") {
+            problem(format!("uri '{}' doesn't start with '/'", lossy(uri)));
+        }
+        if let Some(message) = check_tag(tag) {
+            problem(message);
+        }
+        if !has_host {
+            problem("no Host header".to_string());
+        }
+    }
+    Ok(bullets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn phantom(ammo: &[u8]) -> (usize, Vec<Problem>) {
+        let mut problems = Vec::new();
+        let bullets = validate_phantom(Cursor::new(ammo), &mut |p| problems.push(p)).unwrap();
+        (bullets, problems)
+    }
+
+    fn messages(problems: &[Problem]) -> Vec<(u64, &str)> {
+        problems.iter().map(|p| (p.offset, p.message.as_str())).collect()
+    }
+
+    #[test]
+    fn valid_phantom() {
+        let request = "POST /api HTTP/1.1\r\nHost: a.ru\r\nContent-Length: 2\r\n\r\nok";
+        let ammo = format!("{} tag|wiz\r\n{}\r\n\n{} \r\n{}\r\n", request.len(), request, request.len(), request);
+        assert_eq!(phantom(ammo.as_bytes()), (2, vec![]));
+        assert_eq!(detect_format(ammo.as_bytes()), OutputFormat::Phantom);
+        // HTTP/1.0 doesn't need Host
+        assert_eq!(check_request(b"GET /a HTTP/1.0\r\n\r\n"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn broken_phantom() {
+        let good = "GET / HTTP/1.1\r\nHost: a.ru\r\n\r\n";
+        // size is too small, the rest of the bullet is skipped
+        let ammo = format!("{} t\r\n{}\r\n{} t\r\n{}\r\n", good.len() - 5, good, good.len(), good);
+        let (bullets, problems) = phantom(ammo.as_bytes());
+        assert_eq!(bullets, 2);
+        assert_eq!(messages(&problems), vec![
+            (0, "size is 25 but request continues after it"),
+            (0, "headers are not terminated with CRLF CRLF"),
+        ]);
+
+        let request = "get /a HTTP/1.1\nUser-Agent: x\r\n\r\n";
+        let ammo = format!("{} bad tag\r\n{}", request.len(), request);
+        assert_eq!(messages(&phantom(ammo.as_bytes()).1), vec![
+            (0, "tag 'bad tag' contains forbidden character 0x20"),
+            (0, "line 1 of request ends with LF instead of CRLF"),
+            (0, "bad request line 'get /a HTTP/1.1'"),
+            (0, "no Host header"),
+        ]);
+
+        let request = "GET /a HTTP/1.1\r\nHost: a.ru\r\nContent-Length: 5\r\n\r\nabc";
+        let ammo = format!("x\r\n{} \r\n{}\r\n100\r\nGET", request.len(), request);
+        assert_eq!(messages(&phantom(ammo.as_bytes()).1), vec![
+            (0, "bad size line 'x'"),
+            (3, "Content-Length is 5 but body has 3 bytes"),
+            (63, "size is 100 but only 3 bytes left"),
+            (63, "headers are not terminated with CRLF CRLF"),
+            (63, "bad request line 'GET'"),
+            (63, "no Host header"),
+        ]);
+
+        // absurd size is a problem of the bullet, not a reason to allocate it
+        for huge in &["99999999999999999", "18446744073709551615"] {
+            let ammo = format!("{} t\r\nGET / HTTP/1.0\r\n\r\n", huge);
+            let (bullets, problems) = phantom(ammo.as_bytes());
+            assert_eq!(bullets, 1);
+            assert_eq!(problems[0].message, format!("size is {} but only 18 bytes left", huge));
+        }
+    }
+
+    #[test]
+    fn uri_ammo() {
+        let ammo = b"[Host: a.ru]\n[Connection: close]\n/search?text=a tag\n\n/api\r\nsearch\n[Host]\n/x bad|tag\xff";
+        assert_eq!(detect_format(ammo), OutputFormat::Uri);
+        let mut problems = Vec::new();
+        let bullets = validate_uri(Cursor::new(ammo.as_ref()), &mut |p| problems.push(p)).unwrap();
+        assert_eq!(bullets, 4);
+        assert_eq!(messages(&problems), vec![
+            (53, "line ends with CRLF, Tank takes CR for part of the line"),
+            (59, "uri 'search' doesn't start with '/'"),
+            (66, "bad header line '[Host]'"),
+            (73, "tag 'bad|tag\u{fffd}' contains forbidden character 0xff"),
+        ]);
+
+        problems.clear();
+        validate_uri(Cursor::new(b"/a\n[Host: ]\n/b\n".as_ref()), &mut |p| problems.push(p)).unwrap();
+        assert_eq!(messages(&problems), vec![(0, "no Host header"), (12, "no Host header")]);
+    }
+}